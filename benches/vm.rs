@@ -0,0 +1,59 @@
+//! Times the Lox programs in `benches/lox` on the VM backend.
+//!
+//! Run `cargo bench` and `cargo bench --features nan-boxing` to compare the
+//! two value representations. Names given on the command line pick which
+//! programs to run, e.g. `cargo bench -- fib`.
+
+use rlox::interpreter::{Backend, Interpreter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/lox");
+    let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Unable to read the benchmarks")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+// Runs `source` once on a fresh VM and returns how long it took.
+fn time(source: &str) -> Duration {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_backend(Backend::Vm);
+    interpreter.set_output(Box::new(io::sink()));
+    let start = Instant::now();
+    let had_error = interpreter.run_source(source.to_string());
+    let elapsed = start.elapsed();
+    assert!(!had_error, "benchmark failed");
+    elapsed
+}
+
+fn main() {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let representation = if cfg!(feature = "nan-boxing") {
+        "nan-boxed"
+    } else {
+        "enum"
+    };
+    println!("VM values: {}, best of {} runs", representation, RUNS);
+
+    for program in programs() {
+        let name = program.file_stem().unwrap().to_string_lossy().into_owned();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        let source = fs::read_to_string(&program).expect("Unable to read benchmark");
+        let best = (0..RUNS).map(|_| time(&source)).min().unwrap();
+        println!("{:<16} {:>10.3} ms", name, best.as_secs_f64() * 1000.0);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::token::Token;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a fresh expression id. Ids are unique for the whole process so
+/// that code parsed separately (for example successive REPL lines) can share
+/// one resolver table.
+pub fn next_expr_id() -> usize {
+    NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A source range. Lines and columns are 1-based and `end_column` points one
+/// past the last character, like `Diagnostic`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    pub fn of_token(token: &Token) -> Span {
+        let mut end_line = token.line;
+        let mut end_column = token.column;
+        for c in token.lexeme.chars() {
+            if c == '\n' {
+                end_line += 1;
+                end_column = 1;
+            } else {
+                end_column += 1;
+            }
+        }
+        Span {
+            line: token.line,
+            column: token.column,
+            end_line,
+            end_column,
+        }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            end_line: other.end_line,
+            end_column: other.end_column,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LiteralValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// An expression node. `id` comes from `next_expr_id` and is used by the
+/// resolver to record where each variable reference is bound.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    pub id: usize,
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr {
+            id: next_expr_id(),
+            kind,
+            span,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExprKind {
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Grouping {
+        expression: Box<Expr>,
+    },
+    Literal {
+        value: LiteralValue,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Super {
+        keyword: Token,
+        method: Token,
+    },
+    This {
+        keyword: Token,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Variable {
+        name: Token,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StmtKind {
+    Block {
+        statements: Vec<Stmt>,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Expr>,
+        methods: Vec<Rc<Function>>,
+    },
+    Expression {
+        expression: Expr,
+    },
+    Function {
+        function: Rc<Function>,
+    },
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    Print {
+        expression: Expr,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Var {
+        name: Token,
+        initializer: Option<Expr>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+}
+
+/// A function or method declaration. Shared with the runtime closures that
+/// are created from it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::TokenType;
+
+    #[test]
+    fn test_span_of_token() {
+        let token = Token::new(TokenType::Identifier, "name".to_string(), None, 2).with_column(5);
+        assert_eq!(
+            Span::of_token(&token),
+            Span {
+                line: 2,
+                column: 5,
+                end_line: 2,
+                end_column: 9
+            }
+        );
+    }
+
+    #[test]
+    fn test_span_of_multiline_token() {
+        let token = Token::new(
+            TokenType::String,
+            "\"a\nbc\"".to_string(),
+            Some("a\nbc".to_string()),
+            1,
+        )
+        .with_column(3);
+        let span = Span::of_token(&token);
+        assert_eq!((span.end_line, span.end_column), (2, 4));
+    }
+
+    #[test]
+    fn test_span_to() {
+        let a = Span {
+            line: 1,
+            column: 1,
+            end_line: 1,
+            end_column: 2,
+        };
+        let b = Span {
+            line: 3,
+            column: 4,
+            end_line: 3,
+            end_column: 8,
+        };
+        assert_eq!(
+            a.to(b),
+            Span {
+                line: 1,
+                column: 1,
+                end_line: 3,
+                end_column: 8
+            }
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use crate::ast::{Expr, ExprKind, Function, LiteralValue, Span, Stmt, StmtKind};
+use crate::json;
+
+/// Output formats for `rlox ast`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AstFormat {
+    /// Lisp-style S-expressions, one top-level statement per line.
+    Sexpr,
+    /// A JSON array of statement nodes, each with its source span.
+    Json,
+}
+
+/// Renders a program in the requested format, ending with a newline.
+pub fn print_program(statements: &[Stmt], format: AstFormat) -> String {
+    match format {
+        AstFormat::Sexpr => {
+            let mut out = String::new();
+            for statement in statements {
+                out.push_str(&sexpr_stmt(statement));
+                out.push('\n');
+            }
+            out
+        }
+        AstFormat::Json => {
+            let nodes: Vec<String> = statements.iter().map(json_stmt).collect();
+            format!("[{}]\n", nodes.join(","))
+        }
+    }
+}
+
+/// Renders an expression as an S-expression, e.g. `(* (group (+ 1 2)) 3)`.
+pub fn sexpr_expr(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Assign { name, value } => {
+            parenthesize("=", &[name.lexeme.clone(), sexpr_expr(value)])
+        }
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+        } => parenthesize(&operator.lexeme, &[sexpr_expr(left), sexpr_expr(right)]),
+        ExprKind::Call {
+            callee, arguments, ..
+        } => {
+            let mut parts = vec![sexpr_expr(callee)];
+            parts.extend(arguments.iter().map(sexpr_expr));
+            parenthesize("call", &parts)
+        }
+        ExprKind::Get { object, name } => {
+            parenthesize(".", &[sexpr_expr(object), name.lexeme.clone()])
+        }
+        ExprKind::Grouping { expression } => parenthesize("group", &[sexpr_expr(expression)]),
+        ExprKind::Literal { value } => sexpr_literal(value),
+        ExprKind::Logical {
+            left,
+            operator,
+            right,
+        } => parenthesize(
+            logical_name(&operator.lexeme),
+            &[sexpr_expr(left), sexpr_expr(right)],
+        ),
+        ExprKind::Set {
+            object,
+            name,
+            value,
+        } => parenthesize(
+            ".=",
+            &[sexpr_expr(object), name.lexeme.clone(), sexpr_expr(value)],
+        ),
+        ExprKind::Super { method, .. } => {
+            parenthesize("super", std::slice::from_ref(&method.lexeme))
+        }
+        ExprKind::This { .. } => "this".to_string(),
+        ExprKind::Unary { operator, right } => parenthesize(&operator.lexeme, &[sexpr_expr(right)]),
+        ExprKind::Variable { name } => name.lexeme.clone(),
+    }
+}
+
+/// Renders a statement as an S-expression, e.g. `(var x (+ 1 2))`.
+pub fn sexpr_stmt(stmt: &Stmt) -> String {
+    match &stmt.kind {
+        StmtKind::Block { statements } => {
+            let parts: Vec<String> = statements.iter().map(sexpr_stmt).collect();
+            parenthesize("block", &parts)
+        }
+        StmtKind::Class {
+            name,
+            superclass,
+            methods,
+        } => {
+            let mut parts = vec![name.lexeme.clone()];
+            if let Some(superclass) = superclass {
+                parts.push("<".to_string());
+                parts.push(sexpr_expr(superclass));
+            }
+            parts.extend(
+                methods
+                    .iter()
+                    .map(|method| sexpr_function("method", method)),
+            );
+            parenthesize("class", &parts)
+        }
+        StmtKind::Expression { expression } => parenthesize(";", &[sexpr_expr(expression)]),
+        StmtKind::Function { function } => sexpr_function("fun", function),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => match else_branch {
+            Some(else_branch) => parenthesize(
+                "if-else",
+                &[
+                    sexpr_expr(condition),
+                    sexpr_stmt(then_branch),
+                    sexpr_stmt(else_branch),
+                ],
+            ),
+            None => parenthesize("if", &[sexpr_expr(condition), sexpr_stmt(then_branch)]),
+        },
+        StmtKind::Print { expression } => parenthesize("print", &[sexpr_expr(expression)]),
+        StmtKind::Return { value, .. } => match value {
+            Some(value) => parenthesize("return", &[sexpr_expr(value)]),
+            None => "(return)".to_string(),
+        },
+        StmtKind::Var { name, initializer } => match initializer {
+            Some(initializer) => {
+                parenthesize("var", &[name.lexeme.clone(), sexpr_expr(initializer)])
+            }
+            None => parenthesize("var", std::slice::from_ref(&name.lexeme)),
+        },
+        StmtKind::While { condition, body } => {
+            parenthesize("while", &[sexpr_expr(condition), sexpr_stmt(body)])
+        }
+    }
+}
+
+fn sexpr_function(keyword: &str, function: &Function) -> String {
+    let params: Vec<&str> = function.params.iter().map(|p| p.lexeme.as_str()).collect();
+    let mut parts = vec![
+        function.name.lexeme.clone(),
+        format!("({})", params.join(" ")),
+    ];
+    parts.extend(function.body.iter().map(sexpr_stmt));
+    parenthesize(keyword, &parts)
+}
+
+fn sexpr_literal(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Nil => "nil".to_string(),
+        LiteralValue::Bool(b) => b.to_string(),
+        LiteralValue::Number(n) => n.to_string(),
+        LiteralValue::String(s) => json::quote(s),
+    }
+}
+
+fn parenthesize(name: &str, parts: &[String]) -> String {
+    let mut out = format!("({}", name);
+    for part in parts {
+        out.push(' ');
+        out.push_str(part);
+    }
+    out.push(')');
+    out
+}
+
+// `&&` and `||` scan to the same tokens as `and` and `or`.
+fn logical_name(lexeme: &str) -> &'static str {
+    match lexeme {
+        "and" | "&&" => "and",
+        _ => "or",
+    }
+}
+
+/// Renders an expression as a JSON object with a `type`, a `span` and one
+/// field per child.
+pub fn json_expr(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Assign { name, value } => json_node(
+            "Assign",
+            expr.span,
+            &[
+                ("name", json::quote(&name.lexeme)),
+                ("value", json_expr(value)),
+            ],
+        ),
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+        } => json_node(
+            "Binary",
+            expr.span,
+            &[
+                ("operator", json::quote(&operator.lexeme)),
+                ("left", json_expr(left)),
+                ("right", json_expr(right)),
+            ],
+        ),
+        ExprKind::Call {
+            callee, arguments, ..
+        } => json_node(
+            "Call",
+            expr.span,
+            &[
+                ("callee", json_expr(callee)),
+                ("arguments", json_array(arguments.iter().map(json_expr))),
+            ],
+        ),
+        ExprKind::Get { object, name } => json_node(
+            "Get",
+            expr.span,
+            &[
+                ("object", json_expr(object)),
+                ("name", json::quote(&name.lexeme)),
+            ],
+        ),
+        ExprKind::Grouping { expression } => json_node(
+            "Grouping",
+            expr.span,
+            &[("expression", json_expr(expression))],
+        ),
+        ExprKind::Literal { value } => {
+            json_node("Literal", expr.span, &[("value", json_literal(value))])
+        }
+        ExprKind::Logical {
+            left,
+            operator,
+            right,
+        } => json_node(
+            "Logical",
+            expr.span,
+            &[
+                ("operator", json::quote(logical_name(&operator.lexeme))),
+                ("left", json_expr(left)),
+                ("right", json_expr(right)),
+            ],
+        ),
+        ExprKind::Set {
+            object,
+            name,
+            value,
+        } => json_node(
+            "Set",
+            expr.span,
+            &[
+                ("object", json_expr(object)),
+                ("name", json::quote(&name.lexeme)),
+                ("value", json_expr(value)),
+            ],
+        ),
+        ExprKind::Super { method, .. } => json_node(
+            "Super",
+            expr.span,
+            &[("method", json::quote(&method.lexeme))],
+        ),
+        ExprKind::This { .. } => json_node("This", expr.span, &[]),
+        ExprKind::Unary { operator, right } => json_node(
+            "Unary",
+            expr.span,
+            &[
+                ("operator", json::quote(&operator.lexeme)),
+                ("right", json_expr(right)),
+            ],
+        ),
+        ExprKind::Variable { name } => json_node(
+            "Variable",
+            expr.span,
+            &[("name", json::quote(&name.lexeme))],
+        ),
+    }
+}
+
+/// Renders a statement as a JSON object, like `json_expr`.
+pub fn json_stmt(stmt: &Stmt) -> String {
+    match &stmt.kind {
+        StmtKind::Block { statements } => json_node(
+            "Block",
+            stmt.span,
+            &[("statements", json_array(statements.iter().map(json_stmt)))],
+        ),
+        StmtKind::Class {
+            name,
+            superclass,
+            methods,
+        } => json_node(
+            "Class",
+            stmt.span,
+            &[
+                ("name", json::quote(&name.lexeme)),
+                (
+                    "superclass",
+                    superclass.as_ref().map_or("null".to_string(), json_expr),
+                ),
+                (
+                    "methods",
+                    json_array(methods.iter().map(|method| json_function(method))),
+                ),
+            ],
+        ),
+        StmtKind::Expression { expression } => json_node(
+            "Expression",
+            stmt.span,
+            &[("expression", json_expr(expression))],
+        ),
+        StmtKind::Function { function } => json_function(function),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => json_node(
+            "If",
+            stmt.span,
+            &[
+                ("condition", json_expr(condition)),
+                ("then_branch", json_stmt(then_branch)),
+                (
+                    "else_branch",
+                    else_branch
+                        .as_ref()
+                        .map_or("null".to_string(), |s| json_stmt(s)),
+                ),
+            ],
+        ),
+        StmtKind::Print { expression } => {
+            json_node("Print", stmt.span, &[("expression", json_expr(expression))])
+        }
+        StmtKind::Return { value, .. } => json_node(
+            "Return",
+            stmt.span,
+            &[(
+                "value",
+                value.as_ref().map_or("null".to_string(), json_expr),
+            )],
+        ),
+        StmtKind::Var { name, initializer } => json_node(
+            "Var",
+            stmt.span,
+            &[
+                ("name", json::quote(&name.lexeme)),
+                (
+                    "initializer",
+                    initializer.as_ref().map_or("null".to_string(), json_expr),
+                ),
+            ],
+        ),
+        StmtKind::While { condition, body } => json_node(
+            "While",
+            stmt.span,
+            &[
+                ("condition", json_expr(condition)),
+                ("body", json_stmt(body)),
+            ],
+        ),
+    }
+}
+
+fn json_function(function: &Function) -> String {
+    json_node(
+        "Function",
+        function.span,
+        &[
+            ("name", json::quote(&function.name.lexeme)),
+            (
+                "params",
+                json_array(function.params.iter().map(|p| json::quote(&p.lexeme))),
+            ),
+            ("body", json_array(function.body.iter().map(json_stmt))),
+        ],
+    )
+}
+
+fn json_literal(value: &LiteralValue) -> String {
+    match value {
+        LiteralValue::Nil => "null".to_string(),
+        LiteralValue::Bool(b) => b.to_string(),
+        LiteralValue::Number(n) => json::number(*n),
+        LiteralValue::String(s) => json::quote(s),
+    }
+}
+
+fn json_span(span: Span) -> String {
+    format!(
+        "{{\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}}}",
+        span.line, span.column, span.end_line, span.end_column
+    )
+}
+
+fn json_array<I: Iterator<Item = String>>(items: I) -> String {
+    let items: Vec<String> = items.collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_node(node_type: &str, span: Span, fields: &[(&str, String)]) -> String {
+    let mut out = format!(
+        "{{\"type\":{},\"span\":{}",
+        json::quote(node_type),
+        json_span(span)
+    );
+    for (name, value) in fields {
+        out.push_str(&format!(",{}:{}", json::quote(name), value));
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens().clone();
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse();
+        assert!(parser.errors().is_empty(), "{:?}", parser.errors());
+        statements
+    }
+
+    fn sexpr(source: &str) -> String {
+        print_program(&parse(source), AstFormat::Sexpr)
+    }
+
+    #[test]
+    fn test_precedence_grouping() {
+        assert_eq!(sexpr("(1 + 2) * 3;"), "(; (* (group (+ 1 2)) 3))\n");
+        assert_eq!(sexpr("1 + 2 * 3;"), "(; (+ 1 (* 2 3)))\n");
+        assert_eq!(sexpr("-a - -b;"), "(; (- (- a) (- b)))\n");
+    }
+
+    #[test]
+    fn test_logical_and_assignment() {
+        assert_eq!(
+            sexpr("a = b or c && !d;"),
+            "(; (= a (or b (and c (! d)))))\n"
+        );
+        assert_eq!(sexpr("a.b.c = 1.5;"), "(; (.= (. a b) c 1.5))\n");
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(
+            sexpr("var x = \"hi\"; print x;"),
+            "(var x \"hi\")\n(print x)\n"
+        );
+        assert_eq!(
+            sexpr("if (x) print 1; else { print nil; }"),
+            "(if-else x (print 1) (block (print nil)))\n"
+        );
+        assert_eq!(
+            sexpr("for (var i = 0; i < 2; i = i + 1) print i;"),
+            "(block (var i 0) (while (< i 2) (block (print i) (; (= i (+ i 1))))))\n"
+        );
+    }
+
+    #[test]
+    fn test_functions_and_classes() {
+        assert_eq!(
+            sexpr("fun add(a, b) { return a + b; } add(1, 2);"),
+            "(fun add (a b) (return (+ a b)))\n(; (call add 1 2))\n"
+        );
+        assert_eq!(
+            sexpr("class B < A { init() { super.init(); this.x = 1; } }"),
+            "(class B < A (method init () (; (call (super init))) (; (.= this x 1))))\n"
+        );
+    }
+
+    #[test]
+    fn test_json_with_spans() {
+        let statements = parse("print -1;");
+        assert_eq!(
+            print_program(&statements, AstFormat::Json),
+            "[{\"type\":\"Print\",\"span\":{\"line\":1,\"column\":1,\"end_line\":1,\"end_column\":10},\
+             \"expression\":{\"type\":\"Unary\",\"span\":{\"line\":1,\"column\":7,\"end_line\":1,\"end_column\":9},\
+             \"operator\":\"-\",\"right\":{\"type\":\"Literal\",\"span\":{\"line\":1,\"column\":8,\"end_line\":1,\"end_column\":9},\
+             \"value\":1}}}]\n"
+        );
+    }
+
+    #[test]
+    fn test_json_optional_fields() {
+        let json = print_program(&parse("var x; return;"), AstFormat::Json);
+        assert!(json.contains("\"name\":\"x\",\"initializer\":null"));
+        assert!(json.contains("\"type\":\"Return\""));
+        assert!(json.contains("\"value\":null"));
+    }
+}
@@ -0,0 +1,902 @@
+use crate::ast_printer::AstFormat;
+use crate::diagnostic::ErrorFormat;
+use crate::highlight::{self, HighlightFormat};
+use crate::interpreter::{Backend, Interpreter, EXIT_DATA_ERROR};
+use crate::runtime::DEFAULT_MAX_DEPTH;
+use crate::sandbox::Sandbox;
+use crate::token::TokenFormat;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use std::thread;
+
+/// The command line was used incorrectly.
+pub const EXIT_USAGE: i32 = 64;
+/// An input file did not exist or was not readable.
+pub const EXIT_NO_INPUT: i32 = 66;
+/// An error occurred while reading input.
+pub const EXIT_IO_ERROR: i32 = 74;
+/// `rlox fmt --check` found a script that is not formatted.
+pub const EXIT_UNFORMATTED: i32 = 1;
+
+// Rust stack for the interpreter thread: a base amount, and for the tree
+// walker enough for each level of calls it may nest, with room to spare in
+// debug builds. Past the largest size, threads may fail to start.
+const BASE_STACK_SIZE: usize = 8 << 20;
+const STACK_PER_CALL: usize = 64 << 10;
+const MAX_STACK_SIZE: usize = 1 << 30;
+
+/// The deepest `--max-depth` the tree walker supports. The VM keeps its
+/// calls off the Rust stack and supports any depth.
+pub const MAX_TREE_DEPTH: usize = (MAX_STACK_SIZE - BASE_STACK_SIZE) / STACK_PER_CALL;
+
+/// Where a subcommand reads its script from.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Input {
+    File(String),
+    /// Given as `-` on the command line.
+    Stdin,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Run(Input),
+    Check(Input),
+    Tokens(Input, TokenFormat),
+    Ast(Input, AstFormat),
+    Highlight(Input, HighlightFormat),
+    /// Prints a script in the standard layout, or with `true` (`--check`)
+    /// only reports whether it is already laid out that way.
+    Fmt(Input, bool),
+    Disasm(Input),
+    /// Compiles a script to bytecode and writes it to the given path.
+    Compile(Input, String),
+    Repl,
+    Eval(String),
+    /// Prints the help for a subcommand, or the general help for `None`.
+    Help(Option<&'static str>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub command: Command,
+    pub error_format: ErrorFormat,
+    pub backend: Backend,
+    pub trace_execution: bool,
+    pub stress_gc: bool,
+    /// Whether the VM compiler optimizes, which `-O0` turns off.
+    pub optimize: bool,
+    /// How deep calls may nest before a "Stack overflow." error.
+    pub max_depth: usize,
+    /// How many steps each run may take, or `None` for no limit.
+    pub max_steps: Option<u64>,
+    /// How many bytes script objects may hold, or `None` for no limit.
+    pub max_memory: Option<usize>,
+    /// Whether scripts run under `Sandbox::restricted`, with the limits
+    /// above taking precedence over its own.
+    pub sandbox: bool,
+}
+
+struct Subcommand {
+    name: &'static str,
+    usage: &'static str,
+    summary: &'static str,
+    options: &'static str,
+}
+
+const SUBCOMMANDS: &[Subcommand] = &[
+    Subcommand {
+        name: "run",
+        usage: "rlox run [options] <script>",
+        summary: "Run a script.",
+        options: "",
+    },
+    Subcommand {
+        name: "check",
+        usage: "rlox check [options] <script>",
+        summary: "Scan, parse and resolve a script without running it.",
+        options: "",
+    },
+    Subcommand {
+        name: "tokens",
+        usage: "rlox tokens [options] [--json] <script>",
+        summary: "Print the tokens of a script, one per line.",
+        options: "  --json                    Print one JSON object per token\n",
+    },
+    Subcommand {
+        name: "ast",
+        usage: "rlox ast [options] [--json] <script>",
+        summary: "Print the syntax tree of a script as S-expressions.",
+        options: "  --json                    Print the tree as JSON with source spans\n",
+    },
+    Subcommand {
+        name: "highlight",
+        usage: "rlox highlight [options] [--ansi] <script>",
+        summary: "Print a script with syntax highlighting as a standalone HTML page.",
+        options: "  --ansi                    Colour the script for a terminal instead\n",
+    },
+    Subcommand {
+        name: "fmt",
+        usage: "rlox fmt [options] [--check] <script>",
+        summary: "Print a script in the standard layout.",
+        options: "  --check                   Fail instead if the script is not in that layout\n",
+    },
+    Subcommand {
+        name: "disasm",
+        usage: "rlox disasm [options] <script>",
+        summary: "Print the bytecode the VM backend compiles a script to.",
+        options: "",
+    },
+    Subcommand {
+        name: "compile",
+        usage: "rlox compile [options] [-o <file>] <script>",
+        summary: "Compile a script to a .loxc bytecode file that 'rlox run' can run.",
+        options:
+            "  -o <file>                 Where to write the bytecode (default: <script>.loxc)\n",
+    },
+    Subcommand {
+        name: "repl",
+        usage: "rlox repl [options]",
+        summary: "Start an interactive session.",
+        options: "",
+    },
+    Subcommand {
+        name: "eval",
+        usage: "rlox eval [options] -e <code>",
+        summary: "Run code given on the command line.",
+        options: "  -e <code>                 The code to run\n",
+    },
+];
+
+const COMMON_OPTIONS: &str = concat!(
+    "  --backend=tree|vm         Engine that runs scripts (default: tree)\n",
+    "  --error-format=text|json  Format of error messages (default: text)\n",
+    "  --trace-execution         Show the VM stack and each instruction as it runs\n",
+    "  --stress-gc               Collect garbage on every VM allocation\n",
+    "  -O0, -O1                  Turn bytecode optimizations off or on (default: on)\n",
+    "  --max-depth=<n>           Deepest nesting of calls allowed (default: 1024)\n",
+    "  --max-steps=<n>           Stop scripts after this many steps (default: no limit)\n",
+    "  --max-memory=<bytes>      Limit the memory script objects hold (default: no limit)\n",
+    "  --sandbox                 Run without the file, environment, clock, exit and\n",
+    "                            import natives, and with step and memory limits\n",
+    "  -h, --help                Print help\n",
+);
+
+/// The general help text.
+pub fn help() -> String {
+    let mut out = String::from(
+        "Usage: rlox [options] <command> [args]\n       rlox [options] [script]\n\nCommands:\n",
+    );
+    for subcommand in SUBCOMMANDS {
+        out.push_str(&format!(
+            "  {:<11}{}\n",
+            subcommand.name, subcommand.summary
+        ));
+    }
+    out.push_str("\nOptions:\n");
+    out.push_str(COMMON_OPTIONS);
+    out.push_str(
+        "\nWithout a command, rlox runs the given script or starts a REPL.\n\
+         Use '-' as the script to read it from standard input.\n",
+    );
+    out
+}
+
+/// The help text for one subcommand.
+pub fn subcommand_help(name: &str) -> String {
+    let subcommand = find_subcommand(name).expect("Unknown subcommand");
+    format!(
+        "Usage: {}\n\n{}\n\nOptions:\n{}{}",
+        subcommand.usage, subcommand.summary, subcommand.options, COMMON_OPTIONS
+    )
+}
+
+fn find_subcommand(name: &str) -> Option<&'static Subcommand> {
+    SUBCOMMANDS
+        .iter()
+        .find(|subcommand| subcommand.name == name)
+}
+
+/// Parses the arguments of the `rlox` binary, including the program name.
+/// Returns a message describing the problem on misuse.
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut error_format = ErrorFormat::Text;
+    let mut backend = None;
+    let mut trace_execution = false;
+    let mut stress_gc = false;
+    let mut optimize = None;
+    let mut max_depth = None;
+    let mut max_steps = None;
+    let mut max_memory = None;
+    let mut sandbox = false;
+    let mut subcommand: Option<&'static str> = None;
+    let mut positional = Vec::new();
+    let mut json = false;
+    let mut ansi = false;
+    let mut check = false;
+    let mut code = None;
+    let mut output = None;
+    let mut help = false;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if let Some(name) = arg.strip_prefix("--error-format=") {
+            error_format = ErrorFormat::parse(name).ok_or_else(|| {
+                format!(
+                    "Unknown error format '{}'. Expected 'text' or 'json'.",
+                    name
+                )
+            })?;
+        } else if let Some(name) = arg.strip_prefix("--backend=") {
+            backend = Backend::parse(name)
+                .map(Some)
+                .ok_or_else(|| format!("Unknown backend '{}'. Expected 'tree' or 'vm'.", name))?;
+        } else if arg == "--trace-execution" {
+            trace_execution = true;
+        } else if arg == "--stress-gc" {
+            stress_gc = true;
+        } else if arg == "--sandbox" {
+            sandbox = true;
+        } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            let depth = depth
+                .parse()
+                .ok()
+                .filter(|depth| *depth > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid maximum depth '{}'. Expected a positive number.",
+                        depth
+                    )
+                })?;
+            max_depth = Some(depth);
+        } else if let Some(steps) = arg.strip_prefix("--max-steps=") {
+            let steps = steps.parse().map_err(|_| {
+                format!(
+                    "Invalid step limit '{}'. Expected a number of steps.",
+                    steps
+                )
+            })?;
+            max_steps = Some(steps);
+        } else if let Some(bytes) = arg.strip_prefix("--max-memory=") {
+            let bytes = bytes.parse().map_err(|_| {
+                format!(
+                    "Invalid memory limit '{}'. Expected a number of bytes.",
+                    bytes
+                )
+            })?;
+            max_memory = Some(bytes);
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            optimize = match level {
+                "0" => Some(false),
+                "1" => Some(true),
+                _ => {
+                    return Err(format!(
+                        "Unknown optimization level '{}'. Expected 0 or 1.",
+                        level
+                    ))
+                }
+            };
+        } else if arg == "-h" || arg == "--help" {
+            help = true;
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "--ansi" {
+            ansi = true;
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--dump-tokens" || arg == "--dump-ast" {
+            // Flag spellings kept from before subcommands existed.
+            subcommand = Some(if arg == "--dump-tokens" {
+                "tokens"
+            } else {
+                "ast"
+            });
+        } else if arg == "-e" {
+            let value = iter.next().ok_or("Option '-e' requires an argument.")?;
+            code = Some(value.clone());
+        } else if arg == "-o" {
+            let value = iter.next().ok_or("Option '-o' requires an argument.")?;
+            output = Some(value.clone());
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("Unknown option '{}'.", arg));
+        } else if let Some(found) = find_subcommand(arg).filter(|_| {
+            // Only the first positional argument can name a subcommand.
+            subcommand.is_none() && positional.is_empty()
+        }) {
+            subcommand = Some(found.name);
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+
+    // Options that only make sense on the VM select it.
+    let vm_option = if trace_execution {
+        Some("--trace-execution")
+    } else if stress_gc {
+        Some("--stress-gc")
+    } else {
+        None
+    };
+    if let (Some(option), Some(Backend::TreeWalker)) = (vm_option, backend) {
+        return Err(format!("Option '{}' requires the VM backend.", option));
+    }
+    // So do compiled scripts.
+    let bytecode = matches!(subcommand, None | Some("run"))
+        && positional.first().is_some_and(|path| is_bytecode(path));
+    if bytecode && backend == Some(Backend::TreeWalker) {
+        return Err("Compiled scripts require the VM backend.".to_string());
+    }
+    let backend = backend.unwrap_or(if vm_option.is_some() || bytecode {
+        Backend::Vm
+    } else {
+        Backend::TreeWalker
+    });
+
+    if let Some(depth) =
+        max_depth.filter(|depth| backend == Backend::TreeWalker && *depth > MAX_TREE_DEPTH)
+    {
+        return Err(format!(
+            "Maximum depth {} is too deep for the tree backend, which allows at most {}. \
+             Use '--backend=vm' for deeper calls.",
+            depth, MAX_TREE_DEPTH
+        ));
+    }
+
+    if help {
+        return Ok(Options {
+            command: Command::Help(subcommand),
+            error_format,
+            backend,
+            trace_execution,
+            stress_gc,
+            optimize: optimize.unwrap_or(true),
+            max_depth: max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+            max_steps,
+            max_memory,
+            sandbox,
+        });
+    }
+
+    let name = subcommand.unwrap_or(if positional.is_empty() { "repl" } else { "run" });
+    if json && name != "tokens" && name != "ast" {
+        return Err(format!("Option '--json' is not supported by '{}'.", name));
+    }
+    if ansi && name != "highlight" {
+        return Err(format!("Option '--ansi' is not supported by '{}'.", name));
+    }
+    if check && name != "fmt" {
+        return Err(format!("Option '--check' is not supported by '{}'.", name));
+    }
+    if let Some(option) = vm_option.filter(|_| !["run", "eval", "repl"].contains(&name)) {
+        return Err(format!(
+            "Option '{}' is not supported by '{}'.",
+            option, name
+        ));
+    }
+    if optimize.is_some() && !["run", "eval", "repl", "disasm", "compile"].contains(&name) {
+        return Err(format!("Option '-O' is not supported by '{}'.", name));
+    }
+    if max_depth.is_some() && !["run", "eval", "repl"].contains(&name) {
+        return Err(format!(
+            "Option '--max-depth' is not supported by '{}'.",
+            name
+        ));
+    }
+    if max_steps.is_some() && !["run", "eval", "repl"].contains(&name) {
+        return Err(format!(
+            "Option '--max-steps' is not supported by '{}'.",
+            name
+        ));
+    }
+    if max_memory.is_some() && !["run", "eval", "repl"].contains(&name) {
+        return Err(format!(
+            "Option '--max-memory' is not supported by '{}'.",
+            name
+        ));
+    }
+    if sandbox && !["run", "eval", "repl"].contains(&name) {
+        return Err(format!(
+            "Option '--sandbox' is not supported by '{}'.",
+            name
+        ));
+    }
+    if code.is_some() && name != "eval" {
+        return Err(format!("Option '-e' is not supported by '{}'.", name));
+    }
+    if output.is_some() && name != "compile" {
+        return Err(format!("Option '-o' is not supported by '{}'.", name));
+    }
+
+    let command = match name {
+        "repl" => {
+            expect_positionals(name, &positional, 0)?;
+            Command::Repl
+        }
+        "eval" => {
+            expect_positionals(name, &positional, 0)?;
+            Command::Eval(code.ok_or("Missing code: use 'rlox eval -e <code>'.")?)
+        }
+        _ => {
+            expect_positionals(name, &positional, 1)?;
+            let input = if positional[0] == "-" {
+                Input::Stdin
+            } else {
+                Input::File(positional[0].clone())
+            };
+            match name {
+                "run" => Command::Run(input),
+                "check" => Command::Check(input),
+                "disasm" => Command::Disasm(input),
+                "fmt" => Command::Fmt(input, check),
+                "compile" => {
+                    let output = match (output, &input) {
+                        (Some(output), _) => output,
+                        (None, Input::File(path)) => Path::new(path)
+                            .with_extension("loxc")
+                            .to_string_lossy()
+                            .into_owned(),
+                        (None, Input::Stdin) => {
+                            return Err(
+                                "Option '-o' is required to compile standard input.".to_string()
+                            )
+                        }
+                    };
+                    Command::Compile(input, output)
+                }
+                "tokens" => Command::Tokens(
+                    input,
+                    if json {
+                        TokenFormat::Json
+                    } else {
+                        TokenFormat::Text
+                    },
+                ),
+                "highlight" => Command::Highlight(
+                    input,
+                    if ansi {
+                        HighlightFormat::Ansi
+                    } else {
+                        HighlightFormat::Html
+                    },
+                ),
+                _ => Command::Ast(
+                    input,
+                    if json {
+                        AstFormat::Json
+                    } else {
+                        AstFormat::Sexpr
+                    },
+                ),
+            }
+        }
+    };
+
+    Ok(Options {
+        command,
+        error_format,
+        backend,
+        trace_execution,
+        stress_gc,
+        optimize: optimize.unwrap_or(true),
+        max_depth: max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+        max_steps,
+        max_memory,
+        sandbox,
+    })
+}
+
+/// Whether `path` names a script compiled by `rlox compile`.
+pub fn is_bytecode(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "loxc")
+}
+
+fn expect_positionals(name: &str, positional: &[String], count: usize) -> Result<(), String> {
+    if positional.len() < count {
+        return Err(format!("Missing script for '{}'.", name));
+    }
+    if positional.len() > count {
+        return Err(format!("Unexpected argument '{}'.", positional[count]));
+    }
+    Ok(())
+}
+
+/// Runs the `rlox` binary with `args` and returns its exit status.
+pub fn run(args: Vec<String>) -> i32 {
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("rlox: {}", message);
+            eprintln!("Run 'rlox --help' for usage.");
+            return EXIT_USAGE;
+        }
+    };
+
+    // The tree walker recurses on the Rust stack, so run it on a thread
+    // with enough for the deepest calls allowed.
+    let stack_size = match options.backend {
+        Backend::TreeWalker => options.max_depth * STACK_PER_CALL + BASE_STACK_SIZE,
+        Backend::Vm => BASE_STACK_SIZE,
+    };
+    let interpreter = thread::Builder::new()
+        .name("rlox".to_string())
+        .stack_size(stack_size)
+        .spawn(move || run_options(options, args));
+    match interpreter {
+        Ok(interpreter) => interpreter
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+        Err(error) => {
+            eprintln!("rlox: Unable to start the interpreter: {}", error);
+            EXIT_USAGE
+        }
+    }
+}
+
+fn run_options(options: Options, args: Vec<String>) -> i32 {
+    let mut interpreter = Interpreter::new_with_args(args);
+    interpreter.error_format = options.error_format;
+    interpreter.set_backend(options.backend);
+    interpreter.set_trace_execution(options.trace_execution);
+    interpreter.set_stress_gc(options.stress_gc);
+    interpreter.set_optimize(options.optimize);
+    interpreter.set_max_depth(options.max_depth);
+    let mut sandbox = if options.sandbox {
+        Sandbox::restricted()
+    } else {
+        Sandbox::unrestricted()
+    };
+    sandbox.max_steps = options.max_steps.or(sandbox.max_steps);
+    sandbox.max_memory = options.max_memory.or(sandbox.max_memory);
+    interpreter.set_sandbox(sandbox);
+
+    match options.command {
+        Command::Help(None) => {
+            print!("{}", help());
+            0
+        }
+        Command::Help(Some(name)) => {
+            print!("{}", subcommand_help(name));
+            0
+        }
+        Command::Repl => {
+            interpreter.run_prompt();
+            0
+        }
+        Command::Eval(code) => {
+            interpreter.set_source_name(Some("<eval>".to_string()));
+            interpreter.run_source(code);
+            interpreter.exit_code()
+        }
+        Command::Run(Input::File(path)) if is_bytecode(&path) => {
+            run_bytecode(&mut interpreter, &path)
+        }
+        Command::Run(input) => with_source(&mut interpreter, &input, |interpreter, source| {
+            interpreter.run_source(source);
+        }),
+        Command::Check(input) => with_source(&mut interpreter, &input, |interpreter, source| {
+            interpreter.check_source(source);
+        }),
+        Command::Tokens(input, format) => {
+            with_source(&mut interpreter, &input, |interpreter, source| {
+                print!("{}", interpreter.dump_tokens(source, format));
+            })
+        }
+        Command::Ast(input, format) => {
+            with_source(&mut interpreter, &input, |interpreter, source| {
+                print!("{}", interpreter.dump_ast(source, format));
+            })
+        }
+        Command::Fmt(input, check) => {
+            let mut unformatted = false;
+            let status = with_source(&mut interpreter, &input, |interpreter, source| {
+                if let Some(formatted) = interpreter.format_source(source.clone()) {
+                    if !check {
+                        print!("{}", formatted);
+                    } else if formatted != source {
+                        eprintln!("rlox: '{}' is not formatted.", input_name(&input));
+                        unformatted = true;
+                    }
+                }
+            });
+            if unformatted {
+                EXIT_UNFORMATTED
+            } else {
+                status
+            }
+        }
+        Command::Disasm(input) => with_source(&mut interpreter, &input, |interpreter, source| {
+            print!("{}", interpreter.disassemble(source));
+        }),
+        Command::Compile(input, output) => {
+            let mut written = true;
+            let status = with_source(&mut interpreter, &input, |interpreter, source| {
+                if let Some(bytes) = interpreter.compile_bytecode(source) {
+                    if let Err(error) = fs::write(&output, bytes) {
+                        eprintln!("rlox: Unable to write '{}': {}", output, error);
+                        written = false;
+                    }
+                }
+            });
+            if written {
+                status
+            } else {
+                EXIT_IO_ERROR
+            }
+        }
+        Command::Highlight(input, format) => {
+            let title = input_name(&input);
+            with_source(&mut interpreter, &input, |_, source| {
+                print!("{}", highlight::render(&source, &title, format));
+            })
+        }
+    }
+}
+
+// Reads `input`, hands it to `action` and returns the resulting status.
+fn with_source<F>(interpreter: &mut Interpreter, input: &Input, action: F) -> i32
+where
+    F: FnOnce(&mut Interpreter, String),
+{
+    let name = input_name(input);
+    let result = match input {
+        Input::File(path) => fs::read_to_string(path),
+        Input::Stdin => {
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer).map(|_| buffer)
+        }
+    };
+
+    match result {
+        Ok(source) => {
+            interpreter.set_source_name(Some(name));
+            action(interpreter, source);
+            interpreter.exit_code()
+        }
+        Err(error) => read_error(&name, &error),
+    }
+}
+
+// The name `input` is reported under.
+fn input_name(input: &Input) -> String {
+    match input {
+        Input::File(path) => path.clone(),
+        Input::Stdin => "<stdin>".to_string(),
+    }
+}
+
+// Loads and runs a script compiled by `rlox compile`.
+fn run_bytecode(interpreter: &mut Interpreter, path: &str) -> i32 {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) => return read_error(path, &error),
+    };
+    interpreter.set_source_name(Some(path.to_string()));
+    match interpreter.run_bytecode(&bytes) {
+        Ok(_) => interpreter.exit_code(),
+        Err(error) => {
+            eprintln!("rlox: Unable to load '{}': {}", path, error);
+            EXIT_DATA_ERROR
+        }
+    }
+}
+
+// Reports that `name` could not be read and returns the matching status.
+fn read_error(name: &str, error: &io::Error) -> i32 {
+    eprintln!("rlox: Unable to read '{}': {}", name, error);
+    match error.kind() {
+        ErrorKind::NotFound | ErrorKind::PermissionDenied => EXIT_NO_INPUT,
+        ErrorKind::InvalidData => EXIT_DATA_ERROR,
+        _ => EXIT_IO_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let mut all = vec!["rlox".to_string()];
+        all.extend(args.iter().map(|a| a.to_string()));
+        parse_args(&all)
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse(args).unwrap().command
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(command(&[]), Command::Repl);
+        assert_eq!(
+            command(&["script.lox"]),
+            Command::Run(Input::File("script.lox".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_subcommands() {
+        assert_eq!(command(&["run", "-"]), Command::Run(Input::Stdin));
+        assert_eq!(
+            command(&["check", "a.lox"]),
+            Command::Check(Input::File("a.lox".to_string()))
+        );
+        assert_eq!(
+            command(&["tokens", "--json", "-"]),
+            Command::Tokens(Input::Stdin, TokenFormat::Json)
+        );
+        assert_eq!(
+            command(&["ast", "-"]),
+            Command::Ast(Input::Stdin, AstFormat::Sexpr)
+        );
+        assert_eq!(
+            command(&["highlight", "a.lox"]),
+            Command::Highlight(Input::File("a.lox".to_string()), HighlightFormat::Html)
+        );
+        assert_eq!(
+            command(&["highlight", "--ansi", "-"]),
+            Command::Highlight(Input::Stdin, HighlightFormat::Ansi)
+        );
+        assert_eq!(
+            command(&["fmt", "a.lox"]),
+            Command::Fmt(Input::File("a.lox".to_string()), false)
+        );
+        assert_eq!(
+            command(&["fmt", "--check", "-"]),
+            Command::Fmt(Input::Stdin, true)
+        );
+        assert_eq!(
+            command(&["disasm", "a.lox"]),
+            Command::Disasm(Input::File("a.lox".to_string()))
+        );
+        assert_eq!(
+            command(&["compile", "a.lox"]),
+            Command::Compile(Input::File("a.lox".to_string()), "a.loxc".to_string())
+        );
+        assert_eq!(
+            command(&["compile", "-", "-o", "b.loxc"]),
+            Command::Compile(Input::Stdin, "b.loxc".to_string())
+        );
+        assert_eq!(command(&["repl"]), Command::Repl);
+        assert_eq!(
+            command(&["eval", "-e", "print 1;"]),
+            Command::Eval("print 1;".to_string())
+        );
+    }
+
+    #[test]
+    fn test_legacy_flags() {
+        assert_eq!(
+            command(&["--dump-tokens", "a.lox"]),
+            Command::Tokens(Input::File("a.lox".to_string()), TokenFormat::Text)
+        );
+        assert_eq!(
+            command(&["--dump-ast", "--json", "a.lox"]),
+            Command::Ast(Input::File("a.lox".to_string()), AstFormat::Json)
+        );
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(command(&["--help"]), Command::Help(None));
+        assert_eq!(command(&["tokens", "-h"]), Command::Help(Some("tokens")));
+        assert!(subcommand_help("eval").starts_with("Usage: rlox eval [options] -e <code>"));
+    }
+
+    #[test]
+    fn test_error_format() {
+        let options = parse(&["--error-format=json", "check", "a.lox"]).unwrap();
+        assert_eq!(options.error_format, ErrorFormat::Json);
+        assert!(parse(&["--error-format=xml"]).is_err());
+    }
+
+    #[test]
+    fn test_backend() {
+        assert_eq!(parse(&["a.lox"]).unwrap().backend, Backend::TreeWalker);
+        let options = parse(&["--backend=vm", "run", "a.lox"]).unwrap();
+        assert_eq!(options.backend, Backend::Vm);
+        assert!(parse(&["--backend=jit", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_optimization_level() {
+        assert!(parse(&["a.lox"]).unwrap().optimize);
+        let options = parse(&["-O0", "disasm", "a.lox"]).unwrap();
+        assert!(!options.optimize);
+        assert_eq!(options.backend, Backend::TreeWalker);
+        assert!(parse(&["-O1", "a.lox"]).unwrap().optimize);
+        assert!(parse(&["-O2", "a.lox"]).is_err());
+        assert!(parse(&["-O0", "tokens", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(parse(&["a.lox"]).unwrap().max_depth, DEFAULT_MAX_DEPTH);
+        let options = parse(&["--max-depth=50", "eval", "-e", "1;"]).unwrap();
+        assert_eq!(options.max_depth, 50);
+        assert!(parse(&["--max-depth=0", "a.lox"]).is_err());
+        assert!(parse(&["--max-depth=deep", "a.lox"]).is_err());
+        assert!(parse(&["--max-depth=50", "check", "a.lox"]).is_err());
+        let too_deep = format!("--max-depth={}", MAX_TREE_DEPTH + 1);
+        assert!(parse(&[&too_deep, "a.lox"]).is_err());
+        assert_eq!(
+            parse(&[&too_deep, "--backend=vm", "a.lox"])
+                .unwrap()
+                .max_depth,
+            MAX_TREE_DEPTH + 1
+        );
+    }
+
+    #[test]
+    fn test_max_steps() {
+        assert_eq!(parse(&["a.lox"]).unwrap().max_steps, None);
+        let options = parse(&["--max-steps=1000", "a.lox"]).unwrap();
+        assert_eq!(options.max_steps, Some(1000));
+        assert!(parse(&["--max-steps=-1", "a.lox"]).is_err());
+        assert!(parse(&["--max-steps=1000", "tokens", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_max_memory() {
+        assert_eq!(parse(&["a.lox"]).unwrap().max_memory, None);
+        let options = parse(&["--max-memory=65536", "repl"]).unwrap();
+        assert_eq!(options.max_memory, Some(65536));
+        assert!(parse(&["--max-memory=64k", "a.lox"]).is_err());
+        assert!(parse(&["--max-memory=65536", "ast", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_sandbox() {
+        assert!(!parse(&["a.lox"]).unwrap().sandbox);
+        let options = parse(&["--sandbox", "--max-steps=1000", "a.lox"]).unwrap();
+        assert!(options.sandbox);
+        assert_eq!(options.max_steps, Some(1000));
+        assert!(parse(&["--sandbox", "eval", "-e", "1;"]).unwrap().sandbox);
+        assert!(parse(&["--sandbox", "check", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_vm_options_select_the_vm() {
+        let options = parse(&["--trace-execution", "a.lox"]).unwrap();
+        assert!(options.trace_execution);
+        assert_eq!(options.backend, Backend::Vm);
+        assert!(parse(&["--trace-execution", "--backend=tree", "a.lox"]).is_err());
+        assert!(parse(&["--trace-execution", "check", "a.lox"]).is_err());
+        let options = parse(&["--stress-gc", "eval", "-e", "1;"]).unwrap();
+        assert!(options.stress_gc);
+        assert_eq!(options.backend, Backend::Vm);
+        assert!(parse(&["--backend=tree", "--stress-gc", "a.lox"]).is_err());
+    }
+
+    #[test]
+    fn test_compiled_scripts_select_the_vm() {
+        assert_eq!(parse(&["a.loxc"]).unwrap().backend, Backend::Vm);
+        assert_eq!(parse(&["run", "a.loxc"]).unwrap().backend, Backend::Vm);
+        assert!(parse(&["--backend=tree", "a.loxc"]).is_err());
+        assert_eq!(
+            parse(&["check", "a.loxc"]).unwrap().backend,
+            Backend::TreeWalker
+        );
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["run", "a.lox", "b.lox"]).is_err());
+        assert!(parse(&["a.lox", "b.lox"]).is_err());
+        assert!(parse(&["eval"]).is_err());
+        assert!(parse(&["run", "--json", "a.lox"]).is_err());
+        assert!(parse(&["ast", "--ansi", "a.lox"]).is_err());
+        assert!(parse(&["check", "--check", "a.lox"]).is_err());
+        assert!(parse(&["run", "-o", "a.loxc", "a.lox"]).is_err());
+        assert!(parse(&["compile", "-"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_script_named_like_a_subcommand_after_another() {
+        assert_eq!(
+            command(&["run", "check"]),
+            Command::Run(Input::File("check".to_string()))
+        );
+    }
+}
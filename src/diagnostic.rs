@@ -0,0 +1,261 @@
+use crate::json;
+use std::fmt;
+
+/// How diagnostics are written to stderr.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ErrorFormat {
+    /// The classic `[line N ] Error ...` lines.
+    Text,
+    /// One JSON object per line, for editors and CI.
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(name: &str) -> Option<ErrorFormat> {
+        match name {
+            "text" => Some(ErrorFormat::Text),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Stable identifiers for every diagnostic the interpreter can produce.
+pub mod codes {
+    /// Reported through `Interpreter::report` without a more specific code.
+    pub const GENERIC: &str = "E0000";
+    pub const UNEXPECTED_CHARACTER: &str = "E0001";
+    pub const UNTERMINATED_STRING: &str = "E0002";
+    pub const UNTERMINATED_COMMENT: &str = "E0003";
+    pub const EXPECT_EXPRESSION: &str = "E0100";
+    pub const EXPECTED_TOKEN: &str = "E0101";
+    pub const INVALID_ASSIGNMENT_TARGET: &str = "E0102";
+    pub const TOO_MANY_ARGUMENTS: &str = "E0103";
+    pub const TOO_MANY_PARAMETERS: &str = "E0104";
+    pub const NESTING_TOO_DEEP: &str = "E0105";
+    pub const ALREADY_DECLARED: &str = "E0200";
+    pub const READ_IN_OWN_INITIALIZER: &str = "E0201";
+    pub const RETURN_FROM_TOP_LEVEL: &str = "E0202";
+    pub const RETURN_FROM_INITIALIZER: &str = "E0203";
+    pub const THIS_OUTSIDE_CLASS: &str = "E0204";
+    pub const SUPER_OUTSIDE_CLASS: &str = "E0205";
+    pub const SUPER_WITHOUT_SUPERCLASS: &str = "E0206";
+    pub const INHERIT_FROM_SELF: &str = "E0207";
+    pub const TYPE_ERROR: &str = "E0300";
+    pub const UNDEFINED_VARIABLE: &str = "E0301";
+    pub const UNDEFINED_PROPERTY: &str = "E0302";
+    pub const NOT_CALLABLE: &str = "E0303";
+    pub const ARITY_MISMATCH: &str = "E0304";
+    pub const INVALID_SUPERCLASS: &str = "E0305";
+    pub const STACK_OVERFLOW: &str = "E0306";
+    pub const STEP_LIMIT: &str = "E0307";
+    pub const OUT_OF_MEMORY: &str = "E0308";
+    pub const IMPORT_FAILED: &str = "E0309";
+    pub const TOO_MANY_CONSTANTS: &str = "E0400";
+    pub const TOO_MANY_LOCALS: &str = "E0401";
+    pub const TOO_MANY_UPVALUES: &str = "E0402";
+    pub const JUMP_TOO_LARGE: &str = "E0403";
+    pub const TOO_MANY_CACHES: &str = "E0404";
+}
+
+/// A single error or warning with its source span.
+///
+/// Lines and columns are 1-based; `end_column` points one past the last
+/// character of the span.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub location: String,
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    pub notes: Vec<String>,
+    /// For runtime errors, the call stack when it happened, innermost
+    /// frame first.
+    pub trace: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: String, line: usize, column: usize) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            location: "".to_string(),
+            file: None,
+            line,
+            column,
+            end_line: line,
+            end_column: column,
+            notes: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn with_end(mut self, end_line: usize, end_column: usize) -> Self {
+        self.end_line = end_line;
+        self.end_column = end_column;
+        self
+    }
+
+    pub fn with_location(mut self, location: String) -> Self {
+        self.location = location;
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Vec<String>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn render(&self, format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Text => self.to_text(),
+            ErrorFormat::Json => self.to_json(),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let label = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        let mut out = format!(
+            "[line {} ] {} {}: {}",
+            self.line, label, self.location, self.message
+        );
+        for note in &self.notes {
+            out.push_str("\n    note: ");
+            out.push_str(note);
+        }
+        for frame in &self.trace {
+            out.push('\n');
+            out.push_str(frame);
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let notes: Vec<String> = self.notes.iter().map(|n| json::quote(n)).collect();
+        let trace: Vec<String> = self.trace.iter().map(|frame| json::quote(frame)).collect();
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"notes\":[{}],\"trace\":[{}]}}",
+            json::quote(&self.severity.to_string()),
+            json::quote(self.code),
+            json::quote(&self.message),
+            json::quote_opt(self.file.as_deref()),
+            self.line,
+            self.column,
+            self.end_line,
+            self.end_column,
+            notes.join(","),
+            trace.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_format_parse() {
+        assert_eq!(ErrorFormat::parse("json"), Some(ErrorFormat::Json));
+        assert_eq!(ErrorFormat::parse("text"), Some(ErrorFormat::Text));
+        assert_eq!(ErrorFormat::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_text_rendering_matches_report_format() {
+        let diagnostic = Diagnostic::error(
+            codes::UNEXPECTED_CHARACTER,
+            "Unexpected character.".to_string(),
+            3,
+            5,
+        );
+        assert_eq!(
+            diagnostic.to_text(),
+            "[line 3 ] Error : Unexpected character."
+        );
+    }
+
+    #[test]
+    fn test_text_rendering_with_notes() {
+        let diagnostic = Diagnostic::error(codes::GENERIC, "Oops.".to_string(), 1, 1)
+            .with_location("at 'x'".to_string())
+            .with_note("first".to_string());
+        assert_eq!(
+            diagnostic.to_text(),
+            "[line 1 ] Error at 'x': Oops.\n    note: first"
+        );
+    }
+
+    #[test]
+    fn test_json_rendering() {
+        let mut diagnostic = Diagnostic::error(
+            codes::UNTERMINATED_STRING,
+            "Unterminated string.".to_string(),
+            2,
+            7,
+        )
+        .with_end(4, 1)
+        .with_note("add a closing \"".to_string());
+        diagnostic.file = Some("main.lox".to_string());
+
+        assert_eq!(
+            diagnostic.to_json(),
+            "{\"severity\":\"error\",\"code\":\"E0002\",\"message\":\"Unterminated string.\",\
+             \"file\":\"main.lox\",\"line\":2,\"column\":7,\"end_line\":4,\"end_column\":1,\
+             \"notes\":[\"add a closing \\\"\"],\"trace\":[]}"
+        );
+    }
+
+    #[test]
+    fn test_json_rendering_without_file() {
+        let diagnostic = Diagnostic::error(codes::GENERIC, "x".to_string(), 1, 1);
+        assert!(diagnostic.to_json().contains("\"file\":null"));
+        assert!(diagnostic.to_json().contains("\"notes\":[]"));
+    }
+
+    #[test]
+    fn test_rendering_with_trace() {
+        let diagnostic =
+            Diagnostic::error(codes::STACK_OVERFLOW, "Stack overflow.".to_string(), 2, 3)
+                .with_location("at ')'".to_string())
+                .with_trace(vec![
+                    "[line 2] in f()".to_string(),
+                    "[line 4] in script".to_string(),
+                ]);
+        assert_eq!(
+            diagnostic.to_text(),
+            "[line 2 ] Error at ')': Stack overflow.\n[line 2] in f()\n[line 4] in script"
+        );
+        assert!(diagnostic
+            .to_json()
+            .ends_with("\"trace\":[\"[line 2] in f()\",\"[line 4] in script\"]}"));
+    }
+}
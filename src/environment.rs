@@ -0,0 +1,188 @@
+use crate::value::{self, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// One scope of variables, chained to the scope that encloses it.
+#[derive(Debug, Default)]
+pub struct Environment {
+    values: HashMap<String, Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Environment::default()
+    }
+
+    pub fn with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Environment {
+            values: HashMap::new(),
+            enclosing: Some(enclosing),
+        }
+    }
+
+    /// Defines or redefines `name` in this scope.
+    pub fn define(&mut self, name: String, value: Value) {
+        self.values.insert(name, value);
+    }
+
+    /// Empties this scope, returning its values and the scope enclosing it.
+    pub(crate) fn take_contents(
+        &mut self,
+    ) -> (HashMap<String, Value>, Option<Rc<RefCell<Environment>>>) {
+        (std::mem::take(&mut self.values), self.enclosing.take())
+    }
+
+    /// Removes `name` from this scope, if it is defined there.
+    pub fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    /// Looks `name` up in this scope and then in the enclosing ones.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.values.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self
+                .enclosing
+                .as_ref()
+                .and_then(|enclosing| enclosing.borrow().get(name)),
+        }
+    }
+
+    /// Assigns to an existing variable. Returns false if it is not defined
+    /// in this scope or any enclosing one.
+    pub fn assign(&mut self, name: &str, value: Value) -> bool {
+        if let Some(slot) = self.values.get_mut(name) {
+            *slot = value;
+            return true;
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow_mut().assign(name, value),
+            None => false,
+        }
+    }
+
+    /// The variables defined directly in this scope, in no particular
+    /// order.
+    pub fn values(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.values.iter()
+    }
+
+    /// The scope this one is nested in, if any.
+    pub fn enclosing(&self) -> Option<&Rc<RefCell<Environment>>> {
+        self.enclosing.as_ref()
+    }
+
+    /// The variables defined directly in this scope, sorted by name.
+    pub fn entries(&self) -> Vec<(String, Value)> {
+        let mut entries: Vec<(String, Value)> = self
+            .values
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Reads `name` from the scope exactly `distance` hops out, as computed
+    /// by the resolver.
+    pub fn get_at(env: &Rc<RefCell<Environment>>, distance: usize, name: &str) -> Option<Value> {
+        Environment::ancestor(env, distance)
+            .borrow()
+            .values
+            .get(name)
+            .cloned()
+    }
+
+    pub fn assign_at(env: &Rc<RefCell<Environment>>, distance: usize, name: &str, value: Value) {
+        Environment::ancestor(env, distance)
+            .borrow_mut()
+            .values
+            .insert(name.to_string(), value);
+    }
+
+    fn ancestor(env: &Rc<RefCell<Environment>>, distance: usize) -> Rc<RefCell<Environment>> {
+        let mut environment = env.clone();
+        for _ in 0..distance {
+            let enclosing = environment
+                .borrow()
+                .enclosing
+                .clone()
+                .expect("Resolver computed a scope distance deeper than the environment chain");
+            environment = enclosing;
+        }
+        environment
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        let (values, enclosing) = self.take_contents();
+        value::drop_links(values.into_values(), enclosing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_define_and_get() {
+        let mut env = Environment::new();
+        env.define("a".to_string(), Value::Number(1.0));
+        assert_eq!(env.get("a"), Some(Value::Number(1.0)));
+        assert_eq!(env.get("b"), None);
+    }
+
+    #[test]
+    fn test_enclosing_lookup_and_assign() {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        globals
+            .borrow_mut()
+            .define("a".to_string(), Value::Number(1.0));
+        let mut local = Environment::with_enclosing(globals.clone());
+
+        assert_eq!(local.get("a"), Some(Value::Number(1.0)));
+        assert!(local.assign("a", Value::Number(2.0)));
+        assert_eq!(globals.borrow().get("a"), Some(Value::Number(2.0)));
+        assert!(!local.assign("missing", Value::Nil));
+    }
+
+    #[test]
+    fn test_entries_are_sorted_and_local() {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        globals.borrow_mut().define("outer".to_string(), Value::Nil);
+        let mut local = Environment::with_enclosing(globals);
+        local.define("b".to_string(), Value::Number(2.0));
+        local.define("a".to_string(), Value::Number(1.0));
+
+        assert_eq!(
+            local.entries(),
+            vec![
+                ("a".to_string(), Value::Number(1.0)),
+                ("b".to_string(), Value::Number(2.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_at_distance() {
+        let outer = Rc::new(RefCell::new(Environment::new()));
+        outer
+            .borrow_mut()
+            .define("a".to_string(), Value::Bool(true));
+        let inner = Rc::new(RefCell::new(Environment::with_enclosing(outer)));
+        inner
+            .borrow_mut()
+            .define("a".to_string(), Value::Bool(false));
+
+        assert_eq!(
+            Environment::get_at(&inner, 0, "a"),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(Environment::get_at(&inner, 1, "a"), Some(Value::Bool(true)));
+        Environment::assign_at(&inner, 1, "a", Value::Nil);
+        assert_eq!(Environment::get_at(&inner, 1, "a"), Some(Value::Nil));
+    }
+}
@@ -0,0 +1,448 @@
+//! Prints syntax trees back as Lox source in one consistent layout, for
+//! `rlox fmt`. The tree has no comments, so they are carried over from the
+//! source by line. The parser turns `for` loops into `while` loops, and the
+//! formatter turns them back.
+
+use crate::ast::{Expr, ExprKind, Function, LiteralValue, Span, Stmt, StmtKind};
+use std::ops::Range;
+
+const INDENT: &str = "  ";
+
+// A comment from the source.
+struct Comment {
+    line: usize,
+    end_line: usize,
+    text: String,
+    // Whether code comes before the comment on its line.
+    trailing: bool,
+}
+
+/// Formats `statements`, parsed from `source`, keeping the comments at the
+/// byte ranges `comments`.
+pub fn format_program(statements: &[Stmt], source: &str, comments: &[Range<usize>]) -> String {
+    let mut formatter = Formatter::new(source, comments);
+    for statement in statements {
+        formatter.statement_line(statement);
+    }
+    formatter.flush_comments(usize::MAX);
+    formatter.out
+}
+
+struct Formatter<'s> {
+    source: &'s str,
+    // Byte offset of the start of each line.
+    line_starts: Vec<usize>,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    out: String,
+    depth: usize,
+    // The source line the last code or comment written ends on, and
+    // whether nothing has been written in the current block yet.
+    last_line: usize,
+    at_block_start: bool,
+}
+
+impl<'s> Formatter<'s> {
+    fn new(source: &'s str, comments: &[Range<usize>]) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        let comments = comments
+            .iter()
+            .map(|range| {
+                let line = line_starts.partition_point(|&start| start <= range.start);
+                let text = source[range.clone()].trim_end().to_string();
+                Comment {
+                    line,
+                    end_line: line + text.matches('\n').count(),
+                    trailing: !source[line_starts[line - 1]..range.start].trim().is_empty(),
+                    text,
+                }
+            })
+            .collect();
+        Formatter {
+            source,
+            line_starts,
+            comments,
+            next_comment: 0,
+            out: String::new(),
+            depth: 0,
+            last_line: 0,
+            at_block_start: true,
+        }
+    }
+
+    // Writes a statement on lines of its own, with the comments before it.
+    fn statement_line(&mut self, stmt: &Stmt) {
+        self.start_line(stmt.span.line);
+        self.statement(stmt);
+        self.end_line(stmt.span.end_line);
+    }
+
+    fn start_line(&mut self, line: usize) {
+        self.flush_comments(line);
+        self.separate(line);
+        self.indent();
+    }
+
+    fn end_line(&mut self, line: usize) {
+        self.out.push('\n');
+        self.last_line = line;
+        self.at_block_start = false;
+    }
+
+    // Keeps one blank line where the source has any between two things.
+    fn separate(&mut self, line: usize) {
+        if !self.at_block_start && line > self.last_line + 1 {
+            self.out.push('\n');
+        }
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    // Writes the comments on lines before `line`. Those after code stay at
+    // the end of its line.
+    fn flush_comments(&mut self, line: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.line >= line {
+                break;
+            }
+            let (text, start, end) = (comment.text.clone(), comment.line, comment.end_line);
+            if comment.trailing && start == self.last_line && self.out.ends_with('\n') {
+                self.out.pop();
+                self.out.push(' ');
+                self.out.push_str(&text);
+                self.out.push('\n');
+            } else {
+                self.separate(start);
+                self.indent();
+                self.out.push_str(&text);
+                self.end_line(end);
+            }
+            self.last_line = end;
+            self.next_comment += 1;
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Block { statements } => match statements.as_slice() {
+                [initializer, while_loop] if self.is_for_loop(stmt.span, while_loop) => {
+                    self.for_loop(Some(initializer), while_loop)
+                }
+                _ => self.block(statements, stmt.span),
+            },
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                self.out.push_str("class ");
+                self.out.push_str(&name.lexeme);
+                if let Some(superclass) = superclass {
+                    self.out.push_str(" < ");
+                    self.out.push_str(&expr(superclass));
+                }
+                self.out.push(' ');
+                self.open_block(stmt.span, methods.is_empty());
+                for method in methods {
+                    self.start_line(method.span.line);
+                    self.function(method);
+                    self.end_line(method.span.end_line);
+                }
+                self.close_block(stmt.span);
+            }
+            StmtKind::Expression { expression } => {
+                self.out.push_str(&expr(expression));
+                self.out.push(';');
+            }
+            StmtKind::Function { function } => {
+                self.out.push_str("fun ");
+                self.function(function);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.out.push_str(&format!("if ({})", expr(condition)));
+                self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    if is_block(then_branch) {
+                        self.out.push(' ');
+                    } else {
+                        self.out.push('\n');
+                        self.indent();
+                    }
+                    self.out.push_str("else");
+                    if let StmtKind::If { .. } = else_branch.kind {
+                        self.out.push(' ');
+                        self.statement(else_branch);
+                    } else {
+                        self.body(else_branch);
+                    }
+                }
+            }
+            StmtKind::Print { expression } => {
+                self.out.push_str(&format!("print {};", expr(expression)));
+            }
+            StmtKind::Return { value, .. } => match value {
+                Some(value) => self.out.push_str(&format!("return {};", expr(value))),
+                None => self.out.push_str("return;"),
+            },
+            StmtKind::Var { name, initializer } => {
+                self.out.push_str("var ");
+                self.out.push_str(&name.lexeme);
+                if let Some(initializer) = initializer {
+                    self.out.push_str(" = ");
+                    self.out.push_str(&expr(initializer));
+                }
+                self.out.push(';');
+            }
+            StmtKind::While { condition, body } => {
+                if self.starts_with(stmt.span, "for") {
+                    self.for_loop(None, stmt);
+                } else {
+                    self.out.push_str(&format!("while ({})", expr(condition)));
+                    self.body(body);
+                }
+            }
+        }
+    }
+
+    // Writes the name, parameters and body of a function or method.
+    fn function(&mut self, function: &Function) {
+        let params: Vec<&str> = function.params.iter().map(|p| p.lexeme.as_str()).collect();
+        self.out
+            .push_str(&format!("{}({}) ", function.name.lexeme, params.join(", ")));
+        self.block(&function.body, function.span);
+    }
+
+    // Writes the body of an `if` or loop: blocks on the same line, other
+    // statements indented on the next.
+    fn body(&mut self, stmt: &Stmt) {
+        if is_block(stmt) {
+            self.out.push(' ');
+            self.statement(stmt);
+        } else {
+            self.out.push('\n');
+            self.depth += 1;
+            self.indent();
+            self.statement(stmt);
+            self.depth -= 1;
+        }
+    }
+
+    fn block(&mut self, statements: &[Stmt], span: Span) {
+        self.open_block(span, statements.is_empty());
+        for statement in statements {
+            self.statement_line(statement);
+        }
+        self.close_block(span);
+    }
+
+    // Opens a block spanning `span`. Empty ones, comments aside, stay on
+    // one line.
+    fn open_block(&mut self, span: Span, empty: bool) {
+        let has_comments = self
+            .comments
+            .get(self.next_comment)
+            .is_some_and(|comment| comment.line < span.end_line);
+        self.out.push('{');
+        if empty && !has_comments {
+            return;
+        }
+        self.out.push('\n');
+        self.depth += 1;
+        self.last_line = span.line;
+        self.at_block_start = true;
+    }
+
+    fn close_block(&mut self, span: Span) {
+        if !self.out.ends_with('{') {
+            self.flush_comments(span.end_line);
+            self.depth -= 1;
+            self.indent();
+        }
+        self.out.push('}');
+    }
+
+    // Writes a `for` loop that the parser turned into `while_loop`, inside
+    // a block with `initializer` if it had one.
+    fn for_loop(&mut self, initializer: Option<&Stmt>, while_loop: &Stmt) {
+        let (condition, body) = match &while_loop.kind {
+            StmtKind::While { condition, body } => (condition, body),
+            _ => unreachable!("For loops are parsed as while loops"),
+        };
+        self.out.push_str("for (");
+        match initializer {
+            Some(initializer) => self.statement(initializer),
+            None => self.out.push(';'),
+        }
+        // A missing condition is parsed as `true` at the semicolon.
+        if !self.starts_with(condition.span, ";") {
+            self.out.push(' ');
+            self.out.push_str(&expr(condition));
+        }
+        self.out.push(';');
+        // The increment runs after the body, in a block spanning just it.
+        let body = match &body.kind {
+            StmtKind::Block { statements } => match statements.as_slice() {
+                [inner, Stmt {
+                    kind: StmtKind::Expression { expression },
+                    ..
+                }] if inner.span == body.span => {
+                    self.out.push(' ');
+                    self.out.push_str(&expr(expression));
+                    inner
+                }
+                _ => body,
+            },
+            _ => body,
+        };
+        self.out.push(')');
+        self.body(body);
+    }
+
+    fn is_for_loop(&self, span: Span, stmt: &Stmt) -> bool {
+        matches!(stmt.kind, StmtKind::While { .. })
+            && stmt.span == span
+            && self.starts_with(span, "for")
+    }
+
+    // Whether the source at the start of `span` reads `text`.
+    fn starts_with(&self, span: Span, text: &str) -> bool {
+        let line_start = match self.line_starts.get(span.line.wrapping_sub(1)) {
+            Some(&start) => start,
+            None => return false,
+        };
+        let line = &self.source[line_start..];
+        match line.char_indices().nth(span.column.saturating_sub(1)) {
+            Some((offset, _)) => line[offset..].starts_with(text),
+            None => false,
+        }
+    }
+}
+
+fn is_block(stmt: &Stmt) -> bool {
+    matches!(stmt.kind, StmtKind::Block { .. })
+}
+
+fn expr(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Assign { name, value } => format!("{} = {}", name.lexeme, self::expr(value)),
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+        }
+        | ExprKind::Logical {
+            left,
+            operator,
+            right,
+        } => format!(
+            "{} {} {}",
+            self::expr(left),
+            operator.lexeme,
+            self::expr(right)
+        ),
+        ExprKind::Call {
+            callee, arguments, ..
+        } => {
+            let arguments: Vec<String> = arguments.iter().map(self::expr).collect();
+            format!("{}({})", self::expr(callee), arguments.join(", "))
+        }
+        ExprKind::Get { object, name } => format!("{}.{}", self::expr(object), name.lexeme),
+        ExprKind::Grouping { expression } => format!("({})", self::expr(expression)),
+        ExprKind::Literal { value } => match value {
+            LiteralValue::Nil => "nil".to_string(),
+            LiteralValue::Bool(b) => b.to_string(),
+            LiteralValue::Number(n) => n.to_string(),
+            LiteralValue::String(s) => format!("\"{}\"", s),
+        },
+        ExprKind::Set {
+            object,
+            name,
+            value,
+        } => format!(
+            "{}.{} = {}",
+            self::expr(object),
+            name.lexeme,
+            self::expr(value)
+        ),
+        ExprKind::Super { method, .. } => format!("super.{}", method.lexeme),
+        ExprKind::This { .. } => "this".to_string(),
+        ExprKind::Unary { operator, right } => {
+            format!("{}{}", operator.lexeme, self::expr(right))
+        }
+        ExprKind::Variable { name } => name.lexeme.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn format(source: &str) -> String {
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens().clone();
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse();
+        assert!(parser.errors().is_empty());
+        format_program(&statements, source, scanner.comments())
+    }
+
+    #[test]
+    fn test_layout() {
+        let source = "var a=1;fun add(x,y){return x+y;}\n\n\n\
+                      class B<A{init(){this.n=-a;}get(){return super.get();}}\n\
+                      if(a>0)print \"yes\";else if (a<0) {print (a);} else print nil;\n\
+                      while(a<3)a=a+1;{}";
+        assert_eq!(
+            format(source),
+            "var a = 1;\n\
+             fun add(x, y) {\n  return x + y;\n}\n\
+             \n\
+             class B < A {\n  init() {\n    this.n = -a;\n  }\n  get() {\n    return super.get();\n  }\n}\n\
+             if (a > 0)\n  print \"yes\";\nelse if (a < 0) {\n  print (a);\n} else\n  print nil;\n\
+             while (a < 3)\n  a = a + 1;\n\
+             {}\n"
+        );
+    }
+
+    #[test]
+    fn test_for_loops_are_kept() {
+        assert_eq!(
+            format("for(var i=0;i<3;i=i+1){print i;}\nfor(;;)print 1;\nfor(i=0;i<1;)i=2;"),
+            "for (var i = 0; i < 3; i = i + 1) {\n  print i;\n}\n\
+             for (;;)\n  print 1;\n\
+             for (i = 0; i < 1;)\n  i = 2;\n"
+        );
+        // A while loop that looks like a desugared for loop stays one.
+        assert_eq!(
+            format("var i = 0;\nwhile (true) { print i; i = i + 1; }"),
+            "var i = 0;\nwhile (true) {\n  print i;\n  i = i + 1;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_comments_are_kept() {
+        let source = "// header\n\nvar a = 1; // one\nfun f() {\n  // inside\n}\n/* block\n   comment */\nprint a;\n// end";
+        assert_eq!(
+            format(source),
+            "// header\n\nvar a = 1; // one\nfun f() {\n  // inside\n}\n/* block\n   comment */\nprint a;\n// end\n"
+        );
+    }
+
+    #[test]
+    fn test_formatting_is_idempotent() {
+        let source = "class A{m(){for(var i=0;i<2;i=i+1)if(i)print i;else{return;}}}// done";
+        let once = format(source);
+        assert_eq!(format(&once), once);
+    }
+}
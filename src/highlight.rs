@@ -0,0 +1,338 @@
+use crate::scanner::Scanner;
+use crate::token::TokenType;
+use std::ops::Range;
+
+/// Output formats for `rlox highlight`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HighlightFormat {
+    /// A standalone HTML page with one CSS class per category.
+    Html,
+    /// Text coloured with ANSI escape codes.
+    Ansi,
+}
+
+// Colours for the HTML output, one rule per `Category::css_class`.
+const STYLESHEET: &str = concat!(
+    "pre.lox { background: #fafafa; color: #24292e; padding: 1em; }\n",
+    ".lox-keyword { color: #a626a4; font-weight: bold; }\n",
+    ".lox-string { color: #50a14f; }\n",
+    ".lox-number { color: #986801; }\n",
+    ".lox-comment { color: #a0a1a7; font-style: italic; }\n",
+    ".lox-operator { color: #0184bc; }\n",
+    ".lox-identifier { color: #24292e; }\n",
+);
+
+/// What a piece of source text is, for the purpose of colouring it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Category {
+    Keyword,
+    String,
+    Number,
+    Comment,
+    Operator,
+    Identifier,
+    /// Whitespace, and characters the scanner could not make sense of.
+    Plain,
+}
+
+impl Category {
+    /// The category of every token of type `token_type`. Punctuation such
+    /// as parentheses and `;` counts as an operator.
+    pub fn of(token_type: &TokenType) -> Category {
+        match token_type {
+            TokenType::And
+            | TokenType::Class
+            | TokenType::Else
+            | TokenType::False
+            | TokenType::Fun
+            | TokenType::For
+            | TokenType::If
+            | TokenType::Nil
+            | TokenType::Or
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Super
+            | TokenType::This
+            | TokenType::True
+            | TokenType::Var
+            | TokenType::While => Category::Keyword,
+            TokenType::String => Category::String,
+            TokenType::Number => Category::Number,
+            TokenType::Identifier => Category::Identifier,
+            TokenType::Eof => Category::Plain,
+            _ => Category::Operator,
+        }
+    }
+
+    /// The CSS class of this category in HTML output, or `None` for text
+    /// that is left unstyled.
+    pub fn css_class(&self) -> Option<&'static str> {
+        match self {
+            Category::Keyword => Some("lox-keyword"),
+            Category::String => Some("lox-string"),
+            Category::Number => Some("lox-number"),
+            Category::Comment => Some("lox-comment"),
+            Category::Operator => Some("lox-operator"),
+            Category::Identifier => Some("lox-identifier"),
+            Category::Plain => None,
+        }
+    }
+
+    /// The SGR parameters used to colour this category on a terminal, or
+    /// `None` to leave it in the default colour.
+    pub fn ansi_style(&self) -> Option<&'static str> {
+        match self {
+            Category::Keyword => Some("1;35"),
+            Category::String => Some("32"),
+            Category::Number => Some("33"),
+            Category::Comment => Some("90"),
+            Category::Operator => Some("36"),
+            Category::Identifier | Category::Plain => None,
+        }
+    }
+}
+
+/// A byte range of the source and its category.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StyledSpan {
+    pub category: Category,
+    pub range: Range<usize>,
+}
+
+/// Splits `source` into styled spans that cover it from start to end,
+/// whitespace and comments included.
+///
+/// Works on any input: it relies on the scanner's error recovery, so an
+/// unterminated string is styled as a string up to the end of the source
+/// and unexpected characters are left plain.
+pub fn highlight(source: &str) -> Vec<StyledSpan> {
+    let mut scanner = Scanner::new(source.to_string());
+    let mut pieces: Vec<StyledSpan> = scanner
+        .scan_tokens()
+        .iter()
+        .filter(|token| token.token_type != TokenType::Eof)
+        .map(|token| StyledSpan {
+            category: Category::of(&token.token_type),
+            range: token.offset..token.offset + token.lexeme.len(),
+        })
+        .collect();
+    pieces.extend(scanner.comments().iter().map(|range| StyledSpan {
+        category: Category::Comment,
+        range: range.clone(),
+    }));
+    pieces.sort_by_key(|span| span.range.start);
+
+    let mut spans = Vec::new();
+    let mut position = 0;
+    for piece in pieces {
+        fill_gap(source, position..piece.range.start, &mut spans);
+        position = piece.range.end;
+        spans.push(piece);
+    }
+    fill_gap(source, position..source.len(), &mut spans);
+    spans
+}
+
+/// Highlights `line` as the continuation of `before`, so that a line inside
+/// an open string or block comment is styled as such. The spans returned
+/// are relative to `line`.
+pub fn highlight_continuation(before: &str, line: &str) -> Vec<StyledSpan> {
+    let offset = before.len();
+    highlight(&format!("{}{}", before, line))
+        .into_iter()
+        .filter(|span| span.range.end > offset)
+        .map(|span| StyledSpan {
+            category: span.category,
+            range: span.range.start.max(offset) - offset..span.range.end - offset,
+        })
+        .collect()
+}
+
+// Styles text the scanner produced nothing for. Apart from whitespace and
+// unexpected characters, that can only be an unterminated string, which
+// runs to the end of the source.
+fn fill_gap(source: &str, gap: Range<usize>, spans: &mut Vec<StyledSpan>) {
+    if gap.is_empty() {
+        return;
+    }
+    match source[gap.clone()].find('"') {
+        Some(quote) => {
+            let quote = gap.start + quote;
+            if quote > gap.start {
+                spans.push(StyledSpan {
+                    category: Category::Plain,
+                    range: gap.start..quote,
+                });
+            }
+            spans.push(StyledSpan {
+                category: Category::String,
+                range: quote..gap.end,
+            });
+        }
+        None => spans.push(StyledSpan {
+            category: Category::Plain,
+            range: gap,
+        }),
+    }
+}
+
+/// Renders `source` with ANSI escape codes for a terminal.
+pub fn to_ansi(source: &str) -> String {
+    render_ansi(source, &highlight(source))
+}
+
+/// Renders the given spans of `source` with ANSI escape codes.
+pub fn render_ansi(source: &str, spans: &[StyledSpan]) -> String {
+    let mut out = String::with_capacity(source.len() * 2);
+    for span in spans {
+        let text = &source[span.range.clone()];
+        match span.category.ansi_style() {
+            Some(style) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", style, text)),
+            None => out.push_str(text),
+        }
+    }
+    out
+}
+
+/// Renders `source` as a standalone HTML page titled `title`.
+pub fn to_html(source: &str, title: &str) -> String {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    out.push_str(&format!("<style>\n{}</style>\n", STYLESHEET));
+    out.push_str("</head>\n<body>\n<pre class=\"lox\"><code>");
+    for span in highlight(source) {
+        let text = escape_html(&source[span.range]);
+        match span.category.css_class() {
+            Some(class) => out.push_str(&format!("<span class=\"{}\">{}</span>", class, text)),
+            None => out.push_str(&text),
+        }
+    }
+    out.push_str("</code></pre>\n</body>\n</html>\n");
+    out
+}
+
+/// Renders `source` in the given format. `title` names the page in HTML.
+pub fn render(source: &str, title: &str, format: HighlightFormat) -> String {
+    match format {
+        HighlightFormat::Html => to_html(source, title),
+        HighlightFormat::Ansi => to_ansi(source),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn categories(source: &str) -> Vec<(Category, &str)> {
+        highlight(source)
+            .into_iter()
+            .map(|span| (span.category, &source[span.range]))
+            .collect()
+    }
+
+    #[test]
+    fn test_categories() {
+        assert_eq!(
+            categories("var x = 1.5; // done"),
+            vec![
+                (Category::Keyword, "var"),
+                (Category::Plain, " "),
+                (Category::Identifier, "x"),
+                (Category::Plain, " "),
+                (Category::Operator, "="),
+                (Category::Plain, " "),
+                (Category::Number, "1.5"),
+                (Category::Operator, ";"),
+                (Category::Plain, " "),
+                (Category::Comment, "// done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partial_and_invalid_input() {
+        assert_eq!(
+            categories("print @ \"unfinished\nline"),
+            vec![
+                (Category::Keyword, "print"),
+                (Category::Plain, " @ "),
+                (Category::String, "\"unfinished\nline"),
+            ]
+        );
+        assert_eq!(
+            categories("nil /* open"),
+            vec![
+                (Category::Keyword, "nil"),
+                (Category::Plain, " "),
+                (Category::Comment, "/* open"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans_cover_source() {
+        let source = "class A < B {\n  m() { return \"é\" + this.x; }\n}\n";
+        let spans = highlight(source);
+        let mut position = 0;
+        for span in &spans {
+            assert_eq!(span.range.start, position);
+            position = span.range.end;
+        }
+        assert_eq!(position, source.len());
+    }
+
+    #[test]
+    fn test_continuation_lines() {
+        let line = "still comment */ x";
+        let spans: Vec<(Category, &str)> = highlight_continuation("1; /* open\n", line)
+            .into_iter()
+            .map(|span| (span.category, &line[span.range]))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (Category::Comment, "still comment */"),
+                (Category::Plain, " "),
+                (Category::Identifier, "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_rendering() {
+        let html = to_html("print a < \"<b>\"; // &\n", "a&b.lox");
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<title>a&amp;b.lox</title>"));
+        assert!(html.contains(".lox-keyword {"));
+        assert!(html.contains(
+            "<pre class=\"lox\"><code><span class=\"lox-keyword\">print</span> \
+             <span class=\"lox-identifier\">a</span> \
+             <span class=\"lox-operator\">&lt;</span> \
+             <span class=\"lox-string\">&quot;&lt;b&gt;&quot;</span>\
+             <span class=\"lox-operator\">;</span> \
+             <span class=\"lox-comment\">// &amp;</span>\n</code></pre>"
+        ));
+    }
+
+    #[test]
+    fn test_ansi_rendering() {
+        assert_eq!(
+            to_ansi("print x;"),
+            "\x1b[1;35mprint\x1b[0m x\x1b[36m;\x1b[0m"
+        );
+    }
+}
@@ -1,60 +1,450 @@
+use crate::ast::{Stmt, StmtKind};
+use crate::ast_printer::{self, AstFormat};
+use crate::diagnostic::{codes, Diagnostic, ErrorFormat};
+use crate::formatter;
+use crate::line_editor::LineEditor;
+use crate::parser::Parser;
+use crate::repl::{self, Input, LineReader};
+use crate::resolver::Resolver;
+use crate::runtime::{self, RuntimeError};
+use crate::sandbox::Sandbox;
 use crate::scanner::Scanner;
-use std::fs::File;
+use crate::token::{Token, TokenFormat};
+use crate::tree_walker::TreeWalker;
+use crate::value::Value;
+use crate::vm::loxc::{self, LoadError};
+use crate::vm::object::ObjRef;
+use crate::vm::{disassembler, Vm};
+use std::cell::RefCell;
+use std::fs::{self, File};
 use std::io::Read;
-use std::io::Write;
+use std::io::{BufRead, IsTerminal, Write};
+use std::rc::Rc;
+use std::time::Instant;
 use std::{env, io};
 
+/// Exit status for scripts with lexical, syntax or resolution errors.
+pub const EXIT_DATA_ERROR: i32 = 65;
+/// Exit status for scripts that fail at runtime.
+pub const EXIT_SOFTWARE: i32 = 70;
+
+/// Which engine runs programs once they have been parsed and resolved.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Backend {
+    /// Walks the syntax tree directly.
+    TreeWalker,
+    /// Compiles to bytecode and runs it on a stack VM.
+    Vm,
+}
+
+impl Backend {
+    pub fn parse(name: &str) -> Option<Backend> {
+        match name {
+            "tree" => Some(Backend::TreeWalker),
+            "vm" => Some(Backend::Vm),
+            _ => None,
+        }
+    }
+}
+
+// The writer given to `set_output`, shared by both backends so output goes
+// to it whichever one runs.
+#[derive(Clone)]
+struct SharedWriter(Rc<RefCell<Box<dyn Write>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
 #[derive(Debug)]
 pub struct Interpreter {
     pub args: Vec<String>,
+    pub error_format: ErrorFormat,
+    // Name of the script being run, attached to diagnostics.
+    source_name: Option<String>,
+    backend: Backend,
+    tree_walker: TreeWalker,
+    vm: Vm,
     had_error: bool,
+    // The runtime error that stopped the last run, if any.
+    last_runtime_error: Option<RuntimeError>,
+    // The status the last run passed to `exit`, if it called it.
+    exit_status: Option<i32>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Interpreter {
-        Interpreter {
-            args: env::args().collect(),
-            had_error: false,
-        }
+        Interpreter::new_with_args(env::args().collect())
     }
 
     pub fn run_file(&mut self) {
-        let mut f = File::open(&self.args[1]).expect("Unable to open file");
+        let path = self.args[1].clone();
+        let buffer = self.read_file(&path);
+        self.run(buffer);
+        if self.exit_code() != 0 {
+            std::process::exit(self.exit_code())
+        }
+    }
+
+    fn read_file(&mut self, path: &str) -> String {
+        let mut f = File::open(path).expect("Unable to open file");
         let mut buffer = String::new();
         f.read_to_string(&mut buffer)
             .expect("Unable to read file to string");
-        self.run(buffer);
-        if self.had_error {
-            std::process::exit(65)
-        }
+        self.source_name = Some(path.to_string());
+        buffer
     }
 
+    /// Starts an interactive session on standard input. On a terminal the
+    /// prompt supports line editing, history and tab completion.
     pub fn run_prompt(&mut self) {
+        let stdin = io::stdin();
+        if stdin.is_terminal() {
+            match LineEditor::new() {
+                Ok(mut editor) => {
+                    self.run_repl_with(&mut editor);
+                    editor.save_history();
+                    return;
+                }
+                Err(error) => eprintln!("Line editing unavailable: {}", error),
+            }
+        }
+        self.run_repl(stdin.lock());
+    }
+
+    /// Runs a REPL session that reads plain lines from `input`.
+    pub fn run_repl<R: BufRead>(&mut self, input: R) {
+        self.run_repl_with(&mut repl::PlainReader::new(input));
+    }
+
+    /// Reads and runs input from `reader` until EOF or `:quit`. Unfinished
+    /// input, such as an open block, is collected over several lines behind
+    /// a continuation prompt. Definitions persist from one piece of input to
+    /// the next and errors do not end the session.
+    pub fn run_repl_with(&mut self, reader: &mut dyn LineReader) {
+        let mut pending = String::new();
         loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-            print!("> ");
-            io::stdout().flush().unwrap();
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
-            let buffer = buffer.trim();
-            if buffer.is_empty() {
-                break;
+            reader.set_globals(self.global_members());
+            reader.set_pending(&pending);
+            let prompt = if pending.is_empty() {
+                repl::PROMPT
+            } else {
+                repl::CONTINUATION_PROMPT
+            };
+            let line = match reader.read_line(prompt) {
+                Ok(Input::Line(line)) => line,
+                Ok(Input::Interrupted) => {
+                    pending.clear();
+                    continue;
+                }
+                Ok(Input::Eof) => {
+                    println!();
+                    if !pending.trim().is_empty() {
+                        self.run_line(pending.trim_end().to_string());
+                        self.report_exit();
+                    }
+                    break;
+                }
+                Err(error) => {
+                    eprintln!("Unable to read line from user: {}", error);
+                    break;
+                }
+            };
+            if pending.is_empty() {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                if repl::is_command(line) {
+                    match repl::parse_command(line) {
+                        Ok(repl::Command::Quit) => break,
+                        Ok(command) => {
+                            self.run_command(command);
+                            self.report_exit();
+                        }
+                        Err(message) => eprintln!("{}", message),
+                    }
+                    continue;
+                }
+            }
+            pending.push_str(&line);
+            pending.push('\n');
+            if repl::is_complete(&pending) {
+                let source = std::mem::take(&mut pending);
+                self.run_line(source.trim_end().to_string());
+                self.report_exit();
             }
-            self.run(buffer.to_string());
-            self.had_error = false;
         }
     }
 
+    // Tells the REPL user that the input just run called `exit`, which
+    // stops that input but not the session.
+    fn report_exit(&mut self) {
+        if let Some(status) = self.exit_status.take() {
+            eprintln!(
+                "Exited with status {}. Type :quit to leave the REPL.",
+                status
+            );
+        }
+    }
+
+    /// Runs one piece of REPL input and prints the value of each top-level
+    /// expression statement. A lone expression may leave off its trailing
+    /// `;`. Returns whether any errors were reported.
+    pub fn run_line(&mut self, source: String) -> bool {
+        self.had_error = false;
+        self.last_runtime_error = None;
+        self.exit_status = None;
+
+        let statements = self.parse_line(source);
+        if !self.had_error && self.resolve(&statements) {
+            self.execute(&statements, true);
+        }
+        self.had_error || self.last_runtime_error.is_some()
+    }
+
+    // Parses one piece of REPL input, where a lone expression may leave off
+    // its trailing `;`.
+    fn parse_line(&mut self, source: String) -> Vec<Stmt> {
+        let tokens = self.scan(source);
+        match Parser::new(tokens.clone()).parse_expression() {
+            Some(expression) => vec![Stmt {
+                span: expression.span,
+                kind: StmtKind::Expression { expression },
+            }],
+            None => self.parse_tokens(tokens),
+        }
+    }
+
+    // Carries out a REPL colon command other than `:quit`.
+    fn run_command(&mut self, command: repl::Command) {
+        match command {
+            repl::Command::Help => print!("{}", repl::help()),
+            repl::Command::Quit => {}
+            repl::Command::Load(path) => match fs::read_to_string(&path) {
+                Ok(source) => {
+                    let previous = self.source_name.replace(path);
+                    self.had_error = false;
+                    self.last_runtime_error = None;
+                    self.exit_status = None;
+                    self.run(source);
+                    self.source_name = previous;
+                }
+                Err(error) => eprintln!("Could not read '{}': {}", path, error),
+            },
+            repl::Command::Reset => match self.backend {
+                Backend::TreeWalker => self.tree_walker.reset(),
+                Backend::Vm => self.vm.reset(),
+            },
+            repl::Command::Env => match self.backend {
+                Backend::TreeWalker => {
+                    for (name, value) in self.globals() {
+                        println!("{} = {}", name, value);
+                    }
+                }
+                Backend::Vm => {
+                    for (name, value) in self.vm.globals() {
+                        println!("{} = {}", name, value);
+                    }
+                }
+            },
+            repl::Command::Tokens(code) => print!("{}", self.dump_tokens(code, TokenFormat::Text)),
+            repl::Command::Ast(code) => {
+                let statements = self.parse_line(code);
+                print!(
+                    "{}",
+                    ast_printer::print_program(&statements, AstFormat::Sexpr)
+                );
+            }
+            repl::Command::Time(code) => {
+                let start = Instant::now();
+                self.run_line(code);
+                println!("took {:.3?}", start.elapsed());
+            }
+        }
+        io::stdout().flush().unwrap();
+    }
+
     fn run(&mut self, source: String) {
+        let statements = match self.compile(source) {
+            Some(statements) => statements,
+            None => return,
+        };
+        self.execute(&statements, false);
+    }
+
+    // Runs resolved statements on the selected backend, reporting any
+    // error. With `echo`, top-level expression statements print their value.
+    fn execute(&mut self, statements: &[Stmt], echo: bool) {
+        self.set_backend_file();
+        let result = match self.backend {
+            Backend::TreeWalker if echo => self.tree_walker.interpret_repl(statements),
+            Backend::TreeWalker => self.tree_walker.interpret(statements),
+            Backend::Vm => match self.vm.compile(statements, echo) {
+                Ok(function) => self.vm.interpret(function),
+                Err(diagnostics) => {
+                    for diagnostic in diagnostics {
+                        self.emit(diagnostic);
+                    }
+                    return;
+                }
+            },
+        };
+        if let Err(error) = result {
+            self.runtime_error(error, self.backend);
+        }
+        self.exit_status = match self.backend {
+            Backend::TreeWalker => self.tree_walker.exit_status(),
+            Backend::Vm => self.vm.exit_status(),
+        };
+    }
+
+    fn scan(&mut self, source: String) -> Vec<Token> {
         let mut scanner = Scanner::new(source);
-        let tokens = scanner.scan_tokens();
-        
-        // For now, just print the tokens
-        for token in tokens {
-            println!("{:?}", token);
+        let tokens = scanner.scan_tokens().clone();
+        for diagnostic in scanner.errors() {
+            self.emit(diagnostic.clone());
+        }
+        tokens
+    }
+
+    fn parse(&mut self, source: String) -> Vec<Stmt> {
+        let tokens = self.scan(source);
+        self.parse_tokens(tokens)
+    }
+
+    fn parse_tokens(&mut self, tokens: Vec<Token>) -> Vec<Stmt> {
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse();
+        for diagnostic in parser.errors() {
+            self.emit(diagnostic.clone());
+        }
+        statements
+    }
+
+    // Scans, parses and resolves `source`. Returns `None` if any of those
+    // steps reported an error.
+    fn compile(&mut self, source: String) -> Option<Vec<Stmt>> {
+        let statements = self.parse(source);
+        if self.had_error || !self.resolve(&statements) {
+            return None;
+        }
+        Some(statements)
+    }
+
+    // Runs the resolver over `statements` and hands its results to the tree
+    // walker. Returns false if it reported errors.
+    fn resolve(&mut self, statements: &[Stmt]) -> bool {
+        let mut resolver = Resolver::new();
+        resolver.resolve(statements);
+        for diagnostic in resolver.errors() {
+            self.emit(diagnostic.clone());
+        }
+        if !resolver.errors().is_empty() {
+            return false;
         }
+        self.tree_walker.resolve(resolver.into_locals());
+        true
+    }
+
+    /// Parses `source` and renders its syntax tree. Statements with syntax
+    /// errors are reported and left out.
+    pub fn dump_ast(&mut self, source: String, format: AstFormat) -> String {
+        let statements = self.parse(source);
+        ast_printer::print_program(&statements, format)
+    }
+
+    /// Parses `source` and prints it back in the standard layout, keeping
+    /// its comments. Returns `None` if syntax errors were reported.
+    pub fn format_source(&mut self, source: String) -> Option<String> {
+        self.had_error = false;
+        let mut scanner = Scanner::new(source.clone());
+        let tokens = scanner.scan_tokens().clone();
+        for diagnostic in scanner.errors() {
+            self.emit(diagnostic.clone());
+        }
+        let statements = self.parse_tokens(tokens);
+        if self.had_error {
+            return None;
+        }
+        Some(formatter::format_program(
+            &statements,
+            &source,
+            scanner.comments(),
+        ))
+    }
+
+    /// Compiles `source` to bytecode and disassembles it, the script first
+    /// and then every function declared in it. Errors are reported as
+    /// diagnostics and give an empty listing.
+    pub fn disassemble(&mut self, source: String) -> String {
+        match self.compile_for_vm(source) {
+            Some(function) => disassembler::disassemble_function(self.vm.heap(), function),
+            None => String::new(),
+        }
+    }
+
+    /// Compiles `source` to bytecode and encodes it as a `.loxc` file.
+    /// Returns `None` if errors were reported.
+    pub fn compile_bytecode(&mut self, source: String) -> Option<Vec<u8>> {
+        let function = self.compile_for_vm(source.clone())?;
+        Some(loxc::write(self.vm.heap(), function, &source))
+    }
+
+    /// Runs a `.loxc` file on the VM, whichever backend is selected.
+    /// Returns whether a runtime error was reported, or why the file could
+    /// not be loaded.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> Result<bool, LoadError> {
+        self.had_error = false;
+        self.last_runtime_error = None;
+        self.exit_status = None;
+        self.set_backend_file();
+        let function = self.vm.load(bytes)?;
+        if let Err(error) = self.vm.interpret(function) {
+            self.runtime_error(error, Backend::Vm);
+        }
+        self.exit_status = self.vm.exit_status();
+        Ok(self.last_runtime_error.is_some())
+    }
+
+    // Compiles `source` all the way to a VM function, reporting errors.
+    fn compile_for_vm(&mut self, source: String) -> Option<ObjRef> {
+        let statements = self.compile(source)?;
+        match self.vm.compile(&statements, false) {
+            Ok(function) => Some(function),
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    self.emit(diagnostic);
+                }
+                None
+            }
+        }
+    }
+
+    /// Scans `source` and renders one line per token, including `EOF`.
+    /// Lexical errors are reported as diagnostics.
+    pub fn dump_tokens(&mut self, source: String, format: TokenFormat) -> String {
+        let mut out = String::new();
+        for token in self.scan(source) {
+            match format {
+                TokenFormat::Text => out.push_str(&token.dump()),
+                TokenFormat::Json => out.push_str(&token.to_json()),
+            }
+            out.push('\n');
+        }
+        out
     }
 
     #[allow(dead_code)]
@@ -63,27 +453,207 @@ impl Interpreter {
     }
 
     pub fn report(&mut self, line: usize, location: String, message: String) {
-        eprintln!("[line {} ] Error {}: {}", line, location, message);
+        let diagnostic =
+            Diagnostic::error(codes::GENERIC, message, line, 0).with_location(location);
+        self.emit(diagnostic);
+    }
+
+    /// Writes a diagnostic to stderr in the configured format.
+    pub fn emit(&mut self, diagnostic: Diagnostic) {
+        self.print_diagnostic(diagnostic);
         self.had_error = true;
     }
 
+    // Tells the backends which file the code they run next comes from.
+    fn set_backend_file(&mut self) {
+        let file: Option<Rc<str>> = self.source_name.as_deref().map(Rc::from);
+        self.tree_walker.set_file(file.clone());
+        self.vm.set_file(file);
+    }
+
+    // Reports an error raised by `backend`, with the stack trace it kept.
+    fn runtime_error(&mut self, error: RuntimeError, backend: Backend) {
+        let trace = match backend {
+            Backend::TreeWalker => self.tree_walker.stack_trace(),
+            Backend::Vm => self.vm.stack_trace(),
+        };
+        let trace = runtime::trace_lines(trace);
+        self.print_diagnostic(error.to_diagnostic().with_trace(trace));
+        self.last_runtime_error = Some(error);
+    }
+
+    fn print_diagnostic(&self, mut diagnostic: Diagnostic) {
+        if diagnostic.file.is_none() {
+            diagnostic.file = self.source_name.clone();
+        }
+        eprintln!("{}", diagnostic.render(self.error_format));
+    }
+
     // Method for testing that doesn't use args
     pub fn new_with_args(args: Vec<String>) -> Self {
         Interpreter {
             args,
+            error_format: ErrorFormat::Text,
+            source_name: None,
+            backend: Backend::TreeWalker,
+            tree_walker: TreeWalker::new(),
+            vm: Vm::new(),
             had_error: false,
+            last_runtime_error: None,
+            exit_status: None,
         }
     }
 
+    /// Sets the file name attached to diagnostics, e.g. `<stdin>`.
+    pub fn set_source_name(&mut self, name: Option<String>) {
+        self.source_name = name;
+    }
+
+    /// Selects the engine that runs programs. Globals are not carried over
+    /// from one backend to the other.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Makes the VM print its stack and each instruction as it runs.
+    pub fn set_trace_execution(&mut self, trace_execution: bool) {
+        self.vm.set_trace_execution(trace_execution);
+    }
+
+    /// Makes the VM collect garbage on every allocation.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.vm.set_stress_gc(stress_gc);
+    }
+
+    /// Turns the VM compiler's optimizations on or off. The tree walker
+    /// has none.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.vm.set_optimize(optimize);
+    }
+
+    /// Sets how deep calls may nest on either backend before a "Stack
+    /// overflow." error. The tree walker also nests Rust calls for each
+    /// level, so deep limits need a thread with a large stack; see
+    /// `TreeWalker::set_max_depth`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.tree_walker.set_max_depth(max_depth);
+        self.vm.set_max_depth(max_depth);
+    }
+
+    /// Limits how many steps each later `run_source`, `run_line` or
+    /// `run_bytecode` call may take before a "Step limit exceeded." error,
+    /// or lifts the limit with `None`. A step is one instruction on the VM,
+    /// and one statement or function call on the tree walker.
+    pub fn set_step_limit(&mut self, step_limit: Option<u64>) {
+        self.tree_walker.set_step_limit(step_limit);
+        self.vm.set_step_limit(step_limit);
+    }
+
+    /// Limits how many bytes the objects a script creates may hold before
+    /// an "Out of memory." error, or lifts the limit with `None`. Sizes
+    /// are estimates, measured by each backend in its own way.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.tree_walker.set_memory_limit(memory_limit);
+        self.vm.set_memory_limit(memory_limit);
+    }
+
+    /// Restricts the natives defined on both backends to the capabilities
+    /// of `sandbox`, and sets its step and memory limits.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.tree_walker.set_capabilities(sandbox.capabilities);
+        self.vm.set_capabilities(sandbox.capabilities);
+        self.set_step_limit(sandbox.max_steps);
+        self.set_memory_limit(sandbox.max_memory);
+    }
+
+    /// The global variables of the tree walker, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.tree_walker.globals()
+    }
+
+    /// The names of the global variables of the selected backend, sorted,
+    /// each with the fields and methods of the instance it holds, for tab
+    /// completion.
+    pub fn global_members(&self) -> Vec<(String, Vec<String>)> {
+        match self.backend {
+            Backend::TreeWalker => self.tree_walker.global_members(),
+            Backend::Vm => self.vm.global_members(),
+        }
+    }
+
+    /// Redirects the output of `print` statements on both backends, for
+    /// embedding and tests.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        let out = SharedWriter(Rc::new(RefCell::new(out)));
+        self.tree_walker.set_output(Box::new(out.clone()));
+        self.vm.set_output(Box::new(out));
+    }
+
     // Method to check if interpreter has errors (useful for testing)
     pub fn has_error(&self) -> bool {
         self.had_error
     }
 
+    pub fn has_runtime_error(&self) -> bool {
+        self.last_runtime_error.is_some()
+    }
+
+    /// The runtime error that stopped the last run, if any. Its `code`
+    /// tells errors in the script apart from the limits set on it, such as
+    /// `codes::STEP_LIMIT`.
+    pub fn last_runtime_error(&self) -> Option<&RuntimeError> {
+        self.last_runtime_error.as_ref()
+    }
+
+    /// The status the last run passed to `exit`, if it called it.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// The status for the last run: the one it passed to `exit` if it
+    /// called it, and otherwise sysexits-style, 65 for static errors, 70
+    /// for runtime errors and 0 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        if let Some(status) = self.exit_status {
+            status
+        } else if self.had_error {
+            EXIT_DATA_ERROR
+        } else if self.last_runtime_error.is_some() {
+            EXIT_SOFTWARE
+        } else {
+            0
+        }
+    }
+
     // Method to run source and return if it had errors (useful for testing)
     pub fn run_source(&mut self, source: String) -> bool {
         self.had_error = false;
+        self.last_runtime_error = None;
+        self.exit_status = None;
         self.run(source);
+        self.had_error || self.last_runtime_error.is_some()
+    }
+
+    /// Scans, parses and resolves `source` without running it. With the VM
+    /// backend it is also compiled to bytecode. Returns whether any errors
+    /// were reported.
+    pub fn check_source(&mut self, source: String) -> bool {
+        self.had_error = false;
+        self.last_runtime_error = None;
+        self.exit_status = None;
+        if let Some(statements) = self.compile(source) {
+            if self.backend == Backend::Vm {
+                if let Err(diagnostics) = self.vm.compile(&statements, false) {
+                    for diagnostic in diagnostics {
+                        self.emit(diagnostic);
+                    }
+                }
+            }
+        }
         self.had_error
     }
 }
@@ -184,6 +754,477 @@ mod tests {
         assert!(!had_error2);
     }
 
+    #[test]
+    fn test_lexical_error_sets_error_flag() {
+        let mut interpreter = Interpreter::new();
+        interpreter.error_format = ErrorFormat::Json;
+        let had_error = interpreter.run_source("var x = 1 # 2;".to_string());
+        assert!(had_error);
+    }
+
+    #[test]
+    fn test_dump_tokens_text() {
+        let mut interpreter = Interpreter::new();
+        let dump = interpreter.dump_tokens("print 1;".to_string(), TokenFormat::Text);
+        assert_eq!(
+            dump,
+            "PRINT\tprint\tnull\t1:1\nNUMBER\t1\t1\t1:7\nSEMICOLON\t;\tnull\t1:8\nEOF\t\tnull\t1:9\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_tokens_json() {
+        let mut interpreter = Interpreter::new();
+        let dump = interpreter.dump_tokens("x".to_string(), TokenFormat::Json);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "{\"type\":\"IDENTIFIER\",\"lexeme\":\"x\",\"literal\":null,\"line\":1,\"column\":1}"
+        );
+        assert!(!interpreter.has_error());
+    }
+
+    #[test]
+    fn test_syntax_error_sets_error_flag() {
+        let mut interpreter = Interpreter::new();
+        let had_error = interpreter.run_source("print (1 + ;".to_string());
+        assert!(had_error);
+    }
+
+    #[test]
+    fn test_dump_ast() {
+        let mut interpreter = Interpreter::new();
+        let dump = interpreter.dump_ast("print (1 + 2) * 3;".to_string(), AstFormat::Sexpr);
+        assert_eq!(dump, "(print (* (group (+ 1 2)) 3))\n");
+        assert!(!interpreter.has_error());
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_captured(source: &str) -> (bool, String) {
+        let mut interpreter = Interpreter::new();
+        let buffer = SharedBuffer::default();
+        interpreter.set_output(Box::new(buffer.clone()));
+        let had_error = interpreter.run_source(source.to_string());
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        (had_error, output)
+    }
+
+    #[test]
+    fn test_print_output() {
+        let (had_error, output) = run_captured("print 1 + 2; print \"a\" + \"b\"; print nil;");
+        assert!(!had_error);
+        assert_eq!(output, "3\nab\nnil\n");
+    }
+
+    #[test]
+    fn test_output_goes_to_the_same_writer_on_both_backends() {
+        let mut interpreter = Interpreter::new();
+        let buffer = SharedBuffer::default();
+        interpreter.set_output(Box::new(buffer.clone()));
+        assert!(!interpreter.run_source("print \"tree\";".to_string()));
+        interpreter.set_backend(Backend::Vm);
+        assert!(!interpreter.run_source("print \"vm\";".to_string()));
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "tree\nvm\n"
+        );
+    }
+
+    #[test]
+    fn test_tail_calls_recurse_a_million_deep() {
+        let source = r#"
+            fun count(n, total) {
+                if (n == 0) return total;
+                return count(n - 1, total + 1);
+            }
+            print count(1000000, 0);
+        "#;
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let buffer = SharedBuffer::default();
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_output(Box::new(buffer.clone()));
+            assert!(!interpreter.run_source(source.to_string()));
+            assert_eq!(buffer.0.borrow().as_slice(), b"1000000\n");
+        }
+    }
+
+    #[test]
+    fn test_dropping_long_chains() {
+        let source = r#"
+            class Node { init(next) { this.next = next; } }
+            var list = nil;
+            for (var i = 0; i < 50000; i = i + 1) list = Node(list);
+            list = nil;
+
+            fun cons(tail) { fun rest() { return tail; } return rest; }
+            var closures = nil;
+            for (var i = 0; i < 50000; i = i + 1) closures = cons(closures);
+            closures = nil;
+            print "done";
+        "#;
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let buffer = SharedBuffer::default();
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_output(Box::new(buffer.clone()));
+            assert!(!interpreter.run_source(source.to_string()));
+            assert_eq!(buffer.0.borrow().as_slice(), b"done\n");
+        }
+    }
+
+    #[test]
+    fn test_closures_and_classes() {
+        let source = r#"
+            fun counter() {
+                var i = 0;
+                fun count() { i = i + 1; return i; }
+                return count;
+            }
+            var c = counter();
+            c();
+            print c();
+
+            class Animal {
+                init(name) { this.name = name; }
+                speak() { return this.name + " makes a sound"; }
+            }
+            class Dog < Animal {
+                speak() { return super.speak() + " (woof)"; }
+            }
+            print Dog("Rex").speak();
+        "#;
+        let (had_error, output) = run_captured(source);
+        assert!(!had_error);
+        assert_eq!(output, "2\nRex makes a sound (woof)\n");
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let mut interpreter = Interpreter::new();
+        let had_error = interpreter.run_source("print -\"a\";".to_string());
+        assert!(had_error);
+        assert!(!interpreter.has_error());
+        assert!(interpreter.has_runtime_error());
+        assert_eq!(interpreter.exit_code(), EXIT_SOFTWARE);
+    }
+
+    #[test]
+    fn test_run_line_echoes_expressions() {
+        let mut interpreter = Interpreter::new();
+        let buffer = SharedBuffer::default();
+        interpreter.set_output(Box::new(buffer.clone()));
+
+        assert!(!interpreter.run_line("var x = 40;".to_string()));
+        assert!(!interpreter.run_line("fun add(a) { return x + a; }".to_string()));
+        assert!(!interpreter.run_line("add(2)".to_string()));
+        assert!(!interpreter.run_line("x = 1; print x + 1; x;".to_string()));
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "42\n1\n2\n1\n"
+        );
+    }
+
+    #[test]
+    fn test_run_line_recovers_after_errors() {
+        let mut interpreter = Interpreter::new();
+        let buffer = SharedBuffer::default();
+        interpreter.set_output(Box::new(buffer.clone()));
+
+        assert!(!interpreter.run_line("var a = 1;".to_string()));
+        assert!(interpreter.run_line("{ var b = 2; print -nil; }".to_string()));
+        assert!(interpreter.run_line("print ;".to_string()));
+        assert!(!interpreter.run_line("a + 1".to_string()));
+        assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), "2\n");
+    }
+
+    #[test]
+    fn test_stack_overflow_leaves_the_interpreter_usable() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+            interpreter.set_max_depth(50);
+
+            let recurse = "fun f(n) { return 1 + f(n + 1); }";
+            assert!(!interpreter.run_line(recurse.to_string()));
+            assert!(interpreter.run_line("f(0)".to_string()));
+            let count = "fun g(n) { if (n > 0) return 1 + g(n - 1); return 0; }";
+            assert!(!interpreter.run_line(count.to_string()));
+            assert!(!interpreter.run_line("g(40)".to_string()));
+            assert!(interpreter.run_line("g(60)".to_string()));
+            assert!(!interpreter.run_line("g(10)".to_string()));
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "40\n10\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+            interpreter.set_step_limit(Some(1000));
+
+            assert!(interpreter.run_source("while (true) {}".to_string()));
+            let error = interpreter.last_runtime_error().unwrap();
+            assert_eq!(error.code, codes::STEP_LIMIT);
+            assert_eq!(error.message, "Step limit exceeded.");
+
+            // Every run gets the whole budget again.
+            let count = "var n = 0; for (var i = 0; i < 10; i = i + 1) n = n + i; print n;";
+            assert!(!interpreter.run_source(count.to_string()));
+            assert!(!interpreter.run_source(count.to_string()));
+            assert!(interpreter.last_runtime_error().is_none());
+
+            assert!(interpreter.run_source("print -nil;".to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::TYPE_ERROR
+            );
+
+            interpreter.set_step_limit(None);
+            assert!(!interpreter.run_source("for (var i = 0; i < 1000; i = i + 1) {}".to_string()));
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "45\n45\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+            interpreter.set_memory_limit(Some(100_000));
+
+            let grow = "var s = \"x\"; while (true) s = s + s;";
+            assert!(interpreter.run_line(grow.to_string()));
+            let error = interpreter.last_runtime_error().unwrap();
+            assert_eq!(error.code, codes::OUT_OF_MEMORY);
+            assert_eq!(error.message, "Out of memory.");
+
+            // Garbage does not count against the limit, and the string
+            // that filled the memory can be dropped to make room again.
+            assert!(!interpreter.run_line("s = nil;".to_string()));
+            let churn = "for (var i = 0; i < 1000; i = i + 1) { var t = \"abc\" + \"def\"; }";
+            assert!(!interpreter.run_line(churn.to_string()));
+            let list = "class Node { init(next) { this.next = next; } }
+                var list = nil;
+                for (var i = 0; i < 50000; i = i + 1) list = Node(list);";
+            assert!(interpreter.run_line(list.to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::OUT_OF_MEMORY
+            );
+            assert!(!interpreter.run_line("list = nil; print \"ok\";".to_string()));
+            // The assignments echo their values, as at the prompt.
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "nil\nnil\nok\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_memory_limit_is_a_hard_cap() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_memory_limit(Some(800_000));
+
+            // Strings of 512, 128 and 64 kilobytes fill most of the
+            // memory, and garbage makes sure it is measured while they do.
+            let fill = "fun grow(n) { var s = \"x\"; for (var i = 0; i < n; i = i + 1) s = s + s; return s; }
+                var s = grow(19); var r = grow(17); var q = grow(16);
+                for (var i = 0; i < 5000; i = i + 1) { var g = \"ab\" + \"cd\"; }";
+            assert!(!interpreter.run_line(fill.to_string()));
+            // Another 128 kilobytes takes it just past the limit.
+            assert!(interpreter.run_line("var t = r + \"y\";".to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::OUT_OF_MEMORY
+            );
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+            // Embedders grant capabilities explicitly.
+            assert!(interpreter.run_source("print readFile;".to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::UNDEFINED_VARIABLE
+            );
+            interpreter.set_sandbox(Sandbox::unrestricted());
+
+            let path = env::temp_dir().join(format!(
+                "rlox-sandbox-{}-{:?}.txt",
+                std::process::id(),
+                backend
+            ));
+            let path = path.to_str().unwrap().replace('\\', "/");
+            let io = format!(
+                "var path = \"{}\"; writeFile(path, \"saved\"); print readFile(path);",
+                path
+            );
+            assert!(!interpreter.run_source(io));
+            assert!(!interpreter.run_source("print getenv(\"RLOX_UNSET_VARIABLE\");".to_string()));
+            fs::remove_file(&path).unwrap();
+
+            interpreter.set_sandbox(Sandbox {
+                max_steps: Some(1_000),
+                ..Sandbox::restricted()
+            });
+            for native in ["clock", "readFile", "writeFile", "getenv", "exit", "import"] {
+                assert!(interpreter.run_source(format!("print {};", native)));
+                assert_eq!(
+                    interpreter.last_runtime_error().unwrap().code,
+                    codes::UNDEFINED_VARIABLE
+                );
+            }
+            assert!(interpreter.run_source("while (true) {}".to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::STEP_LIMIT
+            );
+            // Globals of scripts are not natives, and stay defined, even
+            // under the name of one.
+            assert!(!interpreter.run_source("print path == nil;".to_string()));
+            assert!(!interpreter.run_source("var getenv = \"mine\";".to_string()));
+
+            interpreter.set_sandbox(Sandbox::unrestricted());
+            assert!(!interpreter.run_source("print clock() > 0;".to_string()));
+            assert!(!interpreter.run_source("print getenv;".to_string()));
+            interpreter.set_sandbox(Sandbox::restricted());
+            assert!(!interpreter.run_source("print getenv;".to_string()));
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "saved\nnil\nfalse\ntrue\nmine\nmine\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_exit_stops_the_script_but_not_the_host() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_sandbox(Sandbox::unrestricted());
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+
+            let source = "fun stop() { exit(4); } print 1; stop(); print 2;";
+            assert!(!interpreter.run_source(source.to_string()));
+            assert_eq!(interpreter.exit_status(), Some(4));
+            assert_eq!(interpreter.exit_code(), 4);
+            assert!(!interpreter.run_source("print 3;".to_string()));
+            assert_eq!(interpreter.exit_status(), None);
+            assert_eq!(interpreter.exit_code(), 0);
+
+            assert!(interpreter.run_source("exit(2.5);".to_string()));
+            assert_eq!(interpreter.exit_status(), None);
+            assert_eq!(interpreter.exit_code(), EXIT_SOFTWARE);
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "1\n3\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_sandbox(Sandbox::unrestricted());
+            let buffer = SharedBuffer::default();
+            interpreter.set_output(Box::new(buffer.clone()));
+
+            let module_path = |name: &str| {
+                let path = env::temp_dir().join(format!(
+                    "rlox-import-{}-{}-{:?}.lox",
+                    std::process::id(),
+                    name,
+                    backend
+                ));
+                path.to_str().unwrap().replace('\\', "/")
+            };
+            let module = module_path("module");
+            fs::write(
+                &module,
+                "print \"loading\";\nvar answer = 21;\nfun double(n) { return n * 2; }\n",
+            )
+            .unwrap();
+            let broken = module_path("broken");
+            fs::write(&broken, "var = 1;\n").unwrap();
+
+            // A module runs once, in the global scope, however often it
+            // is imported.
+            let source = format!(
+                "fun load() {{ return import(\"{0}\"); }} print load(); import(\"{0}\"); print double(answer);",
+                module
+            );
+            assert!(!interpreter.run_source(source));
+            assert!(interpreter.run_source(format!("import(\"{}\");", broken)));
+            let error = interpreter.last_runtime_error().unwrap();
+            assert_eq!(error.code, codes::IMPORT_FAILED);
+            assert_eq!(
+                error.message,
+                format!(
+                    "Could not import '{}': line 1: Expect variable name.",
+                    broken
+                )
+            );
+            assert!(interpreter.run_source("import(\"rlox-no-such-module.lox\");".to_string()));
+            assert_eq!(
+                interpreter.last_runtime_error().unwrap().code,
+                codes::IMPORT_FAILED
+            );
+            fs::remove_file(&module).unwrap();
+            fs::remove_file(&broken).unwrap();
+            assert_eq!(
+                String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                "loading\nnil\n42\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_source_does_not_run() {
+        let mut interpreter = Interpreter::new();
+        assert!(!interpreter.check_source("print undefined_name;".to_string()));
+        assert!(interpreter.check_source("return 1;".to_string()));
+        assert_eq!(interpreter.exit_code(), EXIT_DATA_ERROR);
+    }
+
     #[test]
     fn test_complex_program() {
         let mut interpreter = Interpreter::new();
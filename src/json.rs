@@ -0,0 +1,65 @@
+/// Wraps `s` in double quotes, escaping it as a JSON string literal.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Renders an optional string as a JSON string or `null`.
+pub fn quote_opt(s: Option<&str>) -> String {
+    match s {
+        Some(s) => quote(s),
+        None => "null".to_string(),
+    }
+}
+
+/// Renders a number as a JSON number. JSON has no infinities or NaN, so
+/// those are written as strings.
+pub fn number(n: f64) -> String {
+    if n.is_finite() {
+        n.to_string()
+    } else {
+        quote(&n.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_plain() {
+        assert_eq!(quote("hello"), "\"hello\"");
+    }
+
+    #[test]
+    fn test_quote_escapes() {
+        assert_eq!(quote("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+        assert_eq!(quote("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number(7.0), "7");
+        assert_eq!(number(2.5), "2.5");
+        assert_eq!(number(f64::INFINITY), "\"inf\"");
+    }
+
+    #[test]
+    fn test_quote_opt() {
+        assert_eq!(quote_opt(None), "null");
+        assert_eq!(quote_opt(Some("x")), "\"x\"");
+    }
+}
@@ -1,7 +1,26 @@
+pub mod ast;
+pub mod ast_printer;
+pub mod cli;
+pub mod diagnostic;
+pub mod environment;
+pub mod formatter;
+pub mod highlight;
 pub mod interpreter;
+pub mod json;
+pub mod line_editor;
+pub mod memory;
+pub mod parser;
+pub mod repl;
+pub mod resolver;
+pub mod runtime;
+pub mod sandbox;
 pub mod scanner;
 pub mod token;
+pub mod tree_walker;
+pub mod value;
+pub mod vm;
 
 pub use interpreter::Interpreter;
+pub use parser::Parser;
 pub use scanner::Scanner;
 pub use token::{Token, TokenType};
\ No newline at end of file
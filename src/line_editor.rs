@@ -0,0 +1,134 @@
+use crate::highlight;
+use crate::repl::{self, Input, LineReader};
+use rustyline::completion::Completer;
+use rustyline::config::{CompletionType, Config, EditMode};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::borrow::Cow;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+/// Name of the history file kept in the user's home directory.
+pub const HISTORY_FILE: &str = ".rlox_history";
+
+// Hooks the editor calls back into while a line is being typed.
+struct LoxHelper {
+    // The session's globals and their members as of the current prompt,
+    // for completion.
+    globals: Vec<(String, Vec<String>)>,
+    // Unfinished input from earlier lines, which colours this one.
+    pending: String,
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(repl::complete(line, pos, &self.globals))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.pending.is_empty() && repl::is_command(line) {
+            return Cow::Borrowed(line);
+        }
+        let spans = highlight::highlight_continuation(&self.pending, line);
+        Cow::Owned(highlight::render_ansi(line, &spans))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+        true
+    }
+}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+/// Terminal line editing for the REPL: emacs-style key bindings, history
+/// that is kept across sessions, tab completion and syntax highlighting.
+pub struct LineEditor {
+    editor: Editor<LoxHelper, DefaultHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    /// Sets up the editor and loads any history from earlier sessions.
+    pub fn new() -> rustyline::Result<Self> {
+        let config = Config::builder()
+            .edit_mode(EditMode::Emacs)
+            .completion_type(CompletionType::List)
+            .auto_add_history(true)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(LoxHelper {
+            globals: Vec::new(),
+            pending: String::new(),
+        }));
+
+        let history_path = history_path();
+        if let Some(path) = &history_path {
+            // There is no history the first time the REPL runs.
+            let _ = editor.load_history(path);
+        }
+        Ok(LineEditor {
+            editor,
+            history_path,
+        })
+    }
+
+    /// Writes the history to `~/.rlox_history`, reporting failures on
+    /// stderr.
+    pub fn save_history(&mut self) {
+        if let Some(path) = &self.history_path {
+            if let Err(error) = self.editor.save_history(path) {
+                eprintln!("Unable to save history to {}: {}", path.display(), error);
+            }
+        }
+    }
+}
+
+impl LineReader for LineEditor {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        match self.editor.readline(prompt) {
+            Ok(line) => Ok(Input::Line(line)),
+            Err(ReadlineError::Interrupted) => Ok(Input::Interrupted),
+            Err(ReadlineError::Eof) => Ok(Input::Eof),
+            Err(ReadlineError::Io(error)) => Err(error),
+            Err(error) => Err(io::Error::other(error.to_string())),
+        }
+    }
+
+    fn set_globals(&mut self, globals: Vec<(String, Vec<String>)>) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.globals = globals;
+        }
+    }
+
+    fn set_pending(&mut self, pending: &str) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.pending = pending.to_string();
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
@@ -0,0 +1,237 @@
+//! Estimates of the memory the tree walker's objects hold, for holding
+//! scripts to a memory limit. The VM counts the objects in its own heap,
+//! but both backends decide when to measure again with `next_measurement`.
+
+use crate::environment::Environment;
+use crate::value::{LoxClass, LoxFunction, LoxInstance, Value};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem;
+use std::rc::Rc;
+
+// The reference counts in front of every `Rc` allocation.
+const RC_HEADER: usize = 2 * mem::size_of::<usize>();
+
+/// How many bytes scripts may seem to hold under `limit` before memory is
+/// measured again, when it was last measured at `live` bytes: halfway from
+/// there to the limit. Memory is always measured again before scripts can
+/// seem to hold more than the limit, so they never hold more unnoticed.
+pub fn next_measurement(live: usize, limit: usize) -> usize {
+    if live >= limit {
+        limit
+    } else {
+        live + (limit - live) / 2
+    }
+}
+
+/// A string of `len` bytes.
+pub fn string_size(len: usize) -> usize {
+    RC_HEADER + len
+}
+
+/// A variable or field called `name`.
+pub fn variable_size(name: &str) -> usize {
+    mem::size_of::<(String, Value)>() + name.len()
+}
+
+/// An empty scope.
+pub fn environment_size() -> usize {
+    RC_HEADER + mem::size_of::<RefCell<Environment>>()
+}
+
+/// An instance without fields.
+pub fn instance_size() -> usize {
+    RC_HEADER + mem::size_of::<RefCell<LoxInstance>>()
+}
+
+/// A function or method, not counting the scope it closes over.
+pub fn function_size() -> usize {
+    RC_HEADER + mem::size_of::<LoxFunction>()
+}
+
+/// A class with `methods` methods, not counting the methods themselves.
+pub fn class_size(name: &str, methods: usize) -> usize {
+    RC_HEADER + mem::size_of::<LoxClass>() + name.len() + methods * variable_size("")
+}
+
+// Something reachable that has not been measured yet.
+enum Object {
+    Environment(Rc<RefCell<Environment>>),
+    Value(Value),
+}
+
+/// Adds up the memory reachable from a set of roots, counting each object
+/// once however many references it has.
+#[derive(Default)]
+pub struct Meter {
+    seen: HashSet<*const ()>,
+    pending: Vec<Object>,
+    bytes: usize,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Meter::default()
+    }
+
+    /// Counts a scope and everything reachable from it.
+    pub fn environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        self.pending.push(Object::Environment(environment.clone()));
+        self.drain();
+    }
+
+    /// Counts a value and everything reachable from it.
+    pub fn value(&mut self, value: &Value) {
+        self.pending.push(Object::Value(value.clone()));
+        self.drain();
+    }
+
+    /// The bytes counted so far.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Whether `object` is met for the first time.
+    fn first_visit<T: ?Sized>(&mut self, object: &Rc<T>) -> bool {
+        self.seen.insert(Rc::as_ptr(object) as *const ())
+    }
+
+    // Measures objects until none are left, without recursing, so long
+    // chains of scopes or instances do not exhaust the Rust stack.
+    fn drain(&mut self) {
+        while let Some(object) = self.pending.pop() {
+            match object {
+                Object::Environment(environment) => {
+                    if !self.first_visit(&environment) {
+                        continue;
+                    }
+                    self.bytes += environment_size();
+                    let environment = environment.borrow();
+                    for (name, value) in environment.values() {
+                        self.bytes += variable_size(name);
+                        self.pending.push(Object::Value(value.clone()));
+                    }
+                    if let Some(enclosing) = environment.enclosing() {
+                        self.pending.push(Object::Environment(enclosing.clone()));
+                    }
+                }
+                Object::Value(value) => self.measure_value(value),
+            }
+        }
+    }
+
+    fn measure_value(&mut self, value: Value) {
+        match value {
+            Value::Nil | Value::Bool(_) | Value::Number(_) | Value::Native(_) => {}
+            Value::String(string) => {
+                if self.first_visit(&string) {
+                    self.bytes += string_size(string.len());
+                }
+            }
+            Value::Function(function) => self.measure_function(&function),
+            Value::Class(class) => self.measure_class(&class),
+            Value::Instance(instance) => {
+                if !self.first_visit(&instance) {
+                    return;
+                }
+                self.bytes += instance_size();
+                let instance = instance.borrow();
+                self.pending
+                    .push(Object::Value(Value::Class(instance.class.clone())));
+                for (name, value) in &instance.fields {
+                    self.bytes += variable_size(name);
+                    self.pending.push(Object::Value(value.clone()));
+                }
+            }
+        }
+    }
+
+    fn measure_function(&mut self, function: &Rc<LoxFunction>) {
+        if self.first_visit(function) {
+            self.bytes += function_size();
+            self.pending
+                .push(Object::Environment(function.closure.clone()));
+        }
+    }
+
+    fn measure_class(&mut self, class: &Rc<LoxClass>) {
+        if !self.first_visit(class) {
+            return;
+        }
+        self.bytes += class_size(&class.name, class.methods.len());
+        for method in class.methods.values() {
+            self.measure_function(method);
+        }
+        if let Some(superclass) = &class.superclass {
+            self.pending
+                .push(Object::Value(Value::Class(superclass.clone())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_next_measurement() {
+        assert_eq!(next_measurement(0, 1000), 500);
+        assert_eq!(next_measurement(900, 1000), 950);
+        assert_eq!(next_measurement(1000, 1000), 1000);
+        assert_eq!(next_measurement(usize::MAX, 1000), 1000);
+    }
+
+    #[test]
+    fn test_shared_objects_count_once() {
+        let string: Rc<str> = "shared".into();
+        let environment = Rc::new(RefCell::new(Environment::new()));
+        environment
+            .borrow_mut()
+            .define("a".to_string(), Value::String(string.clone()));
+        environment
+            .borrow_mut()
+            .define("b".to_string(), Value::String(string));
+
+        let mut meter = Meter::new();
+        meter.environment(&environment);
+        meter.environment(&environment);
+        assert_eq!(
+            meter.bytes(),
+            environment_size() + variable_size("a") + variable_size("b") + string_size(6)
+        );
+    }
+
+    #[test]
+    fn test_cycles_and_long_chains() {
+        let mut scope = Rc::new(RefCell::new(Environment::new()));
+        for _ in 0..1_000 {
+            scope = Rc::new(RefCell::new(Environment::with_enclosing(scope)));
+        }
+        let class = Rc::new(LoxClass {
+            name: "Node".to_string(),
+            superclass: None,
+            methods: HashMap::new(),
+        });
+        let node = Rc::new(RefCell::new(LoxInstance::new(class)));
+        // An instance that refers to itself.
+        node.borrow_mut()
+            .fields
+            .insert("next".to_string(), Value::Instance(node.clone()));
+        scope
+            .borrow_mut()
+            .define("node".to_string(), Value::Instance(node.clone()));
+
+        let mut meter = Meter::new();
+        meter.environment(&scope);
+        node.borrow_mut().fields.clear();
+        assert_eq!(
+            meter.bytes(),
+            1_001 * environment_size()
+                + variable_size("node")
+                + instance_size()
+                + variable_size("next")
+                + class_size("Node", 0)
+        );
+    }
+}
@@ -0,0 +1,869 @@
+use crate::ast::{Expr, ExprKind, Function, LiteralValue, Span, Stmt, StmtKind};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::token::{Token, TokenType};
+use std::rc::Rc;
+
+/// Functions and calls are limited to this many parameters and arguments.
+pub const MAX_ARGUMENTS: usize = 255;
+
+/// How deeply expressions and statements may nest. Every later pass walks
+/// the syntax tree recursively, so deeper trees could overflow the stack.
+pub const MAX_NESTING: usize = 128;
+
+// Marker for a syntax error that has already been recorded in `errors`.
+struct ParseError;
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// Recursive descent parser for the full Lox grammar.
+///
+/// `for` loops are desugared into `while` loops wrapped in blocks, so later
+/// passes only ever see the core statement forms.
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<Diagnostic>,
+    // How deeply the syntax being parsed is nested.
+    depth: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Parser {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    /// Parses a whole program. Statements with syntax errors are skipped
+    /// after recovering at the next statement boundary.
+    pub fn parse(&mut self) -> Vec<Stmt> {
+        let mut statements = Vec::new();
+        while !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        statements
+    }
+
+    /// Parses a single expression that must span all of the input.
+    pub fn parse_expression(&mut self) -> Option<Expr> {
+        let expression = self.expression().ok()?;
+        if !self.is_at_end() {
+            let token = self.peek().clone();
+            self.error(&token, codes::EXPECTED_TOKEN, "Expect end of expression.");
+            return None;
+        }
+        Some(expression)
+    }
+
+    /// Syntax errors found so far.
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = if self.match_types(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.match_types(&[TokenType::Fun]) {
+            let keyword = self.previous().clone();
+            self.function("function").map(|function| Stmt {
+                span: Span::of_token(&keyword).to(function.span),
+                kind: StmtKind::Function { function },
+            })
+        } else if self.match_types(&[TokenType::Var]) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        match result {
+            Ok(statement) => Some(statement),
+            Err(ParseError) => {
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let name = self
+            .consume(TokenType::Identifier, "Expect class name.")?
+            .clone();
+
+        let superclass = if self.match_types(&[TokenType::Less]) {
+            let superclass = self
+                .consume(TokenType::Identifier, "Expect superclass name.")?
+                .clone();
+            Some(Expr::new(
+                ExprKind::Variable {
+                    name: superclass.clone(),
+                },
+                Span::of_token(&superclass),
+            ))
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        let brace = self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt {
+            span: Span::of_token(&keyword).to(Span::of_token(brace)),
+            kind: StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            },
+        })
+    }
+
+    fn function(&mut self, kind: &str) -> ParseResult<Rc<Function>> {
+        let name = self
+            .consume(TokenType::Identifier, &format!("Expect {} name.", kind))?
+            .clone();
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after {} name.", kind),
+        )?;
+        let mut params = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    let token = self.peek().clone();
+                    self.error(
+                        &token,
+                        codes::TOO_MANY_PARAMETERS,
+                        "Can't have more than 255 parameters.",
+                    );
+                }
+                params.push(
+                    self.consume(TokenType::Identifier, "Expect parameter name.")?
+                        .clone(),
+                );
+                if !self.match_types(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        let (body, brace) = self.nested(Parser::block)?;
+        Ok(Rc::new(Function {
+            span: Span::of_token(&name).to(Span::of_token(&brace)),
+            name,
+            params,
+            body,
+        }))
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let name = self
+            .consume(TokenType::Identifier, "Expect variable name.")?
+            .clone();
+        let initializer = if self.match_types(&[TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        let semicolon = self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(Stmt {
+            span: Span::of_token(&keyword).to(Span::of_token(semicolon)),
+            kind: StmtKind::Var { name, initializer },
+        })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        self.nested(|parser| {
+            if parser.match_types(&[TokenType::For]) {
+                return parser.for_statement();
+            }
+            if parser.match_types(&[TokenType::If]) {
+                return parser.if_statement();
+            }
+            if parser.match_types(&[TokenType::Print]) {
+                return parser.print_statement();
+            }
+            if parser.match_types(&[TokenType::Return]) {
+                return parser.return_statement();
+            }
+            if parser.match_types(&[TokenType::While]) {
+                return parser.while_statement();
+            }
+            if parser.match_types(&[TokenType::LeftBrace]) {
+                let brace = parser.previous().clone();
+                let (statements, end) = parser.block()?;
+                return Ok(Stmt {
+                    span: Span::of_token(&brace).to(Span::of_token(&end)),
+                    kind: StmtKind::Block { statements },
+                });
+            }
+            parser.expression_statement()
+        })
+    }
+
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+
+        let initializer = if self.match_types(&[TokenType::Semicolon]) {
+            None
+        } else if self.match_types(&[TokenType::Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let condition = if !self.check(&TokenType::Semicolon) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        let semicolon = self
+            .consume(TokenType::Semicolon, "Expect ';' after loop condition.")?
+            .clone();
+
+        let increment = if !self.check(&TokenType::RightParen) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.statement()?;
+        let span = Span::of_token(&keyword).to(body.span);
+
+        if let Some(increment) = increment {
+            let increment_span = increment.span;
+            body = Stmt {
+                span: body.span,
+                kind: StmtKind::Block {
+                    statements: vec![
+                        body,
+                        Stmt {
+                            span: increment_span,
+                            kind: StmtKind::Expression {
+                                expression: increment,
+                            },
+                        },
+                    ],
+                },
+            };
+        }
+
+        let condition = condition.unwrap_or_else(|| {
+            Expr::new(
+                ExprKind::Literal {
+                    value: LiteralValue::Bool(true),
+                },
+                Span::of_token(&semicolon),
+            )
+        });
+        body = Stmt {
+            span,
+            kind: StmtKind::While {
+                condition,
+                body: Box::new(body),
+            },
+        };
+
+        if let Some(initializer) = initializer {
+            body = Stmt {
+                span,
+                kind: StmtKind::Block {
+                    statements: vec![initializer, body],
+                },
+            };
+        }
+
+        Ok(body)
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
+
+        let then_branch = self.statement()?;
+        let mut span = Span::of_token(&keyword).to(then_branch.span);
+        let else_branch = if self.match_types(&[TokenType::Else]) {
+            let else_branch = self.statement()?;
+            span = span.to(else_branch.span);
+            Some(Box::new(else_branch))
+        } else {
+            None
+        };
+
+        Ok(Stmt {
+            span,
+            kind: StmtKind::If {
+                condition,
+                then_branch: Box::new(then_branch),
+                else_branch,
+            },
+        })
+    }
+
+    fn print_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let expression = self.expression()?;
+        let semicolon = self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+        Ok(Stmt {
+            span: Span::of_token(&keyword).to(Span::of_token(semicolon)),
+            kind: StmtKind::Print { expression },
+        })
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let value = if !self.check(&TokenType::Semicolon) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        let semicolon = self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        Ok(Stmt {
+            span: Span::of_token(&keyword).to(Span::of_token(semicolon)),
+            kind: StmtKind::Return { keyword, value },
+        })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = self.statement()?;
+        Ok(Stmt {
+            span: Span::of_token(&keyword).to(body.span),
+            kind: StmtKind::While {
+                condition,
+                body: Box::new(body),
+            },
+        })
+    }
+
+    // Parses the statements of a block whose '{' has been consumed and
+    // returns them with the closing brace.
+    fn block(&mut self) -> ParseResult<(Vec<Stmt>, Token)> {
+        let mut statements = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        let brace = self
+            .consume(TokenType::RightBrace, "Expect '}' after block.")?
+            .clone();
+        Ok((statements, brace))
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let expression = self.expression()?;
+        let semicolon = self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        Ok(Stmt {
+            span: expression.span.to(Span::of_token(semicolon)),
+            kind: StmtKind::Expression { expression },
+        })
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested(Parser::assignment)
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+
+        if self.match_types(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = self.expression()?;
+            let span = expr.span.to(value.span);
+
+            return match expr.kind {
+                ExprKind::Variable { name } => Ok(Expr::new(
+                    ExprKind::Assign {
+                        name,
+                        value: Box::new(value),
+                    },
+                    span,
+                )),
+                ExprKind::Get { object, name } => Ok(Expr::new(
+                    ExprKind::Set {
+                        object,
+                        name,
+                        value: Box::new(value),
+                    },
+                    span,
+                )),
+                kind => {
+                    self.error(
+                        &equals,
+                        codes::INVALID_ASSIGNMENT_TARGET,
+                        "Invalid assignment target.",
+                    );
+                    Ok(Expr {
+                        id: expr.id,
+                        kind,
+                        span: expr.span,
+                    })
+                }
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+        while self.match_types(&[TokenType::Or]) {
+            self.deepen()?;
+            let operator = self.previous().clone();
+            let right = self.and()?;
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Logical {
+                    left: Box::new(expr),
+                    operator,
+                    right: Box::new(right),
+                },
+                span,
+            );
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
+        while self.match_types(&[TokenType::And]) {
+            self.deepen()?;
+            let operator = self.previous().clone();
+            let right = self.equality()?;
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Logical {
+                    left: Box::new(expr),
+                    operator,
+                    right: Box::new(right),
+                },
+                span,
+            );
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        self.binary(
+            &[TokenType::BangEqual, TokenType::EqualEqual],
+            Parser::comparison,
+        )
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        self.binary(
+            &[
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+            ],
+            Parser::term,
+        )
+    }
+
+    fn term(&mut self) -> ParseResult<Expr> {
+        self.binary(&[TokenType::Minus, TokenType::Plus], Parser::factor)
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr> {
+        self.binary(&[TokenType::Slash, TokenType::Star], Parser::unary)
+    }
+
+    // Parses a left-associative chain of binary operators from `types`
+    // whose operands are parsed by `operand`.
+    fn binary(
+        &mut self,
+        types: &[TokenType],
+        operand: fn(&mut Parser) -> ParseResult<Expr>,
+    ) -> ParseResult<Expr> {
+        let mut expr = operand(self)?;
+        while self.match_types(types) {
+            self.deepen()?;
+            let operator = self.previous().clone();
+            let right = operand(self)?;
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    operator,
+                    right: Box::new(right),
+                },
+                span,
+            );
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        if self.match_types(&[TokenType::Bang, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Parser::unary)?;
+            let span = Span::of_token(&operator).to(right.span);
+            return Ok(Expr::new(
+                ExprKind::Unary {
+                    operator,
+                    right: Box::new(right),
+                },
+                span,
+            ));
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_types(&[TokenType::LeftParen]) {
+                self.deepen()?;
+                expr = self.finish_call(expr)?;
+            } else if self.match_types(&[TokenType::Dot]) {
+                self.deepen()?;
+                let name = self
+                    .consume(TokenType::Identifier, "Expect property name after '.'.")?
+                    .clone();
+                let span = expr.span.to(Span::of_token(&name));
+                expr = Expr::new(
+                    ExprKind::Get {
+                        object: Box::new(expr),
+                        name,
+                    },
+                    span,
+                );
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
+        let mut arguments = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGUMENTS {
+                    let token = self.peek().clone();
+                    self.error(
+                        &token,
+                        codes::TOO_MANY_ARGUMENTS,
+                        "Can't have more than 255 arguments.",
+                    );
+                }
+                arguments.push(self.expression()?);
+                if !self.match_types(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self
+            .consume(TokenType::RightParen, "Expect ')' after arguments.")?
+            .clone();
+        let span = callee.span.to(Span::of_token(&paren));
+        Ok(Expr::new(
+            ExprKind::Call {
+                callee: Box::new(callee),
+                paren,
+                arguments,
+            },
+            span,
+        ))
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        let token = self.peek().clone();
+        let span = Span::of_token(&token);
+        let kind = match token.token_type {
+            TokenType::False => ExprKind::Literal {
+                value: LiteralValue::Bool(false),
+            },
+            TokenType::True => ExprKind::Literal {
+                value: LiteralValue::Bool(true),
+            },
+            TokenType::Nil => ExprKind::Literal {
+                value: LiteralValue::Nil,
+            },
+            TokenType::Number => {
+                let text = token.literal.as_deref().unwrap_or(&token.lexeme);
+                ExprKind::Literal {
+                    value: LiteralValue::Number(text.parse().unwrap_or(0.0)),
+                }
+            }
+            TokenType::String => ExprKind::Literal {
+                value: LiteralValue::String(token.literal.clone().unwrap_or_default()),
+            },
+            TokenType::Super => {
+                self.advance();
+                self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+                let method = self
+                    .consume(TokenType::Identifier, "Expect superclass method name.")?
+                    .clone();
+                let span = span.to(Span::of_token(&method));
+                return Ok(Expr::new(
+                    ExprKind::Super {
+                        keyword: token,
+                        method,
+                    },
+                    span,
+                ));
+            }
+            TokenType::This => ExprKind::This {
+                keyword: token.clone(),
+            },
+            TokenType::Identifier => ExprKind::Variable {
+                name: token.clone(),
+            },
+            TokenType::LeftParen => {
+                self.advance();
+                let expression = self.expression()?;
+                let paren = self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+                return Ok(Expr::new(
+                    ExprKind::Grouping {
+                        expression: Box::new(expression),
+                    },
+                    span.to(Span::of_token(paren)),
+                ));
+            }
+            _ => {
+                self.error(&token, codes::EXPECT_EXPRESSION, "Expect expression.");
+                return Err(ParseError);
+            }
+        };
+        self.advance();
+        Ok(Expr::new(kind, span))
+    }
+
+    // Parses something nested one level deeper than what encloses it. Chains
+    // such as `a + b + c` nest one level deeper for every operator, and
+    // come back up here.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Parser) -> ParseResult<T>) -> ParseResult<T> {
+        let depth = self.depth;
+        self.deepen()?;
+        let result = parse(self);
+        self.depth = depth;
+        result
+    }
+
+    // Goes one level of nesting deeper, unless that would be too deep.
+    fn deepen(&mut self) -> ParseResult<()> {
+        if self.depth >= MAX_NESTING {
+            let token = self.peek().clone();
+            self.error(&token, codes::NESTING_TOO_DEEP, "Nesting too deep.");
+            return Err(ParseError);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn match_types(&mut self, types: &[TokenType]) -> bool {
+        for token_type in types {
+            if self.check(token_type) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> ParseResult<&Token> {
+        if self.check(&token_type) {
+            return Ok(self.advance());
+        }
+        let token = self.peek().clone();
+        self.error(&token, codes::EXPECTED_TOKEN, message);
+        Err(ParseError)
+    }
+
+    fn check(&self, token_type: &TokenType) -> bool {
+        !self.is_at_end() && self.peek().token_type == *token_type
+    }
+
+    fn advance(&mut self) -> &Token {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        self.previous()
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().token_type == TokenType::Eof
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn error(&mut self, token: &Token, code: &'static str, message: &str) {
+        let location = if token.token_type == TokenType::Eof {
+            "at end".to_string()
+        } else {
+            format!("at '{}'", token.lexeme)
+        };
+        let span = Span::of_token(token);
+        self.errors.push(
+            Diagnostic::error(code, message.to_string(), span.line, span.column)
+                .with_end(span.end_line, span.end_column)
+                .with_location(location),
+        );
+    }
+
+    // Discards tokens until the start of the next statement.
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.is_at_end() {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn parser_for(source: &str) -> Parser {
+        let mut scanner = Scanner::new(source.to_string());
+        Parser::new(scanner.scan_tokens().clone())
+    }
+
+    #[test]
+    fn test_parse_empty_program() {
+        let mut parser = parser_for("");
+        assert!(parser.parse().is_empty());
+        assert!(parser.errors().is_empty());
+    }
+
+    #[test]
+    fn test_parse_expression_statement_span() {
+        let mut parser = parser_for("  1 + 2;");
+        let statements = parser.parse();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(
+            statements[0].span,
+            Span {
+                line: 1,
+                column: 3,
+                end_line: 1,
+                end_column: 9
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_semicolon_reports_at_end() {
+        let mut parser = parser_for("print 1");
+        parser.parse();
+        let errors = parser.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::EXPECTED_TOKEN);
+        assert_eq!(errors[0].location, "at end");
+        assert_eq!(errors[0].message, "Expect ';' after value.");
+    }
+
+    #[test]
+    fn test_error_recovery_continues_parsing() {
+        let mut parser = parser_for("var = 1; print 2; print );");
+        let statements = parser.parse();
+        assert_eq!(statements.len(), 1);
+        let errors = parser.errors();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expect variable name.");
+        assert_eq!(errors[1].code, codes::EXPECT_EXPRESSION);
+        assert_eq!(errors[1].location, "at ')'");
+    }
+
+    #[test]
+    fn test_invalid_assignment_target() {
+        let mut parser = parser_for("a + b = c;");
+        let statements = parser.parse();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(parser.errors()[0].code, codes::INVALID_ASSIGNMENT_TARGET);
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let mut parser = parser_for("(1 + 2) * 3");
+        let expr = parser.parse_expression().unwrap();
+        assert!(matches!(expr.kind, ExprKind::Binary { .. }));
+
+        let mut parser = parser_for("1 2");
+        assert!(parser.parse_expression().is_none());
+    }
+
+    #[test]
+    fn test_expression_ids_are_unique() {
+        let mut parser = parser_for("a; a;");
+        let statements = parser.parse();
+        let ids: Vec<usize> = statements
+            .iter()
+            .map(|s| match &s.kind {
+                StmtKind::Expression { expression } => expression.id,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let too_deep = [
+            format!("print {}1;", "-".repeat(200_000)),
+            format!("print 1{};", " + 1".repeat(100_000)),
+            format!("{}{}", "{".repeat(100_000), "}".repeat(100_000)),
+        ];
+        for source in too_deep.iter() {
+            let mut parser = parser_for(source);
+            parser.parse();
+            assert_eq!(parser.errors()[0].code, codes::NESTING_TOO_DEEP);
+            assert_eq!(parser.errors()[0].message, "Nesting too deep.");
+        }
+
+        let deep = format!("print {}1;", "-".repeat(MAX_NESTING - 10));
+        let mut parser = parser_for(&deep);
+        parser.parse();
+        assert!(parser.errors().is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use crate::diagnostic::codes;
+use crate::parser::Parser;
+use crate::scanner::{Scanner, KEYWORDS};
+use crate::token::TokenType;
+use std::io::{self, BufRead, Write};
+
+/// Prompt shown when the REPL is waiting for a new piece of input.
+pub const PROMPT: &str = "> ";
+/// Prompt shown while the REPL is collecting the rest of unfinished input.
+pub const CONTINUATION_PROMPT: &str = "... ";
+
+/// The result of asking a `LineReader` for a line.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// A line of input, without its line ending.
+    Line(String),
+    /// The user pressed Ctrl-C; whatever was typed so far is dropped.
+    Interrupted,
+    Eof,
+}
+
+/// Where the REPL gets its input from.
+pub trait LineReader {
+    /// Shows `prompt` and reads one line.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input>;
+
+    /// Called before every prompt with the names of the current global
+    /// variables, each with the fields and methods of the instance it
+    /// holds, so readers that offer completion can suggest them.
+    fn set_globals(&mut self, _globals: Vec<(String, Vec<String>)>) {}
+
+    /// Called before every prompt with the unfinished input collected so
+    /// far, which the next line continues.
+    fn set_pending(&mut self, _pending: &str) {}
+}
+
+/// Reads lines from any `BufRead`, writing prompts to stdout. Used when
+/// standard input is not a terminal.
+pub struct PlainReader<R> {
+    input: R,
+}
+
+impl<R: BufRead> PlainReader<R> {
+    pub fn new(input: R) -> Self {
+        PlainReader { input }
+    }
+}
+
+impl<R: BufRead> LineReader for PlainReader<R> {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(Input::Eof);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Input::Line(line))
+    }
+}
+
+/// Tab completion for the word that ends at byte offset `pos` of `line`.
+/// After a `.` it offers the members listed for the global named before
+/// the dot; elsewhere it offers keywords and global names. Returns where
+/// the completed word starts and the sorted candidates.
+pub fn complete(
+    line: &str,
+    pos: usize,
+    globals: &[(String, Vec<String>)],
+) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let start = word_start(before);
+    let prefix = &before[start..];
+
+    let mut candidates: Vec<String> = match before[..start].strip_suffix('.') {
+        Some(object) => {
+            let name = &object[word_start(object)..];
+            match globals.iter().find(|(global, _)| global == name) {
+                Some((_, members)) => members.clone(),
+                None => Vec::new(),
+            }
+        }
+        None => KEYWORDS
+            .iter()
+            .map(|(keyword, _)| keyword.to_string())
+            .chain(globals.iter().map(|(name, _)| name.clone()))
+            .collect(),
+    };
+    candidates.retain(|candidate| candidate.starts_with(prefix));
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+// Byte offset where the identifier at the end of `text` begins.
+fn word_start(text: &str) -> usize {
+    text.char_indices()
+        .rev()
+        .take_while(|(_, c)| c.is_alphanumeric() || *c == '_')
+        .last()
+        .map_or(text.len(), |(index, _)| index)
+}
+
+/// A colon command typed at the REPL prompt. These are handled by the REPL
+/// itself and never reach the scanner.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Help,
+    Quit,
+    Load(String),
+    Reset,
+    Env,
+    Tokens(String),
+    Ast(String),
+    Time(String),
+}
+
+struct CommandInfo {
+    name: &'static str,
+    argument: Option<&'static str>,
+    summary: &'static str,
+}
+
+const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "help",
+        argument: None,
+        summary: "Show this list of commands.",
+    },
+    CommandInfo {
+        name: "quit",
+        argument: None,
+        summary: "Leave the REPL.",
+    },
+    CommandInfo {
+        name: "load",
+        argument: Some("<file>"),
+        summary: "Run a script in the current session.",
+    },
+    CommandInfo {
+        name: "reset",
+        argument: None,
+        summary: "Forget all global definitions.",
+    },
+    CommandInfo {
+        name: "env",
+        argument: None,
+        summary: "List the global variables and their values.",
+    },
+    CommandInfo {
+        name: "tokens",
+        argument: Some("<code>"),
+        summary: "Show the tokens the scanner produces for code.",
+    },
+    CommandInfo {
+        name: "ast",
+        argument: Some("<code>"),
+        summary: "Show the syntax tree the parser builds for code.",
+    },
+    CommandInfo {
+        name: "time",
+        argument: Some("<code>"),
+        summary: "Run code and report how long it took.",
+    },
+];
+
+/// Whether a line of REPL input is a colon command rather than Lox code.
+pub fn is_command(line: &str) -> bool {
+    line.trim_start().starts_with(':')
+}
+
+/// Parses a line such as `:load foo.lox`. Returns a message describing the
+/// problem for unknown commands or a missing argument.
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let line = line.strip_prefix(':').unwrap_or(line);
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let info = COMMANDS
+        .iter()
+        .find(|info| info.name == name)
+        .ok_or_else(|| format!("Unknown command ':{}'. Type :help for a list.", name))?;
+    match info.argument {
+        Some(placeholder) if argument.is_empty() => {
+            return Err(format!("Usage: :{} {}", info.name, placeholder));
+        }
+        None if !argument.is_empty() => {
+            return Err(format!(":{} takes no argument.", info.name));
+        }
+        _ => {}
+    }
+
+    let argument = argument.to_string();
+    Ok(match name {
+        "help" => Command::Help,
+        "quit" => Command::Quit,
+        "load" => Command::Load(argument),
+        "reset" => Command::Reset,
+        "env" => Command::Env,
+        "tokens" => Command::Tokens(argument),
+        "ast" => Command::Ast(argument),
+        "time" => Command::Time(argument),
+        _ => unreachable!("command table and parser disagree"),
+    })
+}
+
+/// The text printed by `:help`.
+pub fn help() -> String {
+    let mut out = String::from("Commands:\n");
+    for info in COMMANDS {
+        let usage = match info.argument {
+            Some(placeholder) => format!(":{} {}", info.name, placeholder),
+            None => format!(":{}", info.name),
+        };
+        out.push_str(&format!("  {:<16}{}\n", usage, info.summary));
+    }
+    out.push_str(
+        "\nAnything else is run as Lox code. The value of a bare expression is printed.\n",
+    );
+    out
+}
+
+/// Whether `source` can be run as it is, or whether the REPL should keep
+/// reading lines. Input is unfinished while it has an unterminated string
+/// or block comment, leaves a `(` or `{` open, or does not end in `;` or
+/// `}`. A lone expression needs no `;`, since the REPL echoes it.
+pub fn is_complete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source.to_string());
+    let tokens = scanner.scan_tokens().clone();
+    let unterminated = scanner.errors().iter().any(|diagnostic| {
+        diagnostic.code == codes::UNTERMINATED_STRING
+            || diagnostic.code == codes::UNTERMINATED_COMMENT
+    });
+    if unterminated {
+        return false;
+    }
+
+    let mut depth = 0isize;
+    for token in &tokens {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        // Extra closing brackets are an error no further input can fix.
+        return depth < 0;
+    }
+
+    // The last token is always EOF, so look at the one before it.
+    match tokens.iter().rev().nth(1) {
+        None => true,
+        Some(token)
+            if matches!(
+                token.token_type,
+                TokenType::Semicolon | TokenType::RightBrace
+            ) =>
+        {
+            true
+        }
+        Some(_) => Parser::new(tokens).parse_expression().is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Backend;
+
+    fn globals(source: &str, backend: Backend) -> Vec<(String, Vec<String>)> {
+        let mut interpreter = crate::Interpreter::new();
+        interpreter.set_backend(backend);
+        interpreter.set_sandbox(crate::sandbox::Sandbox::unrestricted());
+        assert!(!interpreter.run_source(source.to_string()));
+        interpreter.global_members()
+    }
+
+    #[test]
+    fn test_complete_keywords_and_globals() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let globals = globals("var counter = 1; fun compute() {}", backend);
+            assert_eq!(
+                complete("co", 2, &globals),
+                (0, vec!["compute".to_string(), "counter".to_string()])
+            );
+            assert_eq!(
+                complete("print cl", 8, &globals),
+                (6, vec!["class".to_string(), "clock".to_string()])
+            );
+            assert_eq!(complete("1 + zz", 6, &globals), (4, Vec::<String>::new()));
+        }
+    }
+
+    #[test]
+    fn test_complete_members_after_dot() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let globals = globals(
+                "class Base { greet() {} } class Point < Base { init() { this.x = 1; } }\n\
+                 var p = Point(); p.y = 2; var q = Point(); var n = 3;",
+                backend,
+            );
+            assert_eq!(
+                complete("print p.", 8, &globals),
+                (
+                    8,
+                    vec![
+                        "greet".to_string(),
+                        "init".to_string(),
+                        "x".to_string(),
+                        "y".to_string()
+                    ]
+                )
+            );
+            assert_eq!(complete("p.g", 3, &globals), (2, vec!["greet".to_string()]));
+            assert_eq!(complete("q.", 2, &globals).1.len(), 3);
+            assert_eq!(complete("n.", 2, &globals), (2, Vec::<String>::new()));
+        }
+    }
+
+    #[test]
+    fn test_plain_reader_strips_line_endings() {
+        let mut reader = PlainReader::new("a\r\nb".as_bytes());
+        assert_eq!(reader.read_line("").unwrap(), Input::Line("a".to_string()));
+        assert_eq!(reader.read_line("").unwrap(), Input::Line("b".to_string()));
+        assert_eq!(reader.read_line("").unwrap(), Input::Eof);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(":help"), Ok(Command::Help));
+        assert_eq!(parse_command("  :quit  "), Ok(Command::Quit));
+        assert_eq!(
+            parse_command(":load  scripts/a b.lox "),
+            Ok(Command::Load("scripts/a b.lox".to_string()))
+        );
+        assert_eq!(
+            parse_command(":time fib(20)"),
+            Ok(Command::Time("fib(20)".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_command_errors() {
+        assert_eq!(
+            parse_command(":nope"),
+            Err("Unknown command ':nope'. Type :help for a list.".to_string())
+        );
+        assert_eq!(
+            parse_command(":tokens"),
+            Err("Usage: :tokens <code>".to_string())
+        );
+        assert_eq!(
+            parse_command(":reset now"),
+            Err(":reset takes no argument.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let help = help();
+        for info in COMMANDS {
+            assert!(help.contains(&format!(":{}", info.name)));
+        }
+    }
+
+    #[test]
+    fn test_complete_input() {
+        assert!(is_complete(""));
+        assert!(is_complete("// just a comment"));
+        assert!(is_complete("var a = 1;"));
+        assert!(is_complete("fun f() { return 1; }"));
+        assert!(is_complete("1 + 2"));
+        assert!(is_complete("a.b(c)"));
+        assert!(is_complete("print 1; }"));
+    }
+
+    #[test]
+    fn test_unbalanced_brackets() {
+        assert!(!is_complete("fun f() {"));
+        assert!(!is_complete("class A {\n  m() { print 1; }"));
+        assert!(!is_complete("print max(1,"));
+        assert!(!is_complete("if (a"));
+    }
+
+    #[test]
+    fn test_unterminated_string_or_comment() {
+        assert!(!is_complete("print \"hello"));
+        assert!(!is_complete("/* a comment"));
+        assert!(is_complete("/* a comment */ print 1;"));
+    }
+
+    #[test]
+    fn test_missing_semicolon() {
+        assert!(!is_complete("var a = 1"));
+        assert!(!is_complete("print 1 +"));
+        assert!(!is_complete("if (a) print 1; else"));
+        assert!(!is_complete("1 +"));
+    }
+}
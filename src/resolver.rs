@@ -0,0 +1,396 @@
+use crate::ast::{Expr, ExprKind, Function, Span, Stmt, StmtKind};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::token::Token;
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// Static pass that binds every local variable reference to the scope it
+/// is declared in, and reports scoping mistakes before anything runs.
+///
+/// Variables that are not found in any enclosing block are assumed to be
+/// globals and are left out of `locals`.
+pub struct Resolver {
+    // Each scope maps a name to whether its initializer has finished.
+    scopes: Vec<HashMap<String, bool>>,
+    locals: HashMap<usize, usize>,
+    errors: Vec<Diagnostic>,
+    current_function: FunctionType,
+    current_class: ClassType,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver {
+            scopes: Vec::new(),
+            locals: HashMap::new(),
+            errors: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+        }
+    }
+
+    pub fn resolve(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.resolve_stmt(statement);
+        }
+    }
+
+    /// Scope distances keyed by expression id, for the expressions that
+    /// refer to local variables.
+    pub fn locals(&self) -> &HashMap<usize, usize> {
+        &self.locals
+    }
+
+    pub fn into_locals(self) -> HashMap<usize, usize> {
+        self.locals
+    }
+
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Block { statements } => {
+                self.begin_scope();
+                self.resolve(statements);
+                self.end_scope();
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+                self.declare(name);
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    if let ExprKind::Variable {
+                        name: superclass_name,
+                    } = &superclass.kind
+                    {
+                        if superclass_name.lexeme == name.lexeme {
+                            self.error(
+                                superclass_name,
+                                codes::INHERIT_FROM_SELF,
+                                "A class can't inherit from itself.",
+                            );
+                        }
+                    }
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass);
+
+                    self.begin_scope();
+                    self.scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert("super".to_string(), true);
+                }
+
+                self.begin_scope();
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert("this".to_string(), true);
+                for method in methods {
+                    let declaration = if method.name.lexeme == "init" {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.resolve_function(method, declaration);
+                }
+                self.end_scope();
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+                self.current_class = enclosing_class;
+            }
+            StmtKind::Expression { expression } => self.resolve_expr(expression),
+            StmtKind::Function { function } => {
+                self.declare(&function.name);
+                self.define(&function.name);
+                self.resolve_function(function, FunctionType::Function);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch);
+                }
+            }
+            StmtKind::Print { expression } => self.resolve_expr(expression),
+            StmtKind::Return { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    self.error(
+                        keyword,
+                        codes::RETURN_FROM_TOP_LEVEL,
+                        "Can't return from top-level code.",
+                    );
+                }
+                if let Some(value) = value {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(
+                            keyword,
+                            codes::RETURN_FROM_INITIALIZER,
+                            "Can't return a value from an initializer.",
+                        );
+                    }
+                    self.resolve_expr(value);
+                }
+            }
+            StmtKind::Var { name, initializer } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.resolve_expr(initializer);
+                }
+                self.define(name);
+            }
+            StmtKind::While { condition, body } => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(body);
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Assign { name, value } => {
+                self.resolve_expr(value);
+                self.resolve_local(expr.id, name);
+            }
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            ExprKind::Call {
+                callee, arguments, ..
+            } => {
+                self.resolve_expr(callee);
+                for argument in arguments {
+                    self.resolve_expr(argument);
+                }
+            }
+            ExprKind::Get { object, .. } => self.resolve_expr(object),
+            ExprKind::Grouping { expression } => self.resolve_expr(expression),
+            ExprKind::Literal { .. } => {}
+            ExprKind::Set { object, value, .. } => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            ExprKind::Super { keyword, .. } => {
+                match self.current_class {
+                    ClassType::None => self.error(
+                        keyword,
+                        codes::SUPER_OUTSIDE_CLASS,
+                        "Can't use 'super' outside of a class.",
+                    ),
+                    ClassType::Class => self.error(
+                        keyword,
+                        codes::SUPER_WITHOUT_SUPERCLASS,
+                        "Can't use 'super' in a class with no superclass.",
+                    ),
+                    ClassType::Subclass => {}
+                }
+                self.resolve_local(expr.id, keyword);
+            }
+            ExprKind::This { keyword } => {
+                if self.current_class == ClassType::None {
+                    self.error(
+                        keyword,
+                        codes::THIS_OUTSIDE_CLASS,
+                        "Can't use 'this' outside of a class.",
+                    );
+                    return;
+                }
+                self.resolve_local(expr.id, keyword);
+            }
+            ExprKind::Unary { right, .. } => self.resolve_expr(right),
+            ExprKind::Variable { name } => {
+                if let Some(scope) = self.scopes.last() {
+                    if scope.get(&name.lexeme) == Some(&false) {
+                        self.error(
+                            name,
+                            codes::READ_IN_OWN_INITIALIZER,
+                            "Can't read local variable in its own initializer.",
+                        );
+                    }
+                }
+                self.resolve_local(expr.id, name);
+            }
+        }
+    }
+
+    fn resolve_function(&mut self, function: &Function, function_type: FunctionType) {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+
+        self.begin_scope();
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.resolve(&function.body);
+        self.end_scope();
+
+        self.current_function = enclosing_function;
+    }
+
+    fn resolve_local(&mut self, id: usize, name: &Token) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(&name.lexeme) {
+                self.locals.insert(id, depth);
+                return;
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &Token) {
+        let already_declared = match self.scopes.last_mut() {
+            Some(scope) => scope.insert(name.lexeme.clone(), false).is_some(),
+            None => return,
+        };
+        if already_declared {
+            self.error(
+                name,
+                codes::ALREADY_DECLARED,
+                "Already a variable with this name in this scope.",
+            );
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme.clone(), true);
+        }
+    }
+
+    fn error(&mut self, token: &Token, code: &'static str, message: &str) {
+        let span = Span::of_token(token);
+        self.errors.push(
+            Diagnostic::error(code, message.to_string(), span.line, span.column)
+                .with_end(span.end_line, span.end_column)
+                .with_location(format!("at '{}'", token.lexeme)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn resolve(source: &str) -> (Vec<Stmt>, Resolver) {
+        let mut scanner = Scanner::new(source.to_string());
+        let mut parser = Parser::new(scanner.scan_tokens().clone());
+        let statements = parser.parse();
+        assert!(parser.errors().is_empty());
+        let mut resolver = Resolver::new();
+        resolver.resolve(&statements);
+        (statements, resolver)
+    }
+
+    fn error_messages(source: &str) -> Vec<String> {
+        let (_, resolver) = resolve(source);
+        resolver
+            .errors()
+            .iter()
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_globals_are_not_resolved() {
+        let (_, resolver) = resolve("var a = 1; print a;");
+        assert!(resolver.locals().is_empty());
+        assert!(resolver.errors().is_empty());
+    }
+
+    #[test]
+    fn test_local_depths() {
+        let (statements, resolver) = resolve("{ var a = 1; { print a; } }");
+        let inner = match &statements[0].kind {
+            StmtKind::Block { statements } => match &statements[1].kind {
+                StmtKind::Block { statements } => match &statements[0].kind {
+                    StmtKind::Print { expression } => expression.id,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(resolver.locals().get(&inner), Some(&1));
+    }
+
+    #[test]
+    fn test_scope_errors() {
+        assert_eq!(
+            error_messages("{ var a = a; }"),
+            vec!["Can't read local variable in its own initializer."]
+        );
+        assert_eq!(
+            error_messages("{ var a; var a; }"),
+            vec!["Already a variable with this name in this scope."]
+        );
+        assert_eq!(
+            error_messages("return 1;"),
+            vec!["Can't return from top-level code."]
+        );
+    }
+
+    #[test]
+    fn test_class_errors() {
+        assert_eq!(
+            error_messages("print this;"),
+            vec!["Can't use 'this' outside of a class."]
+        );
+        assert_eq!(
+            error_messages("class A { f() { super.f(); } }"),
+            vec!["Can't use 'super' in a class with no superclass."]
+        );
+        assert_eq!(
+            error_messages("class A < A {}"),
+            vec!["A class can't inherit from itself."]
+        );
+        assert_eq!(
+            error_messages("class A { init() { return 1; } }"),
+            vec!["Can't return a value from an initializer."]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! What both backends share while running scripts: runtime errors, the
+//! stack traces reported with them, and where `print` writes by default.
+
+use crate::ast::Span;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::token::{Token, TokenType};
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// How deep calls may nest before a "Stack overflow." error, unless
+/// configured otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+/// An error raised while a script is running.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+    pub code: &'static str,
+    pub token: Token,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(code: &'static str, token: &Token, message: String) -> Self {
+        RuntimeError {
+            code,
+            token: token.clone(),
+            message,
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let span = Span::of_token(&self.token);
+        let diagnostic = Diagnostic::error(self.code, self.message.clone(), span.line, span.column)
+            .with_end(span.end_line, span.end_column);
+        // Errors raised outside any one token, such as running out of
+        // steps, have only a line.
+        if self.token.token_type == TokenType::Eof {
+            diagnostic
+        } else {
+            diagnostic.with_location(format!("at '{}'", self.token.lexeme))
+        }
+    }
+
+    // What unwinds a script that called `exit`. The backends return the
+    // exit status instead of reporting it.
+    pub(crate) fn exit() -> Self {
+        let token = Token::new(TokenType::Eof, String::new(), None, 0);
+        RuntimeError::new(codes::GENERIC, &token, "Exited.".to_string())
+    }
+}
+
+/// A function call in progress when a runtime error was raised.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceFrame {
+    /// The function's name, or empty for the top-level script.
+    pub function: String,
+    /// The file the function was declared in, if it came from one.
+    pub file: Option<Rc<str>>,
+    /// The line the function had reached.
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "[line {}] in script", self.line)
+        } else {
+            write!(f, "[line {}] in {}()", self.line, self.function)
+        }
+    }
+}
+
+// Frames repeated more than this many times in a row are collapsed when a
+// stack trace is printed.
+const REPEATED_FRAMES_SHOWN: usize = 3;
+
+/// The lines to print for a stack trace, innermost frame first. Frames
+/// declared in another file than the script name it, and a run of the same
+/// frame, as deep recursion leaves, shows only its first few frames.
+pub fn trace_lines(trace: &[TraceFrame]) -> Vec<String> {
+    let script_file = trace.last().and_then(|frame| frame.file.clone());
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < trace.len() {
+        let frame = &trace[start];
+        let repeats = trace[start..]
+            .iter()
+            .take_while(|other| *other == frame)
+            .count();
+        let line = match &frame.file {
+            Some(file) if Some(file) != script_file.as_ref() => format!("{} ({})", frame, file),
+            _ => frame.to_string(),
+        };
+        lines.extend(std::iter::repeat_n(
+            line,
+            repeats.min(REPEATED_FRAMES_SHOWN),
+        ));
+        if repeats > REPEATED_FRAMES_SHOWN {
+            lines.push(format!("... {} more", repeats - REPEATED_FRAMES_SHOWN));
+        }
+        start += repeats;
+    }
+    lines
+}
+
+// Standard output through `print!`, so that the test harness captures it.
+pub(crate) struct Stdout;
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
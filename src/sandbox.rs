@@ -0,0 +1,237 @@
+//! What scripts are allowed to do: which host facilities the native
+//! functions expose to them, and how many steps and how much memory they
+//! may take.
+
+use crate::ast::Stmt;
+use crate::diagnostic::Diagnostic;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::scanner::Scanner;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many steps a script may take under `Sandbox::restricted`.
+pub const RESTRICTED_MAX_STEPS: u64 = 10_000_000;
+/// How many bytes script objects may hold under `Sandbox::restricted`.
+pub const RESTRICTED_MAX_MEMORY: usize = 64 << 20;
+
+/// A host facility that some native functions give scripts access to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Capability {
+    /// `readFile` and `writeFile`.
+    FileIo,
+    /// `getenv`.
+    EnvVars,
+    /// `clock`.
+    Clock,
+    /// `exit`.
+    ProcessExit,
+    /// `import`.
+    Modules,
+}
+
+/// The capabilities scripts are granted. Natives for the others are not
+/// defined at all, so using them is an undefined variable error. The
+/// default grants none.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Capabilities {
+    pub file_io: bool,
+    pub env_vars: bool,
+    pub clock: bool,
+    pub process_exit: bool,
+    pub modules: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Capabilities {
+            file_io: true,
+            env_vars: true,
+            clock: true,
+            process_exit: true,
+            modules: true,
+        }
+    }
+
+    pub fn none() -> Self {
+        Capabilities {
+            file_io: false,
+            env_vars: false,
+            clock: false,
+            process_exit: false,
+            modules: false,
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::FileIo => self.file_io,
+            Capability::EnvVars => self.env_vars,
+            Capability::Clock => self.clock,
+            Capability::ProcessExit => self.process_exit,
+            Capability::Modules => self.modules,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::none()
+    }
+}
+
+/// The limits a script runs under, for running code that is not trusted.
+/// The default grants no capabilities and sets no limits, which is how
+/// scripts run unless configured otherwise.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Sandbox {
+    pub capabilities: Capabilities,
+    /// How many steps each run may take, or `None` for no limit.
+    pub max_steps: Option<u64>,
+    /// How many bytes script objects may hold, or `None` for no limit.
+    pub max_memory: Option<usize>,
+}
+
+impl Sandbox {
+    /// Every capability and no limits, which is how the `rlox` binary runs
+    /// scripts without `--sandbox`.
+    pub fn unrestricted() -> Self {
+        Sandbox {
+            capabilities: Capabilities::all(),
+            ..Sandbox::default()
+        }
+    }
+
+    /// No capabilities, and limits generous enough for ordinary scripts
+    /// but not for runaway ones.
+    pub fn restricted() -> Self {
+        Sandbox {
+            capabilities: Capabilities::none(),
+            max_steps: Some(RESTRICTED_MAX_STEPS),
+            max_memory: Some(RESTRICTED_MAX_MEMORY),
+        }
+    }
+}
+
+/// How a native function call ends without a value: with an error, or by
+/// asking the backend running it to do what natives cannot do themselves.
+#[derive(Debug, PartialEq)]
+pub enum NativeError {
+    /// A runtime error with this message.
+    Message(String),
+    /// Stop the script, with this exit status.
+    Exit(i32),
+    /// Run the module at this path, unless it has run already.
+    Import(String),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+// The host side of the natives, shared by both backends. They take and
+// return Rust values, leaving the conversion from and to Lox values to
+// each backend.
+
+pub(crate) fn seconds_since_epoch() -> Result<f64, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(now.as_secs_f64())
+}
+
+pub(crate) fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Could not read '{}': {}.", path, e))
+}
+
+pub(crate) fn write_file(path: &str, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Could not write '{}': {}.", path, e))
+}
+
+pub(crate) fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+pub(crate) fn exit(code: f64) -> NativeError {
+    if code.fract() != 0.0 || !(0.0..=255.0).contains(&code) {
+        return NativeError::Message("Exit code must be an integer between 0 and 255.".to_string());
+    }
+    NativeError::Exit(code as i32)
+}
+
+// Reads, parses and resolves the module at `path` for `import`, returning
+// its statements and the scope distances of its local variables.
+pub(crate) fn load_module(path: &str) -> Result<(Vec<Stmt>, HashMap<usize, usize>), String> {
+    let source = read_file(path)?;
+    let mut scanner = Scanner::new(source);
+    let tokens = scanner.scan_tokens().clone();
+    let mut parser = Parser::new(tokens);
+    let statements = parser.parse();
+    if let Some(diagnostic) = scanner.errors().iter().chain(parser.errors()).next() {
+        return Err(module_error(path, diagnostic));
+    }
+    let mut resolver = Resolver::new();
+    resolver.resolve(&statements);
+    if let Some(diagnostic) = resolver.errors().first() {
+        return Err(module_error(path, diagnostic));
+    }
+    Ok((statements, resolver.into_locals()))
+}
+
+// The message of the runtime error `import` raises for a module with
+// errors.
+pub(crate) fn module_error(path: &str, diagnostic: &Diagnostic) -> String {
+    format!(
+        "Could not import '{}': line {}: {}",
+        path, diagnostic.line, diagnostic.message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let sandbox = Sandbox::unrestricted();
+        assert!(sandbox.capabilities.allows(Capability::FileIo));
+        assert_eq!(sandbox.max_steps, None);
+        assert_eq!(sandbox.max_memory, None);
+
+        let sandbox = Sandbox::restricted();
+        for capability in [
+            Capability::FileIo,
+            Capability::EnvVars,
+            Capability::Clock,
+            Capability::ProcessExit,
+            Capability::Modules,
+        ] {
+            assert!(!sandbox.capabilities.allows(capability));
+        }
+        assert_eq!(sandbox.max_steps, Some(RESTRICTED_MAX_STEPS));
+        assert_eq!(sandbox.max_memory, Some(RESTRICTED_MAX_MEMORY));
+    }
+
+    #[test]
+    fn test_nothing_is_allowed_by_default() {
+        assert_eq!(Sandbox::default().capabilities, Capabilities::none());
+        assert_eq!(
+            Sandbox::unrestricted(),
+            Sandbox {
+                capabilities: Capabilities::all(),
+                ..Sandbox::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_exit_code_must_be_a_status() {
+        assert_eq!(exit(3.0), NativeError::Exit(3));
+        for code in [1.5, -1.0, 256.0].iter() {
+            assert!(matches!(exit(*code), NativeError::Message(_)));
+        }
+    }
+}
@@ -1,41 +1,62 @@
+use crate::diagnostic::{codes, Diagnostic};
 use crate::token::{Token, TokenType};
 use std::collections::HashMap;
+use std::ops::Range;
+
+/// Every reserved word of Lox and the token it scans to.
+pub const KEYWORDS: &[(&str, TokenType)] = &[
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
 
 pub struct Scanner {
     source: String,
     tokens: Vec<Token>,
+    errors: Vec<Diagnostic>,
+    // Byte ranges of the comments skipped so far.
+    comments: Vec<Range<usize>>,
     start: usize,
     current: usize,
     line: usize,
+    // The column of `current`, counted in characters.
+    column: usize,
+    start_line: usize,
+    start_column: usize,
     keywords: HashMap<String, TokenType>,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
-        let mut keywords = HashMap::new();
-        keywords.insert("and".to_string(), TokenType::And);
-        keywords.insert("class".to_string(), TokenType::Class);
-        keywords.insert("else".to_string(), TokenType::Else);
-        keywords.insert("false".to_string(), TokenType::False);
-        keywords.insert("for".to_string(), TokenType::For);
-        keywords.insert("fun".to_string(), TokenType::Fun);
-        keywords.insert("if".to_string(), TokenType::If);
-        keywords.insert("nil".to_string(), TokenType::Nil);
-        keywords.insert("or".to_string(), TokenType::Or);
-        keywords.insert("print".to_string(), TokenType::Print);
-        keywords.insert("return".to_string(), TokenType::Return);
-        keywords.insert("super".to_string(), TokenType::Super);
-        keywords.insert("this".to_string(), TokenType::This);
-        keywords.insert("true".to_string(), TokenType::True);
-        keywords.insert("var".to_string(), TokenType::Var);
-        keywords.insert("while".to_string(), TokenType::While);
+        let keywords = KEYWORDS
+            .iter()
+            .map(|(text, token_type)| (text.to_string(), token_type.clone()))
+            .collect();
 
         Scanner {
             source,
             tokens: Vec::new(),
+            errors: Vec::new(),
+            comments: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
             keywords,
         }
     }
@@ -43,18 +64,45 @@ impl Scanner {
     pub fn scan_tokens(&mut self) -> &Vec<Token> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
             self.scan_token();
         }
 
-        self.tokens.push(Token::new(
-            TokenType::Eof,
-            "".to_string(),
-            None,
-            self.line,
-        ));
+        self.tokens.push(
+            Token::new(TokenType::Eof, "".to_string(), None, self.line)
+                .with_column(self.column)
+                .with_offset(self.current),
+        );
         &self.tokens
     }
 
+    /// Byte ranges of the comments in the source, which produce no tokens.
+    /// An unterminated block comment runs to the end of the source.
+    pub fn comments(&self) -> &[Range<usize>] {
+        &self.comments
+    }
+
+    /// Lexical errors found by the last call to `scan_tokens`.
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
+    fn error(&mut self, code: &'static str, message: &str) -> Diagnostic {
+        Diagnostic::error(
+            code,
+            message.to_string(),
+            self.start_line,
+            self.start_column,
+        )
+        .with_end(self.line, self.column)
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -110,6 +158,10 @@ impl Scanner {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    self.comments.push(self.start..self.current);
+                } else if self.match_char('*') {
+                    self.block_comment();
+                    self.comments.push(self.start..self.current);
                 } else {
                     self.add_token(TokenType::Slash);
                 }
@@ -117,20 +169,28 @@ impl Scanner {
             '&' => {
                 if self.match_char('&') {
                     self.add_token(TokenType::And);
+                } else {
+                    let diagnostic = self
+                        .error(codes::UNEXPECTED_CHARACTER, "Unexpected character.")
+                        .with_note("use 'and' or '&&' for logical and".to_string());
+                    self.errors.push(diagnostic);
                 }
-                // Single & is not valid in Lox, could report error here
             }
             '|' => {
                 if self.match_char('|') {
                     self.add_token(TokenType::Or);
+                } else {
+                    let diagnostic = self
+                        .error(codes::UNEXPECTED_CHARACTER, "Unexpected character.")
+                        .with_note("use 'or' or '||' for logical or".to_string());
+                    self.errors.push(diagnostic);
                 }
-                // Single | is not valid in Lox, could report error here
             }
             ' ' | '\r' | '\t' => {
                 // Ignore whitespace
             }
             '\n' => {
-                self.line += 1;
+                self.newline();
             }
             '"' => self.string(),
             _ => {
@@ -139,15 +199,18 @@ impl Scanner {
                 } else if c.is_alphabetic() || c == '_' {
                     self.identifier();
                 } else {
-                    // Unexpected character - in a real implementation, we'd report an error
+                    let diagnostic =
+                        self.error(codes::UNEXPECTED_CHARACTER, "Unexpected character.");
+                    self.errors.push(diagnostic);
                 }
             }
         }
     }
 
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap_or('\0');
-        self.current += 1;
+        let c = self.source[self.current..].chars().next().unwrap_or('\0');
+        self.current += c.len_utf8();
+        self.column += 1;
         c
     }
 
@@ -157,17 +220,22 @@ impl Scanner {
 
     fn add_token_with_literal(&mut self, token_type: TokenType, literal: Option<String>) {
         let text = self.source[self.start..self.current].to_string();
-        self.tokens.push(Token::new(token_type, text, literal, self.line));
+        self.tokens.push(
+            Token::new(token_type, text, literal, self.start_line)
+                .with_column(self.start_column)
+                .with_offset(self.start),
+        );
     }
 
     fn match_char(&mut self, expected: char) -> bool {
         if self.is_at_end() {
             return false;
         }
-        if self.source.chars().nth(self.current).unwrap_or('\0') != expected {
+        if self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.current += expected.len_utf8();
+        self.column += 1;
         true
     }
 
@@ -175,20 +243,23 @@ impl Scanner {
         if self.is_at_end() {
             '\0'
         } else {
-            self.source.chars().nth(self.current).unwrap_or('\0')
+            self.source[self.current..].chars().next().unwrap_or('\0')
         }
     }
 
     fn string(&mut self) {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            let c = self.advance();
+            if c == '\n' {
+                self.newline();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            // Unterminated string - in a real implementation, we'd report an error
+            let diagnostic = self
+                .error(codes::UNTERMINATED_STRING, "Unterminated string.")
+                .with_note("add a closing '\"' to end the string".to_string());
+            self.errors.push(diagnostic);
             return;
         }
 
@@ -200,6 +271,25 @@ impl Scanner {
         self.add_token_with_literal(TokenType::String, Some(value));
     }
 
+    fn block_comment(&mut self) {
+        while !self.is_at_end() {
+            if self.peek() == '*' && self.peek_next() == '/' {
+                // The closing */
+                self.advance();
+                self.advance();
+                return;
+            }
+            if self.advance() == '\n' {
+                self.newline();
+            }
+        }
+
+        let diagnostic = self
+            .error(codes::UNTERMINATED_COMMENT, "Unterminated block comment.")
+            .with_note("add '*/' to end the comment".to_string());
+        self.errors.push(diagnostic);
+    }
+
     fn number(&mut self) {
         while self.peek().is_ascii_digit() {
             self.advance();
@@ -220,11 +310,9 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current + 1).unwrap_or('\0')
-        }
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
     }
 
     fn identifier(&mut self) {
@@ -360,6 +448,41 @@ mod tests {
         assert_eq!(tokens[2].token_type, TokenType::Semicolon);
     }
 
+    #[test]
+    fn test_block_comment() {
+        let mut scanner = Scanner::new("var /* a\n * b */ x;".to_string());
+        let tokens = scanner.scan_tokens().clone();
+
+        assert!(scanner.errors().is_empty());
+        assert_eq!(tokens.len(), 4); // var, x, ;, EOF
+        assert_eq!(tokens[1].lexeme, "x");
+        assert_eq!((tokens[1].line, tokens[1].column), (2, 9));
+    }
+
+    #[test]
+    fn test_offsets_and_comment_ranges() {
+        let source = "// intro\nvar é = \"s\"; /* x */";
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens().clone();
+
+        let offsets: Vec<usize> = tokens.iter().map(|t| t.offset).collect();
+        assert_eq!(offsets, vec![9, 13, 16, 18, 21, 30]);
+        assert_eq!(&source[tokens[3].offset..][..3], "\"s\"");
+        assert_eq!(scanner.comments(), &[0..8, 23..30]);
+    }
+
+    #[test]
+    fn test_unterminated_block_comment_error() {
+        let mut scanner = Scanner::new("print 1; /* oops".to_string());
+        let tokens = scanner.scan_tokens();
+
+        assert_eq!(tokens.len(), 4); // print, 1, ;, EOF
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::UNTERMINATED_COMMENT);
+        assert_eq!((errors[0].line, errors[0].column), (1, 10));
+    }
+
     #[test]
     fn test_whitespace_handling() {
         let mut scanner = Scanner::new("   var   x   ;   ".to_string());
@@ -380,4 +503,52 @@ mod tests {
         assert_eq!(tokens[1].line, 2);
         assert_eq!(tokens[2].line, 3);
     }
+
+    #[test]
+    fn test_column_tracking() {
+        let mut scanner = Scanner::new("var x;\n  print x;".to_string());
+        let tokens = scanner.scan_tokens();
+
+        assert_eq!(tokens[0].column, 1);
+        assert_eq!(tokens[1].column, 5);
+        assert_eq!(tokens[2].column, 6);
+        assert_eq!(tokens[3].column, 3);
+        assert_eq!(tokens[4].column, 9);
+    }
+
+    #[test]
+    fn test_unexpected_character_error() {
+        let mut scanner = Scanner::new("var x = 1;\nx @ 2;".to_string());
+        scanner.scan_tokens();
+
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::UNEXPECTED_CHARACTER);
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].column, 3);
+        assert_eq!(errors[0].end_column, 4);
+    }
+
+    #[test]
+    fn test_unterminated_string_error() {
+        let mut scanner = Scanner::new("print \"oops\nmore".to_string());
+        let tokens = scanner.scan_tokens();
+
+        assert_eq!(tokens.len(), 2); // print + EOF
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, codes::UNTERMINATED_STRING);
+        assert_eq!((errors[0].line, errors[0].column), (1, 7));
+        assert_eq!((errors[0].end_line, errors[0].end_column), (2, 5));
+    }
+
+    #[test]
+    fn test_non_ascii_identifier() {
+        let mut scanner = Scanner::new("var café = 1;".to_string());
+        let tokens = scanner.scan_tokens();
+
+        assert_eq!(tokens[1].lexeme, "café");
+        assert_eq!(tokens[2].column, 10);
+        assert!(scanner.errors().is_empty());
+    }
 }
@@ -1,3 +1,5 @@
+use crate::json;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     // Single-character tokens
@@ -49,12 +51,70 @@ pub enum TokenType {
     Eof,
 }
 
+impl TokenType {
+    /// Stable, upper snake case name used by the token dump formats.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenType::LeftParen => "LEFT_PAREN",
+            TokenType::RightParen => "RIGHT_PAREN",
+            TokenType::LeftBrace => "LEFT_BRACE",
+            TokenType::RightBrace => "RIGHT_BRACE",
+            TokenType::Comma => "COMMA",
+            TokenType::Dot => "DOT",
+            TokenType::Minus => "MINUS",
+            TokenType::Plus => "PLUS",
+            TokenType::Semicolon => "SEMICOLON",
+            TokenType::Slash => "SLASH",
+            TokenType::Star => "STAR",
+            TokenType::Bang => "BANG",
+            TokenType::BangEqual => "BANG_EQUAL",
+            TokenType::Equal => "EQUAL",
+            TokenType::EqualEqual => "EQUAL_EQUAL",
+            TokenType::Greater => "GREATER",
+            TokenType::GreaterEqual => "GREATER_EQUAL",
+            TokenType::Less => "LESS",
+            TokenType::LessEqual => "LESS_EQUAL",
+            TokenType::Identifier => "IDENTIFIER",
+            TokenType::String => "STRING",
+            TokenType::Number => "NUMBER",
+            TokenType::And => "AND",
+            TokenType::Class => "CLASS",
+            TokenType::Else => "ELSE",
+            TokenType::False => "FALSE",
+            TokenType::Fun => "FUN",
+            TokenType::For => "FOR",
+            TokenType::If => "IF",
+            TokenType::Nil => "NIL",
+            TokenType::Or => "OR",
+            TokenType::Print => "PRINT",
+            TokenType::Return => "RETURN",
+            TokenType::Super => "SUPER",
+            TokenType::This => "THIS",
+            TokenType::True => "TRUE",
+            TokenType::Var => "VAR",
+            TokenType::While => "WHILE",
+            TokenType::Eof => "EOF",
+        }
+    }
+}
+
+/// Output formats for `rlox tokens`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: Option<String>,
     pub line: usize,
+    /// 1-based column of the first character, or 0 when unknown.
+    pub column: usize,
+    /// Byte offset of the lexeme in the scanned source.
+    pub offset: usize,
 }
 
 impl Token {
@@ -64,8 +124,70 @@ impl Token {
             lexeme,
             literal,
             line,
+            column: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn with_column(mut self, column: usize) -> Self {
+        self.column = column;
+        self
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Renders the literal as a JSON value: strings are quoted, numbers are
+    /// written as the value they parse to and tokens without a literal are
+    /// `null`.
+    fn literal_json(&self) -> String {
+        match (&self.token_type, &self.literal) {
+            (_, None) => "null".to_string(),
+            (TokenType::Number, Some(number)) => match number.parse() {
+                Ok(number) => json::number(number),
+                Err(_) => json::quote(number),
+            },
+            (_, Some(text)) => json::quote(text),
         }
     }
+
+    /// One line of the stable text dump:
+    /// `TYPE<TAB>lexeme<TAB>literal<TAB>line:column`.
+    ///
+    /// The lexeme has backslashes, tabs and line breaks escaped so that each
+    /// token stays on one line; the literal uses the JSON value syntax.
+    pub fn dump(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}:{}",
+            self.token_type.name(),
+            escape_lexeme(&self.lexeme),
+            self.literal_json(),
+            self.line,
+            self.column
+        )
+    }
+
+    /// The token as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"type\":{},\"lexeme\":{},\"literal\":{},\"line\":{},\"column\":{}}}",
+            json::quote(self.token_type.name()),
+            json::quote(&self.lexeme),
+            self.literal_json(),
+            self.line,
+            self.column
+        )
+    }
+}
+
+fn escape_lexeme(lexeme: &str) -> String {
+    lexeme
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
 }
 
 #[cfg(test)]
@@ -115,6 +237,63 @@ mod tests {
         assert_eq!(original, cloned);
     }
 
+    #[test]
+    fn test_token_dump() {
+        let token = Token::new(TokenType::Var, "var".to_string(), None, 1).with_column(1);
+        assert_eq!(token.dump(), "VAR\tvar\tnull\t1:1");
+
+        let token = Token::new(
+            TokenType::Number,
+            "4.5".to_string(),
+            Some("4.5".to_string()),
+            2,
+        )
+        .with_column(9);
+        assert_eq!(token.dump(), "NUMBER\t4.5\t4.5\t2:9");
+    }
+
+    #[test]
+    fn test_token_dump_escapes_multiline_strings() {
+        let token = Token::new(
+            TokenType::String,
+            "\"a\nb\"".to_string(),
+            Some("a\nb".to_string()),
+            1,
+        )
+        .with_column(3);
+        assert_eq!(token.dump(), "STRING\t\"a\\nb\"\t\"a\\nb\"\t1:3");
+    }
+
+    #[test]
+    fn test_token_to_json() {
+        let token = Token::new(
+            TokenType::String,
+            "\"hi\"".to_string(),
+            Some("hi".to_string()),
+            3,
+        )
+        .with_column(7);
+        assert_eq!(
+            token.to_json(),
+            "{\"type\":\"STRING\",\"lexeme\":\"\\\"hi\\\"\",\"literal\":\"hi\",\"line\":3,\"column\":7}"
+        );
+    }
+
+    #[test]
+    fn test_number_literals_are_valid_json() {
+        let token = Token::new(
+            TokenType::Number,
+            "007".to_string(),
+            Some("007".to_string()),
+            1,
+        )
+        .with_column(9);
+        assert_eq!(
+            token.to_json(),
+            "{\"type\":\"NUMBER\",\"lexeme\":\"007\",\"literal\":7,\"line\":1,\"column\":9}"
+        );
+    }
+
     #[test]
     fn test_token_type_equality() {
         assert_eq!(TokenType::Plus, TokenType::Plus);
@@ -0,0 +1,1035 @@
+use crate::ast::{Expr, ExprKind, Function, LiteralValue, Span, Stmt, StmtKind};
+use crate::diagnostic::codes;
+use crate::environment::Environment;
+use crate::memory::{self, Meter};
+use crate::runtime::{RuntimeError, Stdout, TraceFrame, DEFAULT_MAX_DEPTH};
+use crate::sandbox::{self, Capabilities, Capability, NativeError};
+use crate::token::{Token, TokenType};
+use crate::value::{LoxClass, LoxFunction, LoxInstance, NativeFunction, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+// A call in progress.
+struct Call {
+    function: String,
+    file: Option<Rc<str>>,
+    // The line of the call in the function that made it.
+    called_from: usize,
+}
+
+// Non-local exits out of statement execution.
+enum Unwind {
+    Error(RuntimeError),
+    Return(Value),
+    TailCall(Box<TailCall>),
+}
+
+// A call whose value is returned, made by `call_function` once the calling
+// function has returned, so tail calls do not nest.
+struct TailCall {
+    callee: Value,
+    arguments: Vec<Value>,
+    paren: Token,
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(error: RuntimeError) -> Self {
+        Unwind::Error(error)
+    }
+}
+
+type ExecResult = Result<(), Unwind>;
+type EvalResult = Result<Value, RuntimeError>;
+type ExecValue = Result<Value, Unwind>;
+
+/// Executes resolved syntax trees directly.
+///
+/// Global variables live for as long as the tree walker does, so running
+/// several programs one after the other shares their definitions.
+pub struct TreeWalker {
+    globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    locals: HashMap<usize, usize>,
+    calls: Vec<Call>,
+    max_depth: usize,
+    // How many steps each run may take, and how many the current one has.
+    step_limit: Option<u64>,
+    steps: u64,
+    // How many bytes objects may hold, an estimate of what they hold now
+    // (what was reachable when last measured, plus what has been allocated
+    // since) and the estimate at which to measure again.
+    memory_limit: Option<usize>,
+    allocated: usize,
+    next_measurement: usize,
+    // The scopes of the blocks being run, outside the innermost one.
+    scopes: Vec<Rc<RefCell<Environment>>>,
+    // The capabilities whose natives are defined.
+    capabilities: Capabilities,
+    // The file of the code being run, given to the functions it declares.
+    file: Option<Rc<str>>,
+    // The call stack when the last runtime error was raised.
+    trace: Vec<TraceFrame>,
+    // The status the last run passed to `exit`, if it called it.
+    exit_status: Option<i32>,
+    // The paths of the modules imported so far.
+    imported: HashSet<String>,
+    out: Box<dyn Write>,
+}
+
+impl fmt::Debug for TreeWalker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TreeWalker")
+            .field("globals", &self.globals)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for TreeWalker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeWalker {
+    pub fn new() -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        let mut tree_walker = TreeWalker {
+            environment: globals.clone(),
+            globals,
+            locals: HashMap::new(),
+            calls: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            step_limit: None,
+            steps: 0,
+            memory_limit: None,
+            allocated: 0,
+            next_measurement: 0,
+            scopes: Vec::new(),
+            capabilities: Capabilities::none(),
+            file: None,
+            trace: Vec::new(),
+            exit_status: None,
+            imported: HashSet::new(),
+            out: Box::new(Stdout),
+        };
+        tree_walker.define_natives();
+        tree_walker
+    }
+
+    /// Forgets every global definition, resolved local and imported
+    /// module, leaving only the native functions. Output still goes to
+    /// the same writer.
+    pub fn reset(&mut self) {
+        let out = std::mem::replace(&mut self.out, Box::new(Stdout));
+        let max_depth = self.max_depth;
+        let step_limit = self.step_limit;
+        let memory_limit = self.memory_limit;
+        let capabilities = self.capabilities;
+        *self = TreeWalker::new();
+        self.out = out;
+        self.max_depth = max_depth;
+        self.step_limit = step_limit;
+        self.memory_limit = memory_limit;
+        self.set_capabilities(capabilities);
+    }
+
+    /// The global variables, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.globals.borrow().entries()
+    }
+
+    /// The names of the global variables, sorted, each with the fields and
+    /// methods of the instance it holds, for tab completion.
+    pub fn global_members(&self) -> Vec<(String, Vec<String>)> {
+        self.globals()
+            .into_iter()
+            .map(|(name, value)| {
+                let members = match value {
+                    Value::Instance(instance) => members(&instance.borrow()),
+                    _ => Vec::new(),
+                };
+                (name, members)
+            })
+            .collect()
+    }
+
+    /// Redirects the output of `print` statements.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Sets how deep calls may nest before a "Stack overflow." error.
+    /// Each level also takes Rust stack, some kilobytes of it in release
+    /// builds and tens of kilobytes in debug builds, so deep limits need a
+    /// thread with a large stack.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Limits how many steps each later call to `interpret` may take
+    /// before a "Step limit exceeded." error, or lifts the limit with
+    /// `None`. Every statement executed and every function called is a
+    /// step.
+    pub fn set_step_limit(&mut self, step_limit: Option<u64>) {
+        self.step_limit = step_limit;
+    }
+
+    /// Limits how many bytes the objects of scripts may hold before an
+    /// "Out of memory." error, or lifts the limit with `None`. The sizes
+    /// are estimates, and memory is only measured once allocations pass
+    /// `memory::next_measurement`.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.memory_limit = memory_limit;
+        self.next_measurement = 0;
+    }
+
+    /// Defines the natives of the capabilities granted, and only those.
+    /// Globals that scripts have defined are left alone, even where they
+    /// share a native's name. No capabilities are granted until this is
+    /// called.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.define_natives();
+    }
+
+    fn define_natives(&mut self) {
+        let mut globals = self.globals.borrow_mut();
+        for &(capability, name, arity, function) in NATIVES {
+            let is_native = match globals.get(name) {
+                Some(Value::Native(native)) => native.name == name,
+                Some(_) => false,
+                None => true,
+            };
+            if !is_native {
+                continue;
+            }
+            if self.capabilities.allows(capability) {
+                let native = NativeFunction {
+                    name: name.to_string(),
+                    arity,
+                    function,
+                };
+                globals.define(name.to_string(), Value::Native(Rc::new(native)));
+            } else {
+                globals.remove(name);
+            }
+        }
+    }
+
+    /// Sets the file the code run next comes from, for stack traces.
+    pub fn set_file(&mut self, file: Option<Rc<str>>) {
+        self.file = file;
+    }
+
+    /// The call stack when the last runtime error was raised, innermost
+    /// frame first.
+    pub fn stack_trace(&self) -> &[TraceFrame] {
+        &self.trace
+    }
+
+    /// The status the last run passed to `exit`, or `None` if it ran to
+    /// the end or stopped with an error.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Records scope distances computed by the resolver.
+    pub fn resolve(&mut self, locals: HashMap<usize, usize>) {
+        self.locals.extend(locals);
+    }
+
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        self.run_statements(statements, false)
+    }
+
+    /// Like `interpret`, but also prints the value of every top-level
+    /// expression statement, the way the REPL shows results.
+    pub fn interpret_repl(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        self.run_statements(statements, true)
+    }
+
+    fn run_statements(&mut self, statements: &[Stmt], echo: bool) -> Result<(), RuntimeError> {
+        let mut result = Ok(());
+        self.trace.clear();
+        self.steps = 0;
+        self.exit_status = None;
+        for statement in statements {
+            let executed = match &statement.kind {
+                StmtKind::Expression { expression } if echo => self
+                    .evaluate(expression)
+                    .map_err(Unwind::Error)
+                    .map(|value| {
+                        writeln!(self.out, "{}", value).expect("Unable to write output");
+                    }),
+                _ => self.execute(statement),
+            };
+            if let Err(Unwind::Error(error)) = executed {
+                // An error can leave us inside a nested scope, and leaves
+                // the calls it was raised in for the stack trace. Calling
+                // `exit` unwinds the same way, but is not an error.
+                self.environment = self.globals.clone();
+                if self.exit_status.is_none() {
+                    self.trace = self.trace_at(error.token.line);
+                    result = Err(error);
+                }
+                self.calls.clear();
+                break;
+            }
+        }
+        self.out.flush().ok();
+        result
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> ExecResult {
+        self.step(stmt.span.line, stmt.span.column)?;
+        match &stmt.kind {
+            StmtKind::Block { statements } => self.execute_scope(statements, stmt.span),
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => self.declare_class(name, superclass.as_ref(), methods),
+            StmtKind::Expression { expression } => {
+                self.evaluate(expression)?;
+                Ok(())
+            }
+            StmtKind::Function { function } => self.declare_function(function),
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.execute(then_branch)
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)
+                } else {
+                    Ok(())
+                }
+            }
+            StmtKind::Print { expression } => {
+                let value = self.evaluate(expression)?;
+                writeln!(self.out, "{}", value).expect("Unable to write output");
+                Ok(())
+            }
+            StmtKind::Return { value, .. } => match value {
+                Some(value) => Err(self.return_value(value)?),
+                None => Err(Unwind::Return(Value::Nil)),
+            },
+            StmtKind::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::Nil,
+                };
+                self.define_variable(name, value)
+            }
+            StmtKind::While { condition, body } => {
+                while self.evaluate(condition)?.is_truthy() {
+                    self.execute(body)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Runs a block statement in a new scope.
+    fn execute_scope(&mut self, statements: &[Stmt], span: Span) -> ExecResult {
+        self.allocate(memory::environment_size(), span.line, span.column)?;
+        let environment = Environment::with_enclosing(self.environment.clone());
+        self.execute_block(statements, Rc::new(RefCell::new(environment)))
+    }
+
+    fn define_variable(&mut self, name: &Token, value: Value) -> ExecResult {
+        self.allocate(memory::variable_size(&name.lexeme), name.line, name.column)?;
+        self.environment
+            .borrow_mut()
+            .define(name.lexeme.clone(), value);
+        Ok(())
+    }
+
+    fn declare_function(&mut self, function: &Rc<Function>) -> ExecResult {
+        let name = &function.name;
+        self.allocate(memory::function_size(), name.line, name.column)?;
+        let value = Value::Function(Rc::new(LoxFunction {
+            declaration: function.clone(),
+            closure: self.environment.clone(),
+            is_initializer: false,
+            file: self.file.clone(),
+        }));
+        self.environment
+            .borrow_mut()
+            .define(function.name.lexeme.clone(), value);
+        Ok(())
+    }
+
+    fn declare_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &[Rc<Function>],
+    ) -> ExecResult {
+        let superclass = match superclass {
+            Some(expr) => match self.evaluate(expr)? {
+                Value::Class(class) => Some(class),
+                _ => {
+                    let token = match &expr.kind {
+                        ExprKind::Variable { name } => name,
+                        _ => name,
+                    };
+                    return Err(RuntimeError::new(
+                        codes::INVALID_SUPERCLASS,
+                        token,
+                        "Superclass must be a class.".to_string(),
+                    )
+                    .into());
+                }
+            },
+            None => None,
+        };
+        let size = memory::class_size(&name.lexeme, methods.len())
+            + methods.len() * memory::function_size();
+        self.allocate(size, name.line, name.column)?;
+
+        self.environment
+            .borrow_mut()
+            .define(name.lexeme.clone(), Value::Nil);
+
+        let enclosing = self.environment.clone();
+        if let Some(superclass) = &superclass {
+            let mut environment = Environment::with_enclosing(self.environment.clone());
+            environment.define("super".to_string(), Value::Class(superclass.clone()));
+            self.environment = Rc::new(RefCell::new(environment));
+        }
+
+        let mut class_methods = HashMap::new();
+        for method in methods {
+            let function = LoxFunction {
+                declaration: method.clone(),
+                closure: self.environment.clone(),
+                is_initializer: method.name.lexeme == "init",
+                file: self.file.clone(),
+            };
+            class_methods.insert(method.name.lexeme.clone(), Rc::new(function));
+        }
+        let class = LoxClass {
+            name: name.lexeme.clone(),
+            superclass,
+            methods: class_methods,
+        };
+
+        self.environment = enclosing;
+        self.environment
+            .borrow_mut()
+            .assign(&name.lexeme, Value::Class(Rc::new(class)));
+        Ok(())
+    }
+
+    fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+    ) -> ExecResult {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        self.scopes.push(previous);
+        let mut result = Ok(());
+        for statement in statements {
+            result = self.execute(statement);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.environment = self.scopes.pop().expect("No scope to return to");
+        result
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> EvalResult {
+        match &expr.kind {
+            ExprKind::Assign { name, value } => {
+                let value = self.evaluate(value)?;
+                match self.locals.get(&expr.id) {
+                    Some(&distance) => Environment::assign_at(
+                        &self.environment,
+                        distance,
+                        &name.lexeme,
+                        value.clone(),
+                    ),
+                    None => {
+                        if !self
+                            .globals
+                            .borrow_mut()
+                            .assign(&name.lexeme, value.clone())
+                        {
+                            return Err(undefined_variable(name));
+                        }
+                    }
+                }
+                Ok(value)
+            }
+            ExprKind::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                self.binary(operator, left, right)
+            }
+            ExprKind::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let callee = self.evaluate(callee)?;
+                let arguments = self.arguments(arguments)?;
+                self.call(callee, arguments, paren)
+            }
+            ExprKind::Get { object, name } => match self.evaluate(object)? {
+                Value::Instance(instance) => get_property(&instance, name),
+                _ => Err(RuntimeError::new(
+                    codes::TYPE_ERROR,
+                    name,
+                    "Only instances have properties.".to_string(),
+                )),
+            },
+            ExprKind::Grouping { expression } => self.evaluate(expression),
+            ExprKind::Literal { value } => self.literal(value, expr.span),
+            ExprKind::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                if short_circuits(operator, &left) {
+                    Ok(left)
+                } else {
+                    self.evaluate(right)
+                }
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                let instance = match self.evaluate(object)? {
+                    Value::Instance(instance) => instance,
+                    _ => {
+                        return Err(RuntimeError::new(
+                            codes::TYPE_ERROR,
+                            name,
+                            "Only instances have fields.".to_string(),
+                        ))
+                    }
+                };
+                let value = self.evaluate(value)?;
+                self.set_field(&instance, name, value)
+            }
+            ExprKind::Super { method, .. } => {
+                let distance = self.locals[&expr.id];
+                let superclass = match Environment::get_at(&self.environment, distance, "super") {
+                    Some(Value::Class(class)) => class,
+                    _ => unreachable!("'super' is always bound to a class"),
+                };
+                // `this` is always bound in the scope just inside `super`.
+                let instance = match Environment::get_at(&self.environment, distance - 1, "this") {
+                    Some(Value::Instance(instance)) => instance,
+                    _ => unreachable!("'this' is always bound to an instance"),
+                };
+                match superclass.find_method(&method.lexeme) {
+                    Some(found) => Ok(Value::Function(Rc::new(found.bind(instance)))),
+                    None => Err(RuntimeError::new(
+                        codes::UNDEFINED_PROPERTY,
+                        method,
+                        format!("Undefined property '{}'.", method.lexeme),
+                    )),
+                }
+            }
+            ExprKind::This { keyword } => self.look_up_variable(keyword, expr.id),
+            ExprKind::Unary { operator, right } => {
+                let right = self.evaluate(right)?;
+                match operator.token_type {
+                    TokenType::Bang => Ok(Value::Bool(!right.is_truthy())),
+                    TokenType::Minus => match right {
+                        Value::Number(n) => Ok(Value::Number(-n)),
+                        _ => Err(RuntimeError::new(
+                            codes::TYPE_ERROR,
+                            operator,
+                            "Operand must be a number.".to_string(),
+                        )),
+                    },
+                    _ => unreachable!("Parser only produces '!' and '-' unary operators"),
+                }
+            }
+            ExprKind::Variable { name } => self.look_up_variable(name, expr.id),
+        }
+    }
+
+    // Evaluates the value of a `return`, leaving a call that would produce
+    // it for `call_function` to make.
+    fn return_value(&mut self, expr: &Expr) -> Result<Unwind, RuntimeError> {
+        match &expr.kind {
+            ExprKind::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let callee = self.evaluate(callee)?;
+                let arguments = self.arguments(arguments)?;
+                Ok(Unwind::TailCall(Box::new(TailCall {
+                    callee,
+                    arguments,
+                    paren: paren.clone(),
+                })))
+            }
+            ExprKind::Grouping { expression } => self.return_value(expression),
+            ExprKind::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                if short_circuits(operator, &left) {
+                    Ok(Unwind::Return(left))
+                } else {
+                    self.return_value(right)
+                }
+            }
+            _ => Ok(Unwind::Return(self.evaluate(expr)?)),
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Expr]) -> Result<Vec<Value>, RuntimeError> {
+        let mut values = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            values.push(self.evaluate(argument)?);
+        }
+        Ok(values)
+    }
+
+    fn look_up_variable(&self, name: &Token, id: usize) -> EvalResult {
+        let value = match self.locals.get(&id) {
+            Some(&distance) => Environment::get_at(&self.environment, distance, &name.lexeme),
+            None => self.globals.borrow().get(&name.lexeme),
+        };
+        value.ok_or_else(|| undefined_variable(name))
+    }
+
+    fn call(&mut self, callee: Value, arguments: Vec<Value>, paren: &Token) -> EvalResult {
+        match callee {
+            Value::Function(function) => {
+                check_arity(function.arity(), arguments.len(), paren)?;
+                self.call_function(&function, arguments, paren)
+            }
+            Value::Native(native) => {
+                check_arity(native.arity, arguments.len(), paren)?;
+                match (native.function)(&arguments) {
+                    Ok(value) => Ok(value),
+                    Err(NativeError::Message(message)) => {
+                        Err(RuntimeError::new(codes::TYPE_ERROR, paren, message))
+                    }
+                    Err(NativeError::Exit(status)) => {
+                        self.exit_status = Some(status);
+                        Err(RuntimeError::exit())
+                    }
+                    Err(NativeError::Import(path)) => self.import(path, paren),
+                }
+            }
+            Value::Class(class) => self.instantiate(&class, arguments, paren),
+            _ => Err(RuntimeError::new(
+                codes::NOT_CALLABLE,
+                paren,
+                "Can only call functions and classes.".to_string(),
+            )),
+        }
+    }
+
+    // Runs the module at `path` in the global scope, unless it has run
+    // already. Functions it declares belong to its file.
+    fn import(&mut self, path: String, paren: &Token) -> EvalResult {
+        if self.imported.contains(&path) {
+            return Ok(Value::Nil);
+        }
+        let (statements, locals) = sandbox::load_module(&path)
+            .map_err(|message| RuntimeError::new(codes::IMPORT_FAILED, paren, message))?;
+        self.locals.extend(locals);
+        let file = self.file.replace(path.as_str().into());
+        self.imported.insert(path);
+        let result = self.execute_block(&statements, self.globals.clone());
+        self.file = file;
+        match result {
+            Err(Unwind::Error(error)) => Err(error),
+            _ => Ok(Value::Nil),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &LoxFunction,
+        arguments: Vec<Value>,
+        paren: &Token,
+    ) -> EvalResult {
+        self.enter_call(function, paren)?;
+        let result = self.run_tail_calls(function, arguments);
+        if result.is_ok() {
+            self.calls.pop();
+        }
+        result
+    }
+
+    // Checks the depth, step and memory limits before a call to `function`
+    // and records it for stack traces.
+    fn enter_call(&mut self, function: &LoxFunction, paren: &Token) -> Result<(), RuntimeError> {
+        self.step(paren.line, paren.column)?;
+        if self.calls.len() >= self.max_depth {
+            return Err(RuntimeError::new(
+                codes::STACK_OVERFLOW,
+                paren,
+                "Stack overflow.".to_string(),
+            ));
+        }
+        self.allocate(call_size(function), paren.line, paren.column)?;
+        self.calls.push(Call {
+            function: function.declaration.name.lexeme.clone(),
+            file: function.file.clone(),
+            called_from: paren.line,
+        });
+        Ok(())
+    }
+
+    // Hands the current call over to `function`, called in tail position,
+    // which then returns to the same place.
+    fn reuse_call(
+        &mut self,
+        function: &LoxFunction,
+        arguments: usize,
+        paren: &Token,
+    ) -> Result<(), RuntimeError> {
+        check_arity(function.arity(), arguments, paren)?;
+        self.allocate(call_size(function), paren.line, paren.column)?;
+        let call = self.calls.last_mut().expect("No call in progress");
+        call.function = function.declaration.name.lexeme.clone();
+        call.file = function.file.clone();
+        Ok(())
+    }
+
+    fn instantiate(
+        &mut self,
+        class: &Rc<LoxClass>,
+        arguments: Vec<Value>,
+        paren: &Token,
+    ) -> EvalResult {
+        check_arity(class.arity(), arguments.len(), paren)?;
+        self.allocate(memory::instance_size(), paren.line, paren.column)?;
+        let instance = Rc::new(RefCell::new(LoxInstance::new(class.clone())));
+        if let Some(initializer) = class.find_method("init") {
+            self.call_function(&initializer.bind(instance.clone()), arguments, paren)?;
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    fn set_field(
+        &mut self,
+        instance: &Rc<RefCell<LoxInstance>>,
+        name: &Token,
+        value: Value,
+    ) -> EvalResult {
+        self.allocate(memory::variable_size(&name.lexeme), name.line, name.column)?;
+        instance
+            .borrow_mut()
+            .fields
+            .insert(name.lexeme.clone(), value.clone());
+        Ok(value)
+    }
+
+    fn literal(&mut self, value: &LiteralValue, span: Span) -> EvalResult {
+        Ok(match value {
+            LiteralValue::Nil => Value::Nil,
+            LiteralValue::Bool(b) => Value::Bool(*b),
+            LiteralValue::Number(n) => Value::Number(*n),
+            LiteralValue::String(s) => {
+                self.allocate(memory::string_size(s.len()), span.line, span.column)?;
+                Value::String(s.as_str().into())
+            }
+        })
+    }
+
+    // Applies a binary operator, counting the string a concatenation makes.
+    fn binary(&mut self, operator: &Token, left: Value, right: Value) -> EvalResult {
+        if let (Value::String(a), Value::String(b)) = (&left, &right) {
+            let size = memory::string_size(a.len() + b.len());
+            self.allocate(size, operator.line, operator.column)?;
+        }
+        binary(operator, left, right)
+    }
+
+    // Runs `function` and then the calls in tail position it leaves, one
+    // after the other instead of inside the functions making them.
+    fn run_tail_calls(&mut self, function: &LoxFunction, arguments: Vec<Value>) -> EvalResult {
+        let mut result = self.run_function(function, arguments);
+        loop {
+            match result {
+                Ok(value) | Err(Unwind::Return(value)) => return Ok(value),
+                Err(Unwind::Error(error)) => return Err(error),
+                Err(Unwind::TailCall(call)) => {
+                    let TailCall {
+                        callee,
+                        arguments,
+                        paren,
+                    } = *call;
+                    match callee {
+                        Value::Function(function) => {
+                            self.reuse_call(&function, arguments.len(), &paren)?;
+                            result = self.run_function(&function, arguments);
+                        }
+                        callee => return self.call(callee, arguments, &paren),
+                    }
+                }
+            }
+        }
+    }
+
+    // Counts a step against the limit, raising an error at `line` and
+    // `column` once it has been used up.
+    fn step(&mut self, line: usize, column: usize) -> Result<(), RuntimeError> {
+        if let Some(limit) = self.step_limit {
+            if self.steps == limit {
+                let token =
+                    Token::new(TokenType::Eof, String::new(), None, line).with_column(column);
+                return Err(RuntimeError::new(
+                    codes::STEP_LIMIT,
+                    &token,
+                    "Step limit exceeded.".to_string(),
+                ));
+            }
+            self.steps += 1;
+        }
+        Ok(())
+    }
+
+    // Counts `bytes` about to be allocated against the memory limit,
+    // raising an error at `line` and `column` if they do not fit.
+    fn allocate(&mut self, bytes: usize, line: usize, column: usize) -> Result<(), RuntimeError> {
+        if let Some(limit) = self.memory_limit {
+            self.allocated += bytes;
+            if self.allocated > self.next_measurement {
+                // Much of what was allocated may be gone by now.
+                self.allocated = self.reachable_bytes() + bytes;
+                self.next_measurement = memory::next_measurement(self.allocated, limit);
+                if self.allocated > limit {
+                    let token =
+                        Token::new(TokenType::Eof, String::new(), None, line).with_column(column);
+                    return Err(RuntimeError::new(
+                        codes::OUT_OF_MEMORY,
+                        &token,
+                        "Out of memory.".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The bytes held by everything a script can still reach.
+    fn reachable_bytes(&self) -> usize {
+        let mut meter = Meter::new();
+        meter.environment(&self.globals);
+        meter.environment(&self.environment);
+        for scope in &self.scopes {
+            meter.environment(scope);
+        }
+        meter.bytes()
+    }
+
+    // The calls in progress, innermost first, for an error raised at `line`.
+    fn trace_at(&self, line: usize) -> Vec<TraceFrame> {
+        let mut trace = Vec::with_capacity(self.calls.len() + 1);
+        let mut line = line;
+        for call in self.calls.iter().rev() {
+            trace.push(TraceFrame {
+                function: call.function.clone(),
+                file: call.file.clone(),
+                line,
+            });
+            line = call.called_from;
+        }
+        trace.push(TraceFrame {
+            function: String::new(),
+            file: self.file.clone(),
+            line,
+        });
+        trace
+    }
+
+    fn run_function(&mut self, function: &LoxFunction, arguments: Vec<Value>) -> ExecValue {
+        let mut environment = Environment::with_enclosing(function.closure.clone());
+        for (param, argument) in function.declaration.params.iter().zip(arguments) {
+            environment.define(param.lexeme.clone(), argument);
+        }
+
+        let result = self.execute_block(
+            &function.declaration.body,
+            Rc::new(RefCell::new(environment)),
+        );
+        let value = match result {
+            Ok(()) => Value::Nil,
+            Err(Unwind::Return(value)) => value,
+            Err(unwind) => return Err(unwind),
+        };
+
+        if function.is_initializer {
+            return Ok(Environment::get_at(&function.closure, 0, "this").unwrap_or(Value::Nil));
+        }
+        Ok(value)
+    }
+}
+
+// The scope a call to `function` allocates for its parameters.
+fn call_size(function: &LoxFunction) -> usize {
+    let params = &function.declaration.params;
+    memory::environment_size()
+        + params
+            .iter()
+            .map(|param| memory::variable_size(&param.lexeme))
+            .sum::<usize>()
+}
+
+fn binary(operator: &Token, left: Value, right: Value) -> EvalResult {
+    let numbers = |left: &Value, right: &Value| match (left, right) {
+        (Value::Number(a), Value::Number(b)) => Ok((*a, *b)),
+        _ => Err(RuntimeError::new(
+            codes::TYPE_ERROR,
+            operator,
+            "Operands must be numbers.".to_string(),
+        )),
+    };
+
+    match operator.token_type {
+        TokenType::Plus => match (&left, &right) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b).into())),
+            _ => Err(RuntimeError::new(
+                codes::TYPE_ERROR,
+                operator,
+                "Operands must be two numbers or two strings.".to_string(),
+            )),
+        },
+        TokenType::Minus => numbers(&left, &right).map(|(a, b)| Value::Number(a - b)),
+        TokenType::Slash => numbers(&left, &right).map(|(a, b)| Value::Number(a / b)),
+        TokenType::Star => numbers(&left, &right).map(|(a, b)| Value::Number(a * b)),
+        TokenType::Greater => numbers(&left, &right).map(|(a, b)| Value::Bool(a > b)),
+        TokenType::GreaterEqual => numbers(&left, &right).map(|(a, b)| Value::Bool(a >= b)),
+        TokenType::Less => numbers(&left, &right).map(|(a, b)| Value::Bool(a < b)),
+        TokenType::LessEqual => numbers(&left, &right).map(|(a, b)| Value::Bool(a <= b)),
+        TokenType::EqualEqual => Ok(Value::Bool(left == right)),
+        TokenType::BangEqual => Ok(Value::Bool(left != right)),
+        _ => unreachable!("Parser only produces binary operators"),
+    }
+}
+
+fn get_property(instance: &Rc<RefCell<LoxInstance>>, name: &Token) -> EvalResult {
+    if let Some(value) = instance.borrow().fields.get(&name.lexeme) {
+        return Ok(value.clone());
+    }
+    let method = instance.borrow().class.find_method(&name.lexeme);
+    match method {
+        Some(method) => Ok(Value::Function(Rc::new(method.bind(instance.clone())))),
+        None => Err(RuntimeError::new(
+            codes::UNDEFINED_PROPERTY,
+            name,
+            format!("Undefined property '{}'.", name.lexeme),
+        )),
+    }
+}
+
+// Whether the left operand of `and` or `or` is the value of the whole
+// expression.
+fn short_circuits(operator: &Token, left: &Value) -> bool {
+    if operator.token_type == TokenType::Or {
+        left.is_truthy()
+    } else {
+        !left.is_truthy()
+    }
+}
+
+fn check_arity(expected: usize, got: usize, paren: &Token) -> Result<(), RuntimeError> {
+    if expected != got {
+        return Err(RuntimeError::new(
+            codes::ARITY_MISMATCH,
+            paren,
+            format!("Expected {} arguments but got {}.", expected, got),
+        ));
+    }
+    Ok(())
+}
+
+// Field names of `instance` and the methods of its class and superclasses.
+fn members(instance: &LoxInstance) -> Vec<String> {
+    let mut members: Vec<String> = instance.fields.keys().cloned().collect();
+    let mut class = Some(instance.class.clone());
+    while let Some(current) = class {
+        members.extend(current.methods.keys().cloned());
+        class = current.superclass.clone();
+    }
+    members
+}
+
+fn undefined_variable(name: &Token) -> RuntimeError {
+    RuntimeError::new(
+        codes::UNDEFINED_VARIABLE,
+        name,
+        format!("Undefined variable '{}'.", name.lexeme),
+    )
+}
+
+// The native functions, with the capability each needs.
+const NATIVES: &[(Capability, &str, usize, NativeFn)] = &[
+    (Capability::Clock, "clock", 0, clock),
+    (Capability::FileIo, "readFile", 1, read_file),
+    (Capability::FileIo, "writeFile", 2, write_file),
+    (Capability::EnvVars, "getenv", 1, getenv),
+    (Capability::ProcessExit, "exit", 1, exit),
+    (Capability::Modules, "import", 1, import),
+];
+
+type NativeFn = fn(&[Value]) -> Result<Value, NativeError>;
+
+fn clock(_arguments: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(sandbox::seconds_since_epoch()?))
+}
+
+fn read_file(arguments: &[Value]) -> Result<Value, NativeError> {
+    let contents = sandbox::read_file(string_argument(&arguments[0], "Path")?)?;
+    Ok(Value::String(contents.into()))
+}
+
+fn write_file(arguments: &[Value]) -> Result<Value, NativeError> {
+    let path = string_argument(&arguments[0], "Path")?;
+    sandbox::write_file(path, string_argument(&arguments[1], "Contents")?)?;
+    Ok(Value::Nil)
+}
+
+fn getenv(arguments: &[Value]) -> Result<Value, NativeError> {
+    let name = string_argument(&arguments[0], "Name")?;
+    Ok(sandbox::env_var(name).map_or(Value::Nil, |value| Value::String(value.into())))
+}
+
+fn exit(arguments: &[Value]) -> Result<Value, NativeError> {
+    match &arguments[0] {
+        Value::Number(code) => Err(sandbox::exit(*code)),
+        _ => Err("Exit code must be a number.".to_string().into()),
+    }
+}
+
+fn import(arguments: &[Value]) -> Result<Value, NativeError> {
+    let path = string_argument(&arguments[0], "Path")?;
+    Err(NativeError::Import(path.to_string()))
+}
+
+fn string_argument<'a>(value: &'a Value, what: &str) -> Result<&'a str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(format!("{} must be a string.", what)),
+    }
+}
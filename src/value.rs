@@ -0,0 +1,257 @@
+use crate::ast::Function;
+use crate::environment::Environment;
+use crate::sandbox::NativeError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// A runtime value of the tree-walking interpreter.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<LoxFunction>),
+    Native(Rc<NativeFunction>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<LoxInstance>>),
+}
+
+impl Value {
+    /// `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "<fn {}>", function.declaration.name.lexeme),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+        }
+    }
+}
+
+/// A user-defined function or method together with the environment it
+/// closes over.
+#[derive(Debug)]
+pub struct LoxFunction {
+    pub declaration: Rc<Function>,
+    pub closure: Rc<RefCell<Environment>>,
+    pub is_initializer: bool,
+    /// The file the function was declared in, if it came from one.
+    pub file: Option<Rc<str>>,
+}
+
+impl LoxFunction {
+    pub fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+
+    /// Returns a copy of this method whose closure binds `this` to
+    /// `instance`.
+    pub fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> LoxFunction {
+        let mut environment = Environment::with_enclosing(self.closure.clone());
+        environment.define("this".to_string(), Value::Instance(instance));
+        LoxFunction {
+            declaration: self.declaration.clone(),
+            closure: Rc::new(RefCell::new(environment)),
+            is_initializer: self.is_initializer,
+            file: self.file.clone(),
+        }
+    }
+}
+
+/// A function implemented in Rust and exposed to Lox scripts.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: fn(&[Value]) -> Result<Value, NativeError>,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxClass {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass>>,
+    pub methods: HashMap<String, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    /// Looks a method up on this class and then on its superclasses.
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self
+                .superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name)),
+        }
+    }
+
+    /// Classes take the same arguments as their `init` method.
+    pub fn arity(&self) -> usize {
+        self.find_method("init").map_or(0, |init| init.arity())
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        LoxInstance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl Drop for LoxInstance {
+    fn drop(&mut self) {
+        drop_links(self.fields.drain().map(|(_, value)| value), None);
+    }
+}
+
+// Something that may be all that keeps other values alive.
+enum Link {
+    Value(Value),
+    Environment(Rc<RefCell<Environment>>),
+}
+
+impl Link {
+    // Whether dropping this would drop what it refers to as well.
+    fn is_last(&self) -> bool {
+        match self {
+            Link::Value(Value::Function(function)) => Rc::strong_count(function) == 1,
+            Link::Value(Value::Class(class)) => Rc::strong_count(class) == 1,
+            Link::Value(Value::Instance(instance)) => Rc::strong_count(instance) == 1,
+            Link::Value(_) => false,
+            Link::Environment(environment) => Rc::strong_count(environment) == 1,
+        }
+    }
+}
+
+/// Drops `values` and `enclosing` along with everything only they keep
+/// alive.
+///
+/// Rust drops what a value owns recursively, so dropping a long chain, such
+/// as a linked list of instances, could overflow the stack. Instances and
+/// environments hand what they own to this instead, which takes chains
+/// apart one link at a time.
+pub(crate) fn drop_links(
+    values: impl Iterator<Item = Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+) {
+    let mut links: Vec<Link> = values
+        .map(Link::Value)
+        .chain(enclosing.map(Link::Environment))
+        .filter(Link::is_last)
+        .collect();
+    while let Some(link) = links.pop() {
+        match link {
+            Link::Value(Value::Instance(instance)) => {
+                if let Ok(instance) = Rc::try_unwrap(instance) {
+                    let mut instance = instance.into_inner();
+                    links.extend(instance.fields.drain().map(|(_, value)| Link::Value(value)));
+                    // The instance lets go of its class at the end of this
+                    // arm, before the class is looked at again.
+                    links.push(Link::Value(Value::Class(instance.class.clone())));
+                }
+            }
+            Link::Value(Value::Function(function)) => {
+                if let Ok(function) = Rc::try_unwrap(function) {
+                    links.push(Link::Environment(function.closure));
+                }
+            }
+            Link::Value(Value::Class(class)) => {
+                if let Ok(class) = Rc::try_unwrap(class) {
+                    links.extend(
+                        class
+                            .superclass
+                            .map(|class| Link::Value(Value::Class(class))),
+                    );
+                    links.extend(
+                        class
+                            .methods
+                            .into_values()
+                            .map(|method| Link::Value(Value::Function(method))),
+                    );
+                }
+            }
+            Link::Value(_) => {}
+            Link::Environment(environment) => {
+                if let Ok(environment) = Rc::try_unwrap(environment) {
+                    let (values, enclosing) = environment.into_inner().take_contents();
+                    links.extend(values.into_values().map(Link::Value));
+                    links.extend(enclosing.map(Link::Environment));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truthiness() {
+        assert!(!Value::Nil.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Number(0.0).is_truthy());
+        assert!(Value::String("".into()).is_truthy());
+    }
+
+    #[test]
+    fn test_equality() {
+        assert_eq!(Value::Nil, Value::Nil);
+        assert_eq!(Value::Number(1.0), Value::Number(1.0));
+        assert_eq!(Value::String("a".into()), Value::String("a".into()));
+        assert_ne!(Value::Number(0.0), Value::Bool(false));
+        assert_ne!(Value::Nil, Value::Bool(false));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+        assert_eq!(Value::String("hi".into()).to_string(), "hi");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Nil.to_string(), "nil");
+    }
+}
@@ -0,0 +1,309 @@
+use crate::token::Token;
+use crate::vm::object::ObjRef;
+use crate::vm::value::Value;
+use std::cell::Cell;
+
+/// The instructions of the bytecode VM. Operands follow the opcode byte:
+/// constant and name indices are two bytes, big-endian, as are jump
+/// offsets. Slot, upvalue and argument counts are one byte, and inline
+/// cache indices two.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum OpCode {
+    /// Pushes a constant. Operand: constant index.
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// Pops several values at once. Operand: how many.
+    PopN,
+    /// Operand: stack slot relative to the current frame.
+    GetLocal,
+    SetLocal,
+    /// Operand: constant index of the variable name.
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    /// Operand: index into the closure's upvalues.
+    GetUpvalue,
+    SetUpvalue,
+    /// Operands: constant index of the property name, inline cache index.
+    GetProperty,
+    SetProperty,
+    /// Pops the superclass and looks a method up on it, bound to the
+    /// receiver below. Operand: constant index of the method name.
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// Operand: forward offset.
+    Jump,
+    /// Jumps if the top of the stack is falsey, without popping it.
+    JumpIfFalse,
+    /// Operand: backward offset.
+    Loop,
+    /// Operand: argument count.
+    Call,
+    /// Calls a property of the receiver below the arguments without
+    /// binding it first, so the property is looked up only after the
+    /// arguments are evaluated. Operands: constant index of the method
+    /// name, argument count, inline cache index.
+    Invoke,
+    /// Pops the superclass and calls one of its methods on the receiver
+    /// below the arguments. Operands: constant index of the method name,
+    /// argument count.
+    SuperInvoke,
+    /// Operand: constant index of a function, followed by an
+    /// `(is_local, index)` byte pair for each of its upvalues.
+    Closure,
+    /// Moves the local on top of the stack into its upvalue and pops it.
+    CloseUpvalue,
+    Return,
+    /// Operand: constant index of the class name.
+    Class,
+    /// Copies the methods of the superclass below the class on top of the
+    /// stack into it, and pops the class.
+    Inherit,
+    /// Adds the closure on top of the stack to the class below it.
+    /// Operand: constant index of the method name.
+    Method,
+}
+
+const OPCODES: &[OpCode] = &[
+    OpCode::Constant,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Pop,
+    OpCode::PopN,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::GetGlobal,
+    OpCode::DefineGlobal,
+    OpCode::SetGlobal,
+    OpCode::GetUpvalue,
+    OpCode::SetUpvalue,
+    OpCode::GetProperty,
+    OpCode::SetProperty,
+    OpCode::GetSuper,
+    OpCode::Equal,
+    OpCode::Greater,
+    OpCode::GreaterEqual,
+    OpCode::Less,
+    OpCode::LessEqual,
+    OpCode::Add,
+    OpCode::Subtract,
+    OpCode::Multiply,
+    OpCode::Divide,
+    OpCode::Not,
+    OpCode::Negate,
+    OpCode::Print,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::Invoke,
+    OpCode::SuperInvoke,
+    OpCode::Closure,
+    OpCode::CloseUpvalue,
+    OpCode::Return,
+    OpCode::Class,
+    OpCode::Inherit,
+    OpCode::Method,
+];
+
+impl OpCode {
+    /// Decodes an opcode byte, or returns `None` if it is not one.
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    /// The number of operand bytes after the opcode. A closure is also
+    /// followed by two bytes for each upvalue of its function.
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::PopN
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => 1,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::Closure => 2,
+            OpCode::SuperInvoke => 3,
+            OpCode::GetProperty | OpCode::SetProperty => 4,
+            OpCode::Invoke => 5,
+            _ => 0,
+        }
+    }
+}
+
+/// What a property access found last time it ran, so the next access on
+/// an instance of the same class can skip the hash table lookups.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum InlineCache {
+    #[default]
+    Empty,
+    /// A field, in this slot of the instance.
+    Field { class: ObjRef, slot: usize },
+    /// A method of the class, valid while the class has `layout` field
+    /// slots: until then no instance can have a field shadowing it.
+    Method {
+        class: ObjRef,
+        method: ObjRef,
+        layout: usize,
+    },
+}
+
+/// A run of consecutive bytes compiled from the same source line.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(super) struct LineRun {
+    pub(super) line: usize,
+    pub(super) count: usize,
+}
+
+/// A sequence of bytecode together with its constant pool and the
+/// information needed to report errors against the source.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    /// One per property access site, filled in as the code runs.
+    pub caches: Vec<Cell<InlineCache>>,
+    // Run-length encoded source line of every byte in `code`.
+    pub(super) lines: Vec<LineRun>,
+    // For instructions that can fail at runtime, the token they were
+    // compiled from, keyed by the instruction's offset.
+    pub(super) tokens: Vec<(usize, Token)>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Chunk::default()
+    }
+
+    /// Appends a byte compiled from `line`.
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+        match self.lines.last_mut() {
+            Some(run) if run.line == line => run.count += 1,
+            _ => self.lines.push(LineRun { line, count: 1 }),
+        }
+    }
+
+    /// Appends a constant and returns its index.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    /// Adds an empty inline cache and returns its index.
+    pub fn add_cache(&mut self) -> usize {
+        self.caches.push(Cell::default());
+        self.caches.len() - 1
+    }
+
+    /// Records `token` as the source of the next instruction written, so
+    /// runtime errors raised by it can point at the token.
+    pub fn add_token(&mut self, token: &Token) {
+        self.tokens.push((self.code.len(), token.clone()));
+    }
+
+    /// The source line of the byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let mut end = 0;
+        for run in &self.lines {
+            end += run.count;
+            if offset < end {
+                return run.line;
+            }
+        }
+        self.lines.last().map_or(0, |run| run.line)
+    }
+
+    /// The token recorded for the instruction at `offset`, if any.
+    pub fn token_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens
+            .binary_search_by_key(&offset, |(start, _)| *start)
+            .ok()
+            .map(|index| &self.tokens[index].1)
+    }
+
+    /// Reads the two-byte operand at `offset`.
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::TokenType;
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for (byte, opcode) in OPCODES.iter().enumerate() {
+            assert_eq!(*opcode as u8, byte as u8);
+            assert_eq!(OpCode::from_byte(byte as u8), Some(*opcode));
+        }
+        assert_eq!(OpCode::from_byte(OPCODES.len() as u8), None);
+    }
+
+    #[test]
+    fn test_line_table() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Pop as u8, 3);
+        chunk.write(OpCode::Return as u8, 4);
+
+        assert_eq!(chunk.lines.len(), 3);
+        assert_eq!(
+            (0..4)
+                .map(|offset| chunk.line_at(offset))
+                .collect::<Vec<_>>(),
+            vec![1, 1, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_tokens_by_offset() {
+        let mut chunk = Chunk::new();
+        let token = Token::new(TokenType::Minus, "-".to_string(), None, 2);
+        chunk.write(OpCode::Nil as u8, 2);
+        chunk.add_token(&token);
+        chunk.write(OpCode::Negate as u8, 2);
+
+        assert_eq!(chunk.token_at(1), Some(&token));
+        assert_eq!(chunk.token_at(0), None);
+    }
+
+    #[test]
+    fn test_constants_and_operands() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Value::NIL), 1);
+        chunk.write(0x12, 1);
+        chunk.write(0x34, 1);
+        assert_eq!(chunk.read_u16(0), 0x1234);
+    }
+}
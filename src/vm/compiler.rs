@@ -0,0 +1,756 @@
+use crate::ast::{Expr, ExprKind, Function, LiteralValue, Span, Stmt, StmtKind};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::token::{Token, TokenType};
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::object::{Obj, ObjFunction, ObjRef};
+use crate::vm::optimizer;
+use crate::vm::value::Value;
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Limits imposed by the one-byte operands of the instruction set.
+pub const MAX_LOCALS: usize = 256;
+pub const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    is_captured: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+// Everything the compiler tracks for the function it is in the middle of.
+struct FunctionState {
+    kind: FunctionKind,
+    name: String,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Constant indices of the names used so far, so each is stored once.
+    identifiers: HashMap<String, u16>,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: String) -> Self {
+        // Slot zero holds the function being called, or `this` in methods.
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        FunctionState {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![Local {
+                name: receiver.to_string(),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            identifiers: HashMap::new(),
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        self.locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Compiles resolved syntax trees to bytecode for the VM in a single walk
+/// over the tree.
+///
+/// Programs reaching the compiler have already been through the resolver,
+/// so the only errors left to report are limits of the bytecode format.
+pub struct Compiler<'v> {
+    // Owns the heap, and knows to keep the compiler's objects alive.
+    vm: &'v mut Vm,
+    functions: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    errors: Vec<Diagnostic>,
+    // Source line of the code being compiled.
+    line: usize,
+    optimize: bool,
+}
+
+/// Compiles a program into a function object for the top-level script.
+/// With `echo`, top-level expression statements print their value, as the
+/// REPL does.
+pub fn compile(statements: &[Stmt], vm: &mut Vm, echo: bool) -> Result<ObjRef, Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(vm);
+    for statement in statements {
+        match &statement.kind {
+            StmtKind::Expression { expression } if echo => {
+                compiler.expression(expression);
+                compiler.emit_op(OpCode::Print);
+            }
+            _ => compiler.statement(statement),
+        }
+    }
+    let function = compiler.end_function();
+    if compiler.errors.is_empty() {
+        Ok(function)
+    } else {
+        Err(compiler.errors)
+    }
+}
+
+impl<'v> Compiler<'v> {
+    fn new(vm: &'v mut Vm) -> Self {
+        let optimize = vm.optimize;
+        Compiler {
+            vm,
+            functions: vec![FunctionState::new(FunctionKind::Script, String::new())],
+            classes: Vec::new(),
+            errors: Vec::new(),
+            line: 1,
+            optimize,
+        }
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("No function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().chunk
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        self.line = stmt.span.line;
+        match &stmt.kind {
+            StmtKind::Block { statements } => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope();
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            StmtKind::Expression { expression } => {
+                self.expression(expression);
+                self.emit_op(OpCode::Pop);
+            }
+            StmtKind::Function { function } => {
+                let global = self.declare_variable(&function.name);
+                // A local function is usable inside its own body.
+                self.function(function, FunctionKind::Function);
+                self.define_variable(global);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if let Some(value) = self.fold(condition) {
+                    if optimizer::is_truthy(&value) {
+                        self.statement(then_branch);
+                    } else if let Some(else_branch) = else_branch {
+                        self.statement(else_branch);
+                    }
+                    return;
+                }
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            StmtKind::Print { expression } => {
+                self.expression(expression);
+                self.emit_op(OpCode::Print);
+            }
+            StmtKind::Return { keyword, value } => {
+                self.line = keyword.line;
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.emit_implicit_return_value(),
+                }
+                self.emit_op(OpCode::Return);
+            }
+            StmtKind::Var { name, initializer } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                let global = self.declare_variable(name);
+                self.define_variable(global);
+            }
+            StmtKind::While { condition, body } => {
+                let loop_start = self.chunk().code.len();
+                if let Some(value) = self.fold(condition) {
+                    if optimizer::is_truthy(&value) {
+                        self.statement(body);
+                        self.emit_loop(loop_start);
+                    }
+                    return;
+                }
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(body);
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    // Compiles a block's statements, leaving out those after one that
+    // always returns.
+    fn statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+            if self.optimize && optimizer::returns(statement) {
+                break;
+            }
+        }
+    }
+
+    // The constant `expr` evaluates to, when optimizing.
+    fn fold(&self, expr: &Expr) -> Option<LiteralValue> {
+        if self.optimize {
+            optimizer::fold(expr)
+        } else {
+            None
+        }
+    }
+
+    fn class(&mut self, name: &Token, superclass: Option<&Expr>, methods: &[Rc<Function>]) {
+        self.line = name.line;
+        let name_constant = self.identifier_constant(name);
+        let global = self.declare_variable(name);
+        self.emit_op(OpCode::Class);
+        self.emit_u16(name_constant);
+        self.define_variable(global);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+            self.begin_scope();
+            self.add_local("super".to_string(), name);
+            self.named_variable(name, false);
+            let token = match &superclass.kind {
+                ExprKind::Variable { name } => name,
+                _ => name,
+            };
+            self.emit_op_at(OpCode::Inherit, token);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(name, false);
+        for method in methods {
+            let kind = if method.name.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+            let constant = self.identifier_constant(&method.name);
+            self.emit_op(OpCode::Method);
+            self.emit_u16(constant);
+        }
+        self.emit_op(OpCode::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    // Compiles the body of `function` and emits the closure that creates
+    // it at runtime.
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        self.functions
+            .push(FunctionState::new(kind, function.name.lexeme.clone()));
+        self.begin_scope();
+        for param in &function.params {
+            self.current().arity += 1;
+            self.add_local(param.lexeme.clone(), param);
+        }
+        self.statements(&function.body);
+        self.line = function.span.end_line;
+        let upvalues = self.current().upvalues.clone();
+        let object = self.end_function();
+        self.line = function.name.line;
+
+        let constant = self.make_constant(Value::obj(object));
+        self.emit_op(OpCode::Closure);
+        self.emit_u16(constant);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    // Finishes the innermost function and moves it to the heap.
+    fn end_function(&mut self) -> ObjRef {
+        self.emit_implicit_return_value();
+        self.emit_op(OpCode::Return);
+        let mut state = self.functions.pop().expect("No function being compiled");
+        if self.optimize {
+            optimizer::peephole(&self.vm.heap, &mut state.chunk);
+        }
+        self.alloc(Obj::Function(ObjFunction {
+            name: state.name,
+            arity: state.arity,
+            upvalue_count: state.upvalues.len(),
+            chunk: Rc::new(state.chunk),
+            file: self.vm.file.clone(),
+        }))
+    }
+
+    fn emit_implicit_return_value(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Expr]) {
+        for argument in arguments {
+            self.expression(argument);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        self.line = expr.span.line;
+        if let ExprKind::Binary { .. } | ExprKind::Unary { .. } | ExprKind::Grouping { .. } =
+            expr.kind
+        {
+            if let Some(value) = self.fold(expr) {
+                self.literal(&value);
+                return;
+            }
+        }
+        match &expr.kind {
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                self.named_variable(name, true);
+            }
+            ExprKind::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                let op = match operator.token_type {
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Star => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    TokenType::Greater => OpCode::Greater,
+                    TokenType::GreaterEqual => OpCode::GreaterEqual,
+                    TokenType::Less => OpCode::Less,
+                    TokenType::LessEqual => OpCode::LessEqual,
+                    TokenType::EqualEqual | TokenType::BangEqual => OpCode::Equal,
+                    _ => unreachable!("Parser only produces binary operators"),
+                };
+                self.emit_op_at(op, operator);
+                if operator.token_type == TokenType::BangEqual {
+                    self.emit_op(OpCode::Not);
+                }
+            }
+            ExprKind::Call {
+                callee,
+                paren,
+                arguments,
+            } => match &callee.kind {
+                // Method calls skip creating a bound method.
+                ExprKind::Get { object, name } => {
+                    self.expression(object);
+                    self.arguments(arguments);
+                    let constant = self.identifier_constant(name);
+                    let cache = self.make_cache();
+                    self.emit_invoke(OpCode::Invoke, name, paren);
+                    self.emit_u16(constant);
+                    self.emit_byte(arguments.len() as u8);
+                    self.emit_u16(cache);
+                }
+                ExprKind::Super { keyword, method } => {
+                    self.named_variable(&this_token(keyword), false);
+                    self.arguments(arguments);
+                    self.named_variable(keyword, false);
+                    let constant = self.identifier_constant(method);
+                    self.emit_invoke(OpCode::SuperInvoke, method, paren);
+                    self.emit_u16(constant);
+                    self.emit_byte(arguments.len() as u8);
+                }
+                _ => {
+                    self.expression(callee);
+                    self.arguments(arguments);
+                    self.emit_op_at(OpCode::Call, paren);
+                    self.emit_byte(arguments.len() as u8);
+                }
+            },
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                let constant = self.identifier_constant(name);
+                let cache = self.make_cache();
+                self.emit_op_at(OpCode::GetProperty, name);
+                self.emit_u16(constant);
+                self.emit_u16(cache);
+            }
+            ExprKind::Grouping { expression } => self.expression(expression),
+            ExprKind::Literal { value } => self.literal(value),
+            ExprKind::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left);
+                if operator.token_type == TokenType::Or {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                let constant = self.identifier_constant(name);
+                let cache = self.make_cache();
+                self.emit_op_at(OpCode::SetProperty, name);
+                self.emit_u16(constant);
+                self.emit_u16(cache);
+            }
+            ExprKind::Super { keyword, method } => {
+                self.named_variable(&this_token(keyword), false);
+                self.named_variable(keyword, false);
+                let constant = self.identifier_constant(method);
+                self.emit_op_at(OpCode::GetSuper, method);
+                self.emit_u16(constant);
+            }
+            ExprKind::This { keyword } => self.named_variable(keyword, false),
+            ExprKind::Unary { operator, right } => {
+                self.expression(right);
+                match operator.token_type {
+                    TokenType::Bang => self.emit_op(OpCode::Not),
+                    TokenType::Minus => self.emit_op_at(OpCode::Negate, operator),
+                    _ => unreachable!("Parser only produces '!' and '-' unary operators"),
+                }
+            }
+            ExprKind::Variable { name } => self.named_variable(name, false),
+        }
+    }
+
+    fn literal(&mut self, value: &LiteralValue) {
+        match value {
+            LiteralValue::Nil => self.emit_op(OpCode::Nil),
+            LiteralValue::Bool(true) => self.emit_op(OpCode::True),
+            LiteralValue::Bool(false) => self.emit_op(OpCode::False),
+            LiteralValue::Number(n) => self.emit_constant(Value::number(*n)),
+            LiteralValue::String(s) => {
+                let string = self.intern(s);
+                self.emit_constant(Value::obj(string));
+            }
+        }
+    }
+
+    // Emits a read of the variable `name`, or a write of the value on top
+    // of the stack to it.
+    fn named_variable(&mut self, name: &Token, assign: bool) {
+        let top = self.functions.len() - 1;
+        let (op, operand) = if let Some(slot) = self.functions[top].resolve_local(&name.lexeme) {
+            let op = if assign {
+                OpCode::SetLocal
+            } else {
+                OpCode::GetLocal
+            };
+            (op, slot as u16)
+        } else if let Some(index) = self.resolve_upvalue(top, name) {
+            let op = if assign {
+                OpCode::SetUpvalue
+            } else {
+                OpCode::GetUpvalue
+            };
+            (op, index as u16)
+        } else {
+            let constant = self.identifier_constant(name);
+            let op = if assign {
+                OpCode::SetGlobal
+            } else {
+                OpCode::GetGlobal
+            };
+            self.emit_op_at(op, name);
+            self.emit_u16(constant);
+            return;
+        };
+        self.emit_op(op);
+        self.emit_byte(operand as u8);
+    }
+
+    // Finds `name` in the functions enclosing `function`, capturing it
+    // along the way. Returns the upvalue index in `function`.
+    fn resolve_upvalue(&mut self, function: usize, name: &Token) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        let enclosing = function - 1;
+        if let Some(slot) = self.functions[enclosing].resolve_local(&name.lexeme) {
+            self.functions[enclosing].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(function, slot, true, name));
+        }
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(function, index, false, name))
+    }
+
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool, name: &Token) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error_at(
+                name,
+                codes::TOO_MANY_UPVALUES,
+                "Too many closure variables in function.",
+            );
+            return 0;
+        }
+        let upvalues = &mut self.functions[function].upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    // Adds a local for `name` when inside a scope. Returns the constant
+    // holding the name when the variable is global instead.
+    fn declare_variable(&mut self, name: &Token) -> Option<u16> {
+        if self.current().scope_depth == 0 {
+            return Some(self.identifier_constant(name));
+        }
+        self.add_local(name.lexeme.clone(), name);
+        None
+    }
+
+    fn define_variable(&mut self, global: Option<u16>) {
+        if let Some(constant) = global {
+            self.emit_op(OpCode::DefineGlobal);
+            self.emit_u16(constant);
+        }
+    }
+
+    fn add_local(&mut self, name: String, token: &Token) {
+        if self.current().locals.len() == MAX_LOCALS {
+            self.error_at(
+                token,
+                codes::TOO_MANY_LOCALS,
+                "Too many local variables in function.",
+            );
+            return;
+        }
+        let depth = self.current().scope_depth;
+        self.current().locals.push(Local {
+            name,
+            depth,
+            is_captured: false,
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+        loop {
+            let state = self.current();
+            let captured = match state.locals.last() {
+                Some(local) if local.depth > state.scope_depth => local.is_captured,
+                _ => break,
+            };
+            state.locals.pop();
+            self.emit_op(if captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            });
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u16 {
+        if let Some(&constant) = self.current().identifiers.get(&name.lexeme) {
+            return constant;
+        }
+        let string = self.intern(&name.lexeme);
+        let constant = self.make_constant(Value::obj(string));
+        self.current()
+            .identifiers
+            .insert(name.lexeme.clone(), constant);
+        constant
+    }
+
+    // Allocates an object for the code being compiled, which keeps it alive
+    // until compilation is done.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let obj = self.vm.alloc(obj);
+        self.vm.compiler_roots.push(obj);
+        obj
+    }
+
+    fn intern(&mut self, s: &str) -> ObjRef {
+        let string = self.vm.intern(s);
+        self.vm.compiler_roots.push(string);
+        string
+    }
+
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let constant = self.chunk().add_constant(value);
+        if constant > u16::MAX as usize {
+            self.error(
+                codes::TOO_MANY_CONSTANTS,
+                "Too many constants in one chunk.",
+            );
+            return 0;
+        }
+        constant as u16
+    }
+
+    fn make_cache(&mut self) -> u16 {
+        let cache = self.chunk().add_cache();
+        if cache > u16::MAX as usize {
+            self.error(
+                codes::TOO_MANY_CACHES,
+                "Too many property accesses in one chunk.",
+            );
+            return 0;
+        }
+        cache as u16
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_op(OpCode::Constant);
+        self.emit_u16(constant);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        for byte in value.to_be_bytes().iter() {
+            self.emit_byte(*byte);
+        }
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+
+    // Emits an instruction that can fail at runtime, remembering `token`
+    // so the error can point at it.
+    fn emit_op_at(&mut self, op: OpCode, token: &Token) {
+        self.line = token.line;
+        self.chunk().add_token(token);
+        self.emit_op(op);
+    }
+
+    // Emits an invoke instruction. Errors finding the method point at
+    // `name` and errors calling it at `paren`, recorded against the first
+    // operand byte.
+    fn emit_invoke(&mut self, op: OpCode, name: &Token, paren: &Token) {
+        self.emit_op_at(op, name);
+        self.chunk().add_token(paren);
+    }
+
+    // Emits a jump with a placeholder offset and returns where the offset
+    // is, for `patch_jump`.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_u16(u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // Skip over the operand of the jump itself.
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(codes::JUMP_TOO_LARGE, "Too much code to jump over.");
+            return;
+        }
+        let bytes = (jump as u16).to_be_bytes();
+        self.chunk().code[offset] = bytes[0];
+        self.chunk().code[offset + 1] = bytes[1];
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(codes::JUMP_TOO_LARGE, "Loop body too large.");
+        }
+        self.emit_u16(offset as u16);
+    }
+
+    fn error(&mut self, code: &'static str, message: &str) {
+        self.errors
+            .push(Diagnostic::error(code, message.to_string(), self.line, 0));
+    }
+
+    fn error_at(&mut self, token: &Token, code: &'static str, message: &str) {
+        let span = Span::of_token(token);
+        self.errors.push(
+            Diagnostic::error(code, message.to_string(), span.line, span.column)
+                .with_end(span.end_line, span.end_column)
+                .with_location(format!("at '{}'", token.lexeme)),
+        );
+    }
+}
+
+// The `this` that a `super` expression implicitly refers to.
+fn this_token(keyword: &Token) -> Token {
+    Token::new(TokenType::This, "this".to_string(), None, keyword.line).with_column(keyword.column)
+}
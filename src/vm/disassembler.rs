@@ -0,0 +1,250 @@
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::object::{Heap, Obj, ObjRef};
+use crate::vm::value::Value;
+use std::fmt::Write;
+
+impl OpCode {
+    /// The name shown in disassembly, e.g. `OP_GET_LOCAL`.
+    pub fn name(self) -> String {
+        let mut name = String::from("OP");
+        for c in format!("{:?}", self).chars() {
+            if c.is_ascii_uppercase() {
+                name.push('_');
+            }
+            name.push(c.to_ascii_uppercase());
+        }
+        name
+    }
+}
+
+/// Disassembles the chunk of `function`, followed by the chunks of the
+/// functions declared in it, depth first.
+pub fn disassemble_function(heap: &Heap, function: ObjRef) -> String {
+    let mut out = String::new();
+    let mut pending = vec![function];
+    while let Some(function) = pending.pop() {
+        let object = heap.function(function);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&disassemble_chunk(
+            heap,
+            &object.chunk,
+            &heap.format_value(Value::obj(function)),
+        ));
+        // Pushed in reverse so nested functions come out in source order.
+        for constant in object.chunk.constants.iter().rev() {
+            if let Some(obj) = constant.as_obj() {
+                if let Obj::Function(_) = heap.get(obj) {
+                    pending.push(obj);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Disassembles every instruction of `chunk` under a `== name ==` header.
+pub fn disassemble_chunk(heap: &Heap, chunk: &Chunk, name: &str) -> String {
+    let mut out = format!("== {} ==\n", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(heap, chunk, offset, &mut out);
+    }
+    out
+}
+
+/// Writes one line for the instruction at `offset`, with the byte offset,
+/// source line (`|` when it is the same as the previous instruction's),
+/// opcode and operands. Returns the offset of the next instruction.
+pub fn disassemble_instruction(
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut String,
+) -> usize {
+    write!(out, "{:04} ", offset).unwrap();
+    let line = chunk.line_at(offset);
+    if offset > 0 && line == chunk.line_at(offset - 1) {
+        out.push_str("   | ");
+    } else {
+        write!(out, "{:4} ", line).unwrap();
+    }
+
+    let op = match OpCode::from_byte(chunk.code[offset]) {
+        Some(op) => op,
+        None => {
+            writeln!(out, "Unknown opcode {}", chunk.code[offset]).unwrap();
+            return offset + 1;
+        }
+    };
+    let name = op.name();
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let constant = chunk.read_u16(offset + 1);
+            writeln!(
+                out,
+                "{:<16} {:4} '{}'",
+                name,
+                constant,
+                heap.format_value(chunk.constants[constant as usize])
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::GetProperty | OpCode::SetProperty => {
+            let constant = chunk.read_u16(offset + 1);
+            writeln!(
+                out,
+                "{:<16} {:4} '{}' (cache {})",
+                name,
+                constant,
+                heap.format_value(chunk.constants[constant as usize]),
+                chunk.read_u16(offset + 3)
+            )
+            .unwrap();
+            offset + 5
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let constant = chunk.read_u16(offset + 1);
+            write!(
+                out,
+                "{:<16} ({} args) {:4} '{}'",
+                name,
+                chunk.code[offset + 3],
+                constant,
+                heap.format_value(chunk.constants[constant as usize])
+            )
+            .unwrap();
+            if op == OpCode::SuperInvoke {
+                out.push('\n');
+                return offset + 4;
+            }
+            writeln!(out, " (cache {})", chunk.read_u16(offset + 4)).unwrap();
+            offset + 6
+        }
+        OpCode::PopN
+        | OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            writeln!(out, "{:<16} {:4}", name, chunk.code[offset + 1]).unwrap();
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            let target = if op == OpCode::Loop {
+                offset + 3 - jump
+            } else {
+                offset + 3 + jump
+            };
+            writeln!(out, "{:<16} {:4} -> {}", name, offset, target).unwrap();
+            offset + 3
+        }
+        OpCode::Closure => {
+            let constant = chunk.read_u16(offset + 1);
+            let function = chunk.constants[constant as usize];
+            writeln!(
+                out,
+                "{:<16} {:4} {}",
+                name,
+                constant,
+                heap.format_value(function)
+            )
+            .unwrap();
+            let upvalue_count = match function.as_obj() {
+                Some(function) => heap.function(function).upvalue_count,
+                None => 0,
+            };
+            let mut offset = offset + 3;
+            for _ in 0..upvalue_count {
+                let kind = if chunk.code[offset] == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                writeln!(
+                    out,
+                    "{:04}    |                     {} {}",
+                    offset,
+                    kind,
+                    chunk.code[offset + 1]
+                )
+                .unwrap();
+                offset += 2;
+            }
+            offset
+        }
+        _ => {
+            writeln!(out, "{}", name).unwrap();
+            offset + 1
+        }
+    }
+}
+
+/// The stack as `--trace-execution` shows it, bottom first.
+pub fn format_stack(heap: &Heap, stack: &[Value]) -> String {
+    let mut out = String::from("          ");
+    for value in stack {
+        write!(out, "[ {} ]", heap.format_value(*value)).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+
+    fn disassemble(source: &str) -> String {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().clone();
+        let statements = Parser::new(tokens).parse();
+        let mut vm = Vm::new();
+        vm.set_optimize(false);
+        let function = vm.compile(&statements, false).unwrap();
+        disassemble_function(vm.heap(), function)
+    }
+
+    #[test]
+    fn test_opcode_names() {
+        assert_eq!(OpCode::Constant.name(), "OP_CONSTANT");
+        assert_eq!(OpCode::JumpIfFalse.name(), "OP_JUMP_IF_FALSE");
+    }
+
+    #[test]
+    fn test_disassemble_script() {
+        assert_eq!(
+            disassemble("var a = 1.5;\nprint a;"),
+            "== <script> ==\n\
+             0000    1 OP_CONSTANT         0 '1.5'\n\
+             0003    | OP_DEFINE_GLOBAL    1 'a'\n\
+             0006    2 OP_GET_GLOBAL       1 'a'\n\
+             0009    | OP_PRINT\n\
+             0010    | OP_NIL\n\
+             0011    | OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_nested_functions() {
+        let out = disassemble(
+            "fun outer() {\n  var x = 1;\n  fun inner() { return x; }\n}\nif (true) print 1;",
+        );
+        assert!(out.contains("OP_CLOSURE          1 <fn outer>\n"));
+        assert!(out.contains("OP_JUMP_IF_FALSE    7 -> 18\n"));
+        assert!(out.contains("\n== <fn outer> ==\n"));
+        assert!(out.contains("|                     local 1\n"));
+        assert!(out.contains("\n== <fn inner> ==\n"));
+        assert!(out.contains("OP_GET_UPVALUE      0\n"));
+        assert!(out.find("== <fn outer> ==") < out.find("== <fn inner> =="));
+    }
+}
@@ -0,0 +1,852 @@
+//! The `.loxc` file format: a compiled script saved so it can run again
+//! without being scanned, parsed and compiled.
+//!
+//! Integers are big-endian, like bytecode operands. A file starts with a
+//! header:
+//!
+//! ```text
+//! magic     4 bytes  "LOXC"
+//! version   u16      FORMAT_VERSION
+//! source    u64      FNV-1a hash of the source it was compiled from
+//! checksum  u64      FNV-1a hash of everything after the header
+//! ```
+//!
+//! The top-level function follows. A function is its name, arity, upvalue
+//! count, code, constants, line table, error tokens and number of inline
+//! caches. Functions among the constants are written in place, so nested
+//! functions come out depth first.
+
+use crate::token::{Token, TokenType};
+use crate::vm::chunk::{Chunk, LineRun, OpCode};
+use crate::vm::object::{Heap, Obj, ObjFunction, ObjRef};
+use crate::vm::value::{Value, ValueKind};
+use crate::vm::Vm;
+use std::fmt;
+use std::rc::Rc;
+
+/// The first bytes of every `.loxc` file.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the format or the instruction set changes.
+pub const FORMAT_VERSION: u16 = 2;
+
+const HEADER_LEN: usize = 4 + 2 + 8 + 8;
+// Deeper nesting than any real script has, to stop a malformed file from
+// recursing without bound.
+const MAX_NESTING: usize = 1024;
+// Inline cache operands are 16 bits wide, so no chunk uses more caches.
+const MAX_CACHES: usize = 1 << 16;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Why a `.loxc` file could not be loaded.
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    /// The input does not start with `MAGIC`.
+    NotBytecode,
+    /// The file was written for another version of the format.
+    Version(u16),
+    /// The file is truncated, altered or otherwise malformed.
+    Corrupt(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoadError::Version(version) => write!(
+                f,
+                "Unsupported bytecode version {} (expected {}). Compile the script again.",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Corrupt(reason) => write!(f, "Corrupt bytecode file: {}.", reason),
+        }
+    }
+}
+
+/// The FNV-1a hash of `bytes`, 64 bits wide.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Encodes `function`, compiled from `source`, as a `.loxc` file.
+pub fn write(heap: &Heap, function: ObjRef, source: &str) -> Vec<u8> {
+    let mut body = Writer(Vec::new());
+    body.function(heap, function);
+    let mut out = Writer(Vec::with_capacity(HEADER_LEN + body.0.len()));
+    out.0.extend_from_slice(MAGIC);
+    out.u16(FORMAT_VERSION);
+    out.u64(hash_bytes(source.as_bytes()));
+    out.u64(hash_bytes(&body.0));
+    out.0.extend(body.0);
+    out.0
+}
+
+/// The hash of the source a `.loxc` file was compiled from, if `bytes`
+/// looks like one.
+pub fn source_hash(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    Reader::new(&bytes[6..14]).u64().ok()
+}
+
+/// Decodes a `.loxc` file into a function ready for `Vm::interpret`,
+/// allocating its objects on the VM's heap.
+///
+/// The checksum rejects files damaged after they were written, and the
+/// code is checked for invalid opcodes, out-of-range operands and
+/// instructions that would find too few values on the stack.
+pub(super) fn read(vm: &mut Vm, bytes: &[u8]) -> Result<ObjRef, LoadError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut header = Reader::new(&bytes[MAGIC.len()..]);
+    let version = header.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LoadError::Version(version));
+    }
+    header.u64()?;
+    let checksum = header.u64()?;
+    let body = &bytes[HEADER_LEN..];
+    if hash_bytes(body) != checksum {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let mut reader = Reader::new(body);
+    let function = reader.function(vm, 0)?;
+    if reader.position != body.len() {
+        return Err(corrupt("unexpected data after the script"));
+    }
+    Ok(function)
+}
+
+fn corrupt(reason: &str) -> LoadError {
+    LoadError::Corrupt(reason.to_string())
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.0.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, heap: &Heap, function: ObjRef) {
+        let function = heap.function(function);
+        let chunk = &function.chunk;
+        self.str(&function.name);
+        self.u32(function.arity);
+        self.u32(function.upvalue_count);
+        self.u32(chunk.code.len());
+        self.0.extend_from_slice(&chunk.code);
+        self.u32(chunk.constants.len());
+        for constant in &chunk.constants {
+            match constant.kind() {
+                ValueKind::Nil => self.u8(TAG_NIL),
+                ValueKind::Bool(false) => self.u8(TAG_FALSE),
+                ValueKind::Bool(true) => self.u8(TAG_TRUE),
+                ValueKind::Number(n) => {
+                    self.u8(TAG_NUMBER);
+                    self.u64(n.to_bits());
+                }
+                ValueKind::Obj(obj) => match heap.get(obj) {
+                    Obj::String(s) => {
+                        self.u8(TAG_STRING);
+                        self.str(&s.chars);
+                    }
+                    Obj::Function(_) => {
+                        self.u8(TAG_FUNCTION);
+                        self.function(heap, obj);
+                    }
+                    _ => unreachable!("Constants are strings or functions"),
+                },
+            }
+        }
+        self.u32(chunk.lines.len());
+        for run in &chunk.lines {
+            self.u32(run.line);
+            self.u32(run.count);
+        }
+        // Errors only show the lexeme and position of a token.
+        self.u32(chunk.tokens.len());
+        for (offset, token) in &chunk.tokens {
+            self.u32(*offset);
+            self.u32(token.line);
+            self.u32(token.column);
+            self.str(&token.lexeme);
+        }
+        self.u32(chunk.caches.len());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() - self.position < len {
+            return Err(corrupt("unexpected end of file"));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    // Reads how many items follow, each taking at least `size` bytes, so a
+    // malformed count cannot make the loader allocate more than the file
+    // could describe.
+    fn count(&mut self, size: usize) -> Result<usize, LoadError> {
+        let count = self.u32()?;
+        if count.saturating_mul(size) > self.bytes.len() - self.position {
+            return Err(corrupt("unexpected end of file"));
+        }
+        Ok(count)
+    }
+
+    fn str(&mut self) -> Result<&'a str, LoadError> {
+        let len = self.u32()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| corrupt("invalid UTF-8 in a string"))
+    }
+
+    // Reads a function and everything nested in it. Each object is kept in
+    // the VM's compiler roots so a collection cannot free it before the
+    // function that refers to it exists.
+    fn function(&mut self, vm: &mut Vm, depth: usize) -> Result<ObjRef, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(corrupt("functions nested too deeply"));
+        }
+        let name = self.str()?.to_string();
+        let arity = self.u32()?;
+        let upvalue_count = self.u32()?;
+        // The VM calls the script with no arguments and nothing to capture.
+        if depth == 0 && (arity != 0 || upvalue_count != 0) {
+            return Err(corrupt("the script takes arguments or captures variables"));
+        }
+        let mut chunk = Chunk::new();
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+
+        for _ in 0..self.count(1)? {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::NIL,
+                TAG_FALSE => Value::bool(false),
+                TAG_TRUE => Value::bool(true),
+                TAG_NUMBER => Value::number(f64::from_bits(self.u64()?)),
+                TAG_STRING => {
+                    let string = vm.intern(self.str()?);
+                    vm.compiler_roots.push(string);
+                    Value::obj(string)
+                }
+                TAG_FUNCTION => Value::obj(self.function(vm, depth + 1)?),
+                _ => return Err(corrupt("unknown constant type")),
+            };
+            chunk.add_constant(constant);
+        }
+        for _ in 0..self.count(8)? {
+            let line = self.u32()?;
+            let count = self.u32()?;
+            chunk.lines.push(LineRun { line, count });
+        }
+        for _ in 0..self.count(16)? {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            let column = self.u32()?;
+            let lexeme = self.str()?.to_string();
+            let token = Token::new(TokenType::Identifier, lexeme, None, line).with_column(column);
+            chunk.tokens.push((offset, token));
+        }
+        let caches = self.u32()?;
+        if caches > MAX_CACHES {
+            return Err(corrupt("too many inline caches"));
+        }
+        for _ in 0..caches {
+            chunk.add_cache();
+        }
+
+        verify(&vm.heap, &chunk, arity, upvalue_count).map_err(LoadError::Corrupt)?;
+        let function = vm.alloc(Obj::Function(ObjFunction {
+            name,
+            arity,
+            upvalue_count,
+            chunk: Rc::new(chunk),
+            file: vm.file.clone(),
+        }));
+        vm.compiler_roots.push(function);
+        Ok(function)
+    }
+}
+
+// Checks that running `chunk`, the code of a function taking `arity`
+// arguments and capturing `upvalue_count` variables, cannot read outside
+// its code, constants, caches, stack slots or upvalues, and that its tables
+// describe its code.
+fn verify(heap: &Heap, chunk: &Chunk, arity: usize, upvalue_count: usize) -> Result<(), String> {
+    let code = &chunk.code;
+    if chunk.lines.iter().map(|run| run.count).sum::<usize>() != code.len() {
+        return Err("line table does not match the code".to_string());
+    }
+    if chunk.tokens.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err("error tokens are out of order".to_string());
+    }
+
+    let constant = |offset: usize| -> Result<Value, String> {
+        let index = chunk.read_u16(offset) as usize;
+        chunk
+            .constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("constant {} out of range", index))
+    };
+    let string = |offset: usize| -> Result<(), String> {
+        match constant(offset)?.as_obj().map(|obj| heap.get(obj)) {
+            Some(Obj::String(_)) => Ok(()),
+            _ => Err("name operand is not a string".to_string()),
+        }
+    };
+    let cache = |offset: usize| -> Result<(), String> {
+        if chunk.read_u16(offset) as usize >= chunk.caches.len() {
+            return Err("inline cache out of range".to_string());
+        }
+        Ok(())
+    };
+
+    let mut starts = Vec::new();
+    let mut jumps = Vec::new();
+    let mut last = None;
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::from_byte(code[offset])
+            .ok_or_else(|| format!("unknown opcode {} at {}", code[offset], offset))?;
+        let operands = op.operand_len();
+        if offset + operands >= code.len() {
+            return Err("instruction runs past the end of the code".to_string());
+        }
+        match op {
+            OpCode::Constant => {
+                constant(offset + 1)?;
+            }
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::SuperInvoke => string(offset + 1)?,
+            OpCode::GetProperty | OpCode::SetProperty => {
+                string(offset + 1)?;
+                cache(offset + 3)?;
+            }
+            OpCode::Invoke => {
+                string(offset + 1)?;
+                cache(offset + 4)?;
+            }
+            OpCode::Jump | OpCode::JumpIfFalse => {
+                jumps.push(offset + 3 + chunk.read_u16(offset + 1) as usize);
+            }
+            OpCode::Loop => {
+                let jump = chunk.read_u16(offset + 1) as usize;
+                let target = (offset + 3)
+                    .checked_sub(jump)
+                    .ok_or("loop jumps before the code")?;
+                jumps.push(target);
+            }
+            _ => {}
+        }
+        starts.push(offset);
+        last = Some(op);
+        offset += 1 + operands;
+
+        if op == OpCode::Closure {
+            let upvalue_count = match constant(offset - 2)?.as_obj().map(|obj| heap.get(obj)) {
+                Some(Obj::Function(function)) => function.upvalue_count,
+                _ => return Err("closure operand is not a function".to_string()),
+            };
+            offset += 2 * upvalue_count;
+            if offset > code.len() {
+                return Err("instruction runs past the end of the code".to_string());
+            }
+        }
+    }
+    // Optimized code can end in a loop that never exits.
+    if !matches!(last, Some(OpCode::Return) | Some(OpCode::Loop)) {
+        return Err("code runs past its end".to_string());
+    }
+    if jumps
+        .iter()
+        .any(|target| starts.binary_search(target).is_err())
+    {
+        return Err("jump into the middle of an instruction".to_string());
+    }
+    verify_stack(heap, chunk, &starts, arity, upvalue_count)
+}
+
+// Follows every path through `chunk`, whose instructions start at
+// `starts`, tracking how many values the frame holds. Each instruction
+// must find the values it pops and the slots it reads, and paths that meet
+// must agree on the depth.
+fn verify_stack(
+    heap: &Heap,
+    chunk: &Chunk,
+    starts: &[usize],
+    arity: usize,
+    upvalue_count: usize,
+) -> Result<(), String> {
+    let code = &chunk.code;
+    let index_of = |offset: usize| {
+        starts
+            .binary_search(&offset)
+            .map_err(|_| "jump into the middle of an instruction".to_string())
+    };
+    let mut depths = vec![None; starts.len()];
+    // The callee or receiver is in slot 0, followed by the arguments.
+    let mut pending = vec![(0, arity + 1)];
+    while let Some((index, depth)) = pending.pop() {
+        let offset = starts[index];
+        match depths[index] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(format!("inconsistent stack depth at {}", offset)),
+            None => depths[index] = Some(depth),
+        }
+        let op = OpCode::from_byte(code[offset]).expect("Opcodes were checked");
+        let operand = |n: usize| code[offset + n] as usize;
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::Class => (0, 1),
+            OpCode::GetLocal | OpCode::SetLocal => {
+                if operand(1) >= depth {
+                    return Err(format!("local slot {} out of range", operand(1)));
+                }
+                if op == OpCode::GetLocal {
+                    (0, 1)
+                } else {
+                    (1, 1)
+                }
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                if operand(1) >= upvalue_count {
+                    return Err(format!("upvalue {} out of range", operand(1)));
+                }
+                if op == OpCode::GetUpvalue {
+                    (0, 1)
+                } else {
+                    (1, 1)
+                }
+            }
+            OpCode::Closure => {
+                let function = chunk.constants[chunk.read_u16(offset + 1) as usize];
+                let function = match function.as_obj().map(|obj| heap.get(obj)) {
+                    Some(Obj::Function(function)) => function,
+                    _ => unreachable!("Closure operands were checked"),
+                };
+                for capture in 0..function.upvalue_count {
+                    let (is_local, index) = (operand(3 + 2 * capture), operand(4 + 2 * capture));
+                    let in_range = match is_local {
+                        0 => index < upvalue_count,
+                        1 => index < depth,
+                        _ => return Err("invalid closure capture".to_string()),
+                    };
+                    if !in_range {
+                        return Err(format!("closure captures {} out of range", index));
+                    }
+                }
+                (0, 1)
+            }
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => (1, 0),
+            OpCode::PopN => (operand(1), 0),
+            OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method => (2, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            // The callee and arguments are replaced by the result.
+            OpCode::Call => (operand(1) + 1, 1),
+            OpCode::Invoke => (operand(3) + 1, 1),
+            // So is the superclass, popped first.
+            OpCode::SuperInvoke => (operand(3) + 2, 1),
+        };
+        if pops > depth {
+            return Err(format!("stack underflow at {}", offset));
+        }
+        let depth = depth - pops + pushes;
+
+        let next = offset + 1 + op.operand_len();
+        match op {
+            OpCode::Return => {}
+            OpCode::Jump => {
+                pending.push((index_of(next + chunk.read_u16(offset + 1) as usize)?, depth))
+            }
+            OpCode::Loop => {
+                pending.push((index_of(next - chunk.read_u16(offset + 1) as usize)?, depth))
+            }
+            OpCode::JumpIfFalse => {
+                pending.push((index_of(next + chunk.read_u16(offset + 1) as usize)?, depth));
+                pending.push((index + 1, depth));
+            }
+            _ => pending.push((index + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use crate::vm::disassembler::disassemble_function;
+
+    const SOURCE: &str = "
+        class Counter {
+          init() { this.count = 0; }
+          add(n) { this.count = this.count + n; return this; }
+        }
+        fun make() {
+          var c = Counter();
+          fun step() { return c.add(1.5).count; }
+          return step;
+        }
+        var step = make();
+        step();
+        print step() + nil;
+    ";
+
+    fn compile(vm: &mut Vm, source: &str) -> ObjRef {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().clone();
+        let statements = Parser::new(tokens).parse();
+        Resolver::new().resolve(&statements);
+        vm.compile(&statements, false).unwrap()
+    }
+
+    fn compiled() -> Vec<u8> {
+        let mut vm = Vm::new();
+        let function = compile(&mut vm, SOURCE);
+        write(vm.heap(), function, SOURCE)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = Vm::new();
+        let function = compile(&mut vm, SOURCE);
+        let bytes = write(vm.heap(), function, SOURCE);
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(source_hash(&bytes), Some(hash_bytes(SOURCE.as_bytes())));
+
+        let mut loaded = Vm::new();
+        let copy = loaded.load(&bytes).unwrap();
+        assert_eq!(
+            disassemble_function(loaded.heap(), copy),
+            disassemble_function(vm.heap(), function)
+        );
+
+        // Errors still point at the source.
+        let error = loaded.interpret(copy).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!((error.token.lexeme.as_str(), error.token.line), ("+", 13));
+    }
+
+    #[test]
+    fn test_rejects_other_files_and_versions() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.load(b"print 1;"), Err(LoadError::NotBytecode));
+
+        let mut bytes = compiled();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        let error = vm.load(&bytes).unwrap_err();
+        assert_eq!(error, LoadError::Version(FORMAT_VERSION + 1));
+        assert!(error.to_string().contains("Unsupported bytecode version"));
+    }
+
+    #[test]
+    fn test_rejects_corruption() {
+        let bytes = compiled();
+        let mut vm = Vm::new();
+        for len in [HEADER_LEN - 1, HEADER_LEN, bytes.len() - 1].iter() {
+            assert!(matches!(
+                vm.load(&bytes[..*len]),
+                Err(LoadError::Corrupt(_))
+            ));
+        }
+        for offset in (HEADER_LEN..bytes.len()).step_by(7) {
+            let mut damaged = bytes.clone();
+            damaged[offset] ^= 0x5a;
+            assert_eq!(
+                vm.load(&damaged),
+                Err(LoadError::Corrupt("checksum mismatch".to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_verifies_code() {
+        let mut heap = Heap::new();
+        let mut chunk = Chunk::new();
+        for byte in [OpCode::Constant as u8, 0, 0, OpCode::Return as u8].iter() {
+            chunk.write(*byte, 1);
+        }
+        assert_eq!(
+            verify(&heap, &chunk, 0, 0),
+            Err("constant 0 out of range".to_string())
+        );
+        chunk.add_constant(Value::number(1.0));
+        assert_eq!(verify(&heap, &chunk, 0, 0), Ok(()));
+
+        chunk.code[0] = OpCode::GetGlobal as u8;
+        assert_eq!(
+            verify(&heap, &chunk, 0, 0),
+            Err("name operand is not a string".to_string())
+        );
+        chunk.constants[0] = Value::obj(heap.intern("x"));
+        assert_eq!(verify(&heap, &chunk, 0, 0), Ok(()));
+
+        let mut chunk = Chunk::new();
+        for byte in [
+            OpCode::Jump as u8,
+            0,
+            1,
+            OpCode::GetLocal as u8,
+            0,
+            OpCode::Return as u8,
+        ]
+        .iter()
+        {
+            chunk.write(*byte, 1);
+        }
+        assert_eq!(
+            verify(&heap, &chunk, 0, 0),
+            Err("jump into the middle of an instruction".to_string())
+        );
+        chunk.code[2] = 0;
+        assert_eq!(verify(&heap, &chunk, 0, 0), Ok(()));
+    }
+
+    // Compiles `source`, replaces the one place its file holds `from` with
+    // `to` and updates the checksum, like a crafted file.
+    fn patched(source: &str, from: &[u8], to: &[u8]) -> Vec<u8> {
+        let mut vm = Vm::new();
+        let function = compile(&mut vm, source);
+        let mut bytes = write(vm.heap(), function, source);
+        let found: Vec<usize> = (HEADER_LEN..bytes.len())
+            .filter(|&offset| bytes[offset..].starts_with(from))
+            .collect();
+        assert_eq!(found.len(), 1, "{:?} is not unique", from);
+        bytes[found[0]..found[0] + to.len()].copy_from_slice(to);
+        checksummed(bytes)
+    }
+
+    // Updates the checksum of `bytes` after they were changed.
+    fn checksummed(mut bytes: Vec<u8>) -> Vec<u8> {
+        let checksum = hash_bytes(&bytes[HEADER_LEN..]);
+        bytes[HEADER_LEN - 8..HEADER_LEN].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_verifies_stack_use() {
+        let get_local = [OpCode::GetLocal as u8, 1, OpCode::Print as u8];
+        let source = "{ var a = 1; print a; }";
+        assert!(Vm::new()
+            .load(&patched(source, &get_local, &get_local))
+            .is_ok());
+
+        let cases: &[(&str, &[u8], &[u8], &str)] = &[
+            (
+                source,
+                &get_local,
+                &[OpCode::GetLocal as u8, 200],
+                "local slot 200 out of range",
+            ),
+            (
+                "1;",
+                &[OpCode::Pop as u8, OpCode::Nil as u8],
+                &[OpCode::Pop as u8, OpCode::Pop as u8],
+                "stack underflow at 5",
+            ),
+            (
+                "print true and 1;",
+                &[OpCode::Pop as u8, OpCode::Constant as u8],
+                &[OpCode::Nil as u8],
+                "inconsistent stack depth at 8",
+            ),
+            (
+                "fun f() { var a = 1; fun g() { return a; } return g; }",
+                &[OpCode::GetUpvalue as u8, 0, OpCode::Return as u8],
+                &[OpCode::GetUpvalue as u8, 3],
+                "upvalue 3 out of range",
+            ),
+            (
+                "fun f() { var a = 1; fun g() { return a; } return g; }",
+                &[1, 1, OpCode::GetLocal as u8],
+                &[1, 9],
+                "closure captures 9 out of range",
+            ),
+        ];
+        for (source, from, to, reason) in cases {
+            assert_eq!(
+                Vm::new().load(&patched(source, from, to)),
+                Err(LoadError::Corrupt(reason.to_string())),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_crafted_functions() {
+        // The script's name is empty, so its arity and upvalue count follow
+        // the name's length, and its six bytes of code come before the
+        // number of constants.
+        let (arity, upvalues, constants) = (HEADER_LEN + 4, HEADER_LEN + 8, HEADER_LEN + 22);
+        let get_local = [OpCode::GetLocal as u8, 4, OpCode::Nil as u8];
+        let constant = [OpCode::Constant as u8, 0, 0, OpCode::Print as u8];
+        let bytes = patched("print 1;", &constant, &get_local);
+        let mut takes_arguments = bytes.clone();
+        takes_arguments[arity..arity + 4].copy_from_slice(&5u32.to_be_bytes());
+        let mut captures = bytes.clone();
+        captures[upvalues..upvalues + 4].copy_from_slice(&1u32.to_be_bytes());
+        let mut many_caches = bytes.clone();
+        let end = many_caches.len();
+        many_caches[end - 4..].copy_from_slice(&u32::MAX.to_be_bytes());
+        let mut many_constants = bytes.clone();
+        many_constants[constants..constants + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let cases = [
+            (
+                takes_arguments,
+                "the script takes arguments or captures variables",
+            ),
+            (captures, "the script takes arguments or captures variables"),
+            (many_caches, "too many inline caches"),
+            (many_constants, "unexpected end of file"),
+        ];
+        for (bytes, reason) in cases.iter() {
+            assert_eq!(
+                Vm::new().load(&checksummed(bytes.clone())),
+                Err(LoadError::Corrupt(reason.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_crafted_classes_fail_at_runtime() {
+        let nil = OpCode::Nil as u8;
+        let class = "class A { m() {} }";
+        let subclass = "class A { m() {} } class B < A { m() { return super.m; } } B().m();";
+        let super_call = "class A { m() {} } class B < A { m() { super.m(); } } B().m();";
+        let cases: &[(&str, &[u8], &[u8], &str)] = &[
+            (
+                class,
+                &[OpCode::GetGlobal as u8, 0, 0, OpCode::Closure as u8, 0, 1],
+                &[nil, nil, nil, nil, nil, nil],
+                "Methods must be functions.",
+            ),
+            (
+                class,
+                &[OpCode::GetGlobal as u8, 0, 0, OpCode::Closure as u8],
+                &[nil, nil, nil],
+                "Only classes have methods.",
+            ),
+            (
+                "class A {} class B < A {}",
+                &[OpCode::GetGlobal as u8, 0, 1, OpCode::Inherit as u8],
+                &[nil, nil, OpCode::Pop as u8],
+                "Only classes can inherit.",
+            ),
+            (
+                subclass,
+                &[OpCode::GetUpvalue as u8, 0, OpCode::GetSuper as u8],
+                &[nil, nil],
+                "Superclass must be a class.",
+            ),
+            (
+                super_call,
+                &[OpCode::GetUpvalue as u8, 0, OpCode::SuperInvoke as u8],
+                &[nil, nil],
+                "Superclass must be a class.",
+            ),
+        ];
+        for (source, from, to, message) in cases {
+            let mut vm = Vm::new();
+            let function = vm.load(&patched(source, from, to)).unwrap();
+            assert_eq!(vm.interpret(function).unwrap_err().message, *message);
+        }
+    }
+}
@@ -0,0 +1,1513 @@
+//! A bytecode compiler and stack-based virtual machine, an alternative to
+//! the tree walker with the same observable behaviour.
+
+pub mod chunk;
+pub mod compiler;
+pub mod disassembler;
+pub mod loxc;
+pub mod object;
+pub mod optimizer;
+pub mod value;
+
+use crate::ast::Stmt;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::memory;
+use crate::runtime::{RuntimeError, Stdout, TraceFrame, DEFAULT_MAX_DEPTH};
+use crate::sandbox::{self, Capabilities, Capability, NativeError};
+use crate::token::{Token, TokenType};
+use chunk::{Chunk, InlineCache, OpCode};
+use loxc::LoadError;
+use object::{
+    Heap, NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative, ObjRef,
+    ObjString, ObjUpvalue, Table,
+};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+use value::{Value, ValueKind};
+
+// A function invocation in progress.
+struct CallFrame {
+    closure: ObjRef,
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // Stack index of the frame's slot zero.
+    slots: usize,
+}
+
+// Where a property lookup found the property.
+enum Property {
+    Field(Value),
+    Method(ObjRef),
+}
+
+type RunResult<T> = Result<T, RuntimeError>;
+
+/// Runs compiled bytecode.
+///
+/// Like the tree walker, global variables outlive a single program, so the
+/// REPL can compile and run one line at a time.
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Table<Value>,
+    // The name of initializers, interned once.
+    init_string: ObjRef,
+    // Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<ObjRef>,
+    // Objects referenced by code that is still being compiled.
+    compiler_roots: Vec<ObjRef>,
+    out: Box<dyn Write>,
+    // Whether to print the stack and each instruction as it runs.
+    trace_execution: bool,
+    stress_gc: bool,
+    // Whether the compiler folds constants and rewrites the bytecode.
+    optimize: bool,
+    // How many calls may be in progress at once, besides the script.
+    max_depth: usize,
+    // How many instructions each run may execute, and how many the current
+    // one has.
+    step_limit: Option<u64>,
+    steps: u64,
+    // How many bytes the heap may hold, the size at which to collect and
+    // measure it against that, and whether an allocation has gone past it.
+    memory_limit: Option<usize>,
+    next_measurement: usize,
+    out_of_memory: bool,
+    // The capabilities whose natives are defined.
+    capabilities: Capabilities,
+    // The file of the code compiled or loaded next.
+    file: Option<Rc<str>>,
+    // The call stack when the last runtime error was raised.
+    trace: Vec<TraceFrame>,
+    // The status the last run passed to `exit`, if it called it.
+    exit_status: Option<i32>,
+    // The paths of the modules imported so far.
+    imported: HashSet<String>,
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("stack", &self.stack)
+            .field("objects", &self.heap.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = Vm {
+            heap,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: Table::default(),
+            init_string,
+            open_upvalues: Vec::new(),
+            compiler_roots: Vec::new(),
+            out: Box::new(Stdout),
+            trace_execution: false,
+            stress_gc: false,
+            optimize: true,
+            max_depth: DEFAULT_MAX_DEPTH,
+            step_limit: None,
+            steps: 0,
+            memory_limit: None,
+            next_measurement: 0,
+            out_of_memory: false,
+            capabilities: Capabilities::none(),
+            file: None,
+            trace: Vec::new(),
+            exit_status: None,
+            imported: HashSet::new(),
+        };
+        vm.define_natives();
+        vm
+    }
+
+    /// Forgets every global definition and imported module, leaving only
+    /// the native functions. Output still goes to the same writer.
+    pub fn reset(&mut self) {
+        let out = std::mem::replace(&mut self.out, Box::new(Stdout));
+        let trace_execution = self.trace_execution;
+        let stress_gc = self.stress_gc;
+        let optimize = self.optimize;
+        let max_depth = self.max_depth;
+        let step_limit = self.step_limit;
+        let memory_limit = self.memory_limit;
+        let capabilities = self.capabilities;
+        *self = Vm::new();
+        self.out = out;
+        self.trace_execution = trace_execution;
+        self.optimize = optimize;
+        self.max_depth = max_depth;
+        self.step_limit = step_limit;
+        self.memory_limit = memory_limit;
+        self.set_stress_gc(stress_gc);
+        self.set_capabilities(capabilities);
+    }
+
+    /// The global variables, sorted by name, with their values formatted
+    /// the way `print` shows them.
+    pub fn globals(&self) -> Vec<(String, String)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| {
+                let name = self.heap.string(*name).to_string();
+                (name, self.heap.format_value(*value))
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    /// The names of the global variables, sorted, each with the fields and
+    /// methods of the instance it holds, for tab completion.
+    pub fn global_members(&self) -> Vec<(String, Vec<String>)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| {
+                let name = self.heap.string(*name).to_string();
+                (name, self.members(*value))
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    // The fields `value` has set and the methods of its class, if it is an
+    // instance. Inherited methods are copied into the class.
+    fn members(&self, value: Value) -> Vec<String> {
+        let instance = match self.as_instance(value) {
+            Some(instance) => self.heap.instance(instance),
+            None => return Vec::new(),
+        };
+        let class = self.heap.class(instance.class);
+        let fields = class
+            .fields
+            .iter()
+            .filter(|(_, slot)| instance.field(**slot).is_some())
+            .map(|(name, _)| name);
+        fields
+            .chain(class.methods.keys())
+            .map(|name| self.heap.string(*name).to_string())
+            .collect()
+    }
+
+    /// The objects allocated so far, for inspecting compiled code.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Redirects the output of `print` statements.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Prints the stack and the instruction about to run before each
+    /// instruction, to the same writer as `print`.
+    pub fn set_trace_execution(&mut self, trace_execution: bool) {
+        self.trace_execution = trace_execution;
+    }
+
+    /// Collects garbage before every allocation instead of when the heap
+    /// has grown, to shake out objects that are not rooted properly.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+        self.heap.set_stress_gc(stress_gc);
+    }
+
+    /// Turns the compiler's optimizations on or off. They are on by
+    /// default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Sets how deep calls may nest before a "Stack overflow." error.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Limits how many instructions each later call to `interpret` may
+    /// execute before a "Step limit exceeded." error, or lifts the limit
+    /// with `None`.
+    pub fn set_step_limit(&mut self, step_limit: Option<u64>) {
+        self.step_limit = step_limit;
+    }
+
+    /// Limits how many bytes the heap may hold before an "Out of memory."
+    /// error, or lifts the limit with `None`. Garbage is collected first
+    /// whenever the heap grows past `memory::next_measurement`.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.memory_limit = memory_limit;
+        self.next_measurement = 0;
+    }
+
+    /// Defines the natives of the capabilities granted, and only those.
+    /// Globals that scripts have defined are left alone, even where they
+    /// share a native's name. No capabilities are granted until this is
+    /// called.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.define_natives();
+    }
+
+    fn define_natives(&mut self) {
+        for &(capability, name, arity, function) in NATIVES {
+            let name_string = self.intern(name);
+            let is_native = match self.globals.get(&name_string).map(|value| value.kind()) {
+                Some(ValueKind::Obj(obj)) => {
+                    matches!(self.heap.get(obj), Obj::Native(native) if native.name == name)
+                }
+                Some(_) => false,
+                None => true,
+            };
+            if !is_native {
+                continue;
+            }
+            if self.capabilities.allows(capability) {
+                self.define_native(name, arity, function);
+            } else {
+                self.globals.remove(&name_string);
+            }
+        }
+    }
+
+    /// Sets the file the code compiled or loaded next comes from, for
+    /// stack traces.
+    pub fn set_file(&mut self, file: Option<Rc<str>>) {
+        self.file = file;
+    }
+
+    /// The call stack when the last runtime error was raised, innermost
+    /// frame first.
+    pub fn stack_trace(&self) -> &[TraceFrame] {
+        &self.trace
+    }
+
+    /// The status the last run passed to `exit`, or `None` if it ran to
+    /// the end or stopped with an error.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Compiles resolved statements into a function for the top-level
+    /// script. With `echo`, top-level expression statements print their
+    /// value, the way the REPL shows results.
+    pub fn compile(&mut self, statements: &[Stmt], echo: bool) -> Result<ObjRef, Vec<Diagnostic>> {
+        let result = compiler::compile(statements, self, echo);
+        self.compiler_roots.clear();
+        result
+    }
+
+    /// Loads a script saved by `loxc::write`, ready for `interpret`.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<ObjRef, LoadError> {
+        let result = loxc::read(self, bytes);
+        self.compiler_roots.clear();
+        result
+    }
+
+    /// Runs a script compiled by `compile` or loaded by `load`.
+    pub fn interpret(&mut self, function: ObjRef) -> RunResult<()> {
+        // Keep the function reachable while the closure is allocated.
+        self.stack.push(Value::obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack[0] = Value::obj(closure);
+        self.push_frame(closure, 0);
+        self.trace.clear();
+        self.steps = 0;
+        self.out_of_memory = false;
+        self.exit_status = None;
+        let mut result = self.run();
+        if let Err(error) = &result {
+            // Calling `exit` unwinds the same way as an error, but is not
+            // one.
+            if self.exit_status.is_some() {
+                result = Ok(());
+            } else {
+                self.trace = self.trace_at(error.token.line);
+            }
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        self.out.flush().ok();
+        result
+    }
+
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name_string = self.intern(name);
+        self.stack.push(Value::obj(name_string));
+        let native = self.alloc(Obj::Native(ObjNative {
+            name: name.to_string(),
+            arity,
+            function,
+        }));
+        self.stack.pop();
+        self.globals.insert(name_string, Value::obj(native));
+    }
+
+    fn run(&mut self) -> RunResult<()> {
+        loop {
+            let start = self.frame().ip;
+            if self.trace_execution {
+                self.trace(start);
+            }
+            if let Some(limit) = self.step_limit {
+                if self.steps == limit {
+                    return Err(self.step_limit_error(start));
+                }
+                self.steps += 1;
+            }
+            if self.out_of_memory {
+                self.out_of_memory = false;
+                let line = self.frame().chunk.line_at(start);
+                return Err(limit_error(line, codes::OUT_OF_MEMORY, "Out of memory."));
+            }
+            let op = OpCode::from_byte(self.read_byte()).expect("Invalid opcode");
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.stack.push(constant);
+                }
+                OpCode::Nil => self.stack.push(Value::NIL),
+                OpCode::True => self.stack.push(Value::bool(true)),
+                OpCode::False => self.stack.push(Value::bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::PopN => {
+                    let count = self.read_byte() as usize;
+                    self.stack.truncate(self.stack.len() - count);
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(self.undefined_variable(start, name)),
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(start, name)),
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[index];
+                    let value = match self.heap.upvalue(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot],
+                        ObjUpvalue::Closed(value) => *value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[index];
+                    let value = self.peek(0);
+                    match self.heap.upvalue_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let cache = self.read_u16() as usize;
+                    let instance = match self.as_instance(self.peek(0)) {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Only instances have properties.".to_string(),
+                            ))
+                        }
+                    };
+                    let value = match self.find_property(instance, name, cache) {
+                        Some(Property::Field(value)) => value,
+                        Some(Property::Method(method)) => self.bind(Value::obj(instance), method),
+                        None => return Err(self.undefined_property(start, name)),
+                    };
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let cache = self.read_u16() as usize;
+                    let instance = match self.as_instance(self.peek(1)) {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Only instances have fields.".to_string(),
+                            ))
+                        }
+                    };
+                    let value = self.pop();
+                    let slot = self.field_slot(instance, name, cache);
+                    self.heap.instance_mut(instance).set_field(slot, value);
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = match self.as_class(self.peek(0)) {
+                        Some(class) => class,
+                        None => return Err(self.invalid_superclass(start)),
+                    };
+                    let receiver = self.peek(1);
+                    let method = self.bind_method(start, superclass, name, receiver)?;
+                    self.pop();
+                    self.pop();
+                    self.stack.push(method);
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    // Strings are interned, so every value compares by identity.
+                    self.stack.push(Value::bool(a == b));
+                }
+                OpCode::Greater => self.compare(start, |a, b| a > b)?,
+                OpCode::GreaterEqual => self.compare(start, |a, b| a >= b)?,
+                OpCode::Less => self.compare(start, |a, b| a < b)?,
+                OpCode::LessEqual => self.compare(start, |a, b| a <= b)?,
+                OpCode::Add => {
+                    let b = self.peek(0);
+                    let a = self.peek(1);
+                    let result = match (a.kind(), b.kind()) {
+                        (ValueKind::Number(a), ValueKind::Number(b)) => Value::number(a + b),
+                        _ => match (self.heap.as_string(a), self.heap.as_string(b)) {
+                            (Some(a), Some(b)) => {
+                                let joined = format!("{}{}", a, b);
+                                // Both operands stay on the stack until the
+                                // result is allocated.
+                                Value::obj(self.intern(&joined))
+                            }
+                            _ => {
+                                return Err(self.error(
+                                    start,
+                                    codes::TYPE_ERROR,
+                                    "Operands must be two numbers or two strings.".to_string(),
+                                ))
+                            }
+                        },
+                    };
+                    self.pop();
+                    self.pop();
+                    self.stack.push(result);
+                }
+                OpCode::Subtract => self.arithmetic(start, |a, b| a - b)?,
+                OpCode::Multiply => self.arithmetic(start, |a, b| a * b)?,
+                OpCode::Divide => self.arithmetic(start, |a, b| a / b)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::bool(value.is_falsey()));
+                }
+                OpCode::Negate => match self.peek(0).kind() {
+                    ValueKind::Number(n) => {
+                        self.pop();
+                        self.stack.push(Value::number(-n));
+                    }
+                    _ => {
+                        return Err(self.error(
+                            start,
+                            codes::TYPE_ERROR,
+                            "Operand must be a number.".to_string(),
+                        ))
+                    }
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    let text = self.heap.format_value(value);
+                    writeln!(self.out, "{}", text).expect("Unable to write output");
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(start, arg_count)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let cache = self.read_u16() as usize;
+                    let instance = match self.as_instance(self.peek(arg_count)) {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Only instances have properties.".to_string(),
+                            ))
+                        }
+                    };
+                    // The call itself reports errors at the parenthesis,
+                    // recorded one byte after the name.
+                    match self.find_property(instance, name, cache) {
+                        Some(Property::Field(value)) => {
+                            let callee_slot = self.stack.len() - arg_count - 1;
+                            self.stack[callee_slot] = value;
+                            self.call_value(start + 1, arg_count)?;
+                        }
+                        Some(Property::Method(method)) => {
+                            self.call(start + 1, method, arg_count)?
+                        }
+                        None => return Err(self.undefined_property(start, name)),
+                    }
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.pop();
+                    let superclass = match self.as_class(superclass) {
+                        Some(class) => class,
+                        None => return Err(self.invalid_superclass(start)),
+                    };
+                    match self.heap.class(superclass).methods.get(&name).copied() {
+                        Some(method) => self.call(start + 1, method, arg_count)?,
+                        None => return Err(self.undefined_property(start, name)),
+                    }
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant().kind() {
+                        ValueKind::Obj(function) => function,
+                        _ => unreachable!("Closure operand is always a function"),
+                    };
+                    let count = self.heap.function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.heap.closure(self.frame().closure).upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(Obj::Closure(ObjClosure { function, upvalues }));
+                    self.stack.push(Value::obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("No frame to return from");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let name = self.heap.string(name).to_string();
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.stack.push(Value::obj(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.as_class(self.peek(1)) {
+                        Some(class) => class,
+                        None => return Err(self.invalid_superclass(start)),
+                    };
+                    let subclass = self.pop();
+                    let subclass = match self.as_class(subclass) {
+                        Some(class) => class,
+                        None => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Only classes can inherit.".to_string(),
+                            ))
+                        }
+                    };
+                    let methods = self.heap.class(superclass).methods.clone();
+                    self.heap.class_mut(subclass).methods.extend(methods);
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let method = match self.pop().kind() {
+                        ValueKind::Obj(closure)
+                            if matches!(self.heap.get(closure), Obj::Closure(_)) =>
+                        {
+                            closure
+                        }
+                        _ => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Methods must be functions.".to_string(),
+                            ))
+                        }
+                    };
+                    let class = match self.as_class(self.peek(0)) {
+                        Some(class) => class,
+                        None => {
+                            return Err(self.error(
+                                start,
+                                codes::TYPE_ERROR,
+                                "Only classes have methods.".to_string(),
+                            ))
+                        }
+                    };
+                    self.heap.class_mut(class).methods.insert(name, method);
+                }
+            }
+        }
+    }
+
+    fn trace(&mut self, offset: usize) {
+        let mut line = disassembler::format_stack(&self.heap, &self.stack);
+        line.push('\n');
+        disassembler::disassemble_instruction(&self.heap, &self.frame().chunk, offset, &mut line);
+        write!(self.out, "{}", line).expect("Unable to write output");
+    }
+
+    // Allocates `obj`, collecting garbage first if the heap is due for it.
+    // Anything the caller still needs must be reachable from a root. An
+    // allocation past the memory limit still succeeds, and the script
+    // stops before its next instruction.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        let due = self.memory_limit.is_some()
+            && self.heap.bytes_allocated() + size > self.next_measurement;
+        if self.heap.should_collect() || due {
+            self.collect_garbage();
+            if let Some(limit) = self.memory_limit {
+                let live = self.heap.bytes_allocated() + size;
+                self.next_measurement = memory::next_measurement(live, limit);
+                self.out_of_memory |= live > limit;
+            }
+        }
+        self.heap.alloc(obj)
+    }
+
+    // Returns the string object with contents `s`, allocating it if no
+    // such string exists yet.
+    fn intern(&mut self, s: &str) -> ObjRef {
+        let hash = object::hash_string(s);
+        match self.heap.find_string(s, hash) {
+            Some(string) => string,
+            None => self.alloc(Obj::String(ObjString {
+                chars: s.into(),
+                hash,
+            })),
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.mark_object(self.init_string);
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        for obj in &self.compiler_roots {
+            self.heap.mark_object(*obj);
+        }
+        self.heap.trace_references();
+        self.heap.sweep();
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No frame is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No frame is running")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_u16() as usize;
+        self.frame().chunk.constants[index]
+    }
+
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant().kind() {
+            ValueKind::Obj(string) => string,
+            _ => unreachable!("Name operands are always strings"),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    // Code compiled from source only finds classes where it expects them,
+    // but code loaded from a crafted file could find anything.
+    fn as_class(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn numbers(&self, start: usize) -> RunResult<(f64, f64)> {
+        match (self.peek(1).kind(), self.peek(0).kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => Ok((a, b)),
+            _ => Err(self.error(
+                start,
+                codes::TYPE_ERROR,
+                "Operands must be numbers.".to_string(),
+            )),
+        }
+    }
+
+    fn arithmetic(&mut self, start: usize, op: fn(f64, f64) -> f64) -> RunResult<()> {
+        let (a, b) = self.numbers(start)?;
+        self.pop();
+        self.pop();
+        self.stack.push(Value::number(op(a, b)));
+        Ok(())
+    }
+
+    fn compare(&mut self, start: usize, op: fn(f64, f64) -> bool) -> RunResult<()> {
+        let (a, b) = self.numbers(start)?;
+        self.pop();
+        self.pop();
+        self.stack.push(Value::bool(op(a, b)));
+        Ok(())
+    }
+
+    // Calls the value below the `arg_count` arguments on top of the stack.
+    fn call_value(&mut self, start: usize, arg_count: usize) -> RunResult<()> {
+        let callee_slot = self.stack.len() - arg_count - 1;
+        let callee = match self.stack[callee_slot].kind() {
+            ValueKind::Obj(obj) => obj,
+            _ => return Err(self.not_callable(start)),
+        };
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(start, callee, arg_count),
+            Obj::Native(native) => {
+                let (arity, function) = (native.arity, native.function);
+                self.check_arity(start, arity, arg_count)?;
+                let arguments = self.stack[callee_slot + 1..].to_vec();
+                match function(&mut self.heap, &arguments) {
+                    Ok(result) => {
+                        self.stack.truncate(callee_slot);
+                        self.stack.push(result);
+                        Ok(())
+                    }
+                    Err(NativeError::Message(message)) => {
+                        Err(self.error(start, codes::TYPE_ERROR, message))
+                    }
+                    Err(NativeError::Exit(status)) => {
+                        self.exit_status = Some(status);
+                        Err(RuntimeError::exit())
+                    }
+                    Err(NativeError::Import(path)) => self.import(start, callee_slot, path),
+                }
+            }
+            Obj::Class(class) => match class.methods.get(&self.init_string).copied() {
+                Some(initializer) => {
+                    self.check_arity(start, self.closure_arity(initializer), arg_count)?;
+                    self.stack[callee_slot] = self.new_instance(callee);
+                    self.call(start, initializer, arg_count)
+                }
+                None => {
+                    self.check_arity(start, 0, arg_count)?;
+                    self.stack[callee_slot] = self.new_instance(callee);
+                    Ok(())
+                }
+            },
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                self.stack[callee_slot] = receiver;
+                self.call(start, method, arg_count)
+            }
+            _ => Err(self.not_callable(start)),
+        }
+    }
+
+    // Replaces the call to `import` at `callee_slot` with a call to the
+    // module at `path`, compiled as a script, unless it has run already.
+    fn import(&mut self, start: usize, callee_slot: usize, path: String) -> RunResult<()> {
+        self.stack.truncate(callee_slot);
+        if self.imported.contains(&path) {
+            self.stack.push(Value::NIL);
+            return Ok(());
+        }
+        let (statements, _) = sandbox::load_module(&path)
+            .map_err(|message| self.error(start, codes::IMPORT_FAILED, message))?;
+        let file = self.file.replace(path.as_str().into());
+        let compiled = self.compile(&statements, false);
+        self.file = file;
+        let function = compiled.map_err(|errors| {
+            let message = sandbox::module_error(&path, &errors[0]);
+            self.error(start, codes::IMPORT_FAILED, message)
+        })?;
+        self.imported.insert(path);
+        // Keep the function reachable while the closure is allocated.
+        self.stack.push(Value::obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack[callee_slot] = Value::obj(closure);
+        self.call(start, closure, 0)
+    }
+
+    fn call(&mut self, start: usize, closure: ObjRef, arg_count: usize) -> RunResult<()> {
+        self.check_arity(start, self.closure_arity(closure), arg_count)?;
+        // A call whose value the caller returns straight away takes over
+        // the caller's frame, so tail recursion runs in constant space.
+        let frame = self.frame();
+        if frame.chunk.code.get(frame.ip) == Some(&(OpCode::Return as u8)) {
+            let slots = frame.slots;
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.close_upvalues(slots);
+            self.stack.drain(slots..callee_slot);
+            self.frames.pop();
+            self.push_frame(closure, slots);
+            return Ok(());
+        }
+        if self.frames.len() > self.max_depth {
+            return Err(self.error(start, codes::STACK_OVERFLOW, "Stack overflow.".to_string()));
+        }
+        self.push_frame(closure, self.stack.len() - arg_count - 1);
+        Ok(())
+    }
+
+    fn push_frame(&mut self, closure: ObjRef, slots: usize) {
+        let function = self.heap.closure(closure).function;
+        self.frames.push(CallFrame {
+            closure,
+            chunk: self.heap.function(function).chunk.clone(),
+            ip: 0,
+            slots,
+        });
+    }
+
+    fn closure_arity(&self, closure: ObjRef) -> usize {
+        self.heap
+            .function(self.heap.closure(closure).function)
+            .arity
+    }
+
+    fn new_instance(&mut self, class: ObjRef) -> Value {
+        Value::obj(self.alloc(Obj::Instance(ObjInstance::new(class))))
+    }
+
+    // Looks `name` up among the methods of `class` and binds it to
+    // `receiver`.
+    fn bind_method(
+        &mut self,
+        start: usize,
+        class: ObjRef,
+        name: ObjRef,
+        receiver: Value,
+    ) -> RunResult<Value> {
+        match self.heap.class(class).methods.get(&name).copied() {
+            Some(method) => Ok(self.bind(receiver, method)),
+            None => Err(self.undefined_property(start, name)),
+        }
+    }
+
+    fn bind(&mut self, receiver: Value, method: ObjRef) -> Value {
+        Value::obj(self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method })))
+    }
+
+    // Finds property `name` of `instance`: a field, or else a method of its
+    // class. Tries what inline cache `cache` of the running chunk
+    // remembers first, and updates it after a full lookup.
+    fn find_property(&self, instance: ObjRef, name: ObjRef, cache: usize) -> Option<Property> {
+        let object = self.heap.instance(instance);
+        let class = self.heap.class(object.class);
+        let cache = &self.frame().chunk.caches[cache];
+        match cache.get() {
+            InlineCache::Field {
+                class: cached,
+                slot,
+            } if cached == object.class => {
+                if let Some(value) = object.field(slot) {
+                    return Some(Property::Field(value));
+                }
+            }
+            InlineCache::Method {
+                class: cached,
+                method,
+                layout,
+            } if cached == object.class && layout == class.fields.len() => {
+                return Some(Property::Method(method));
+            }
+            _ => {}
+        }
+
+        let slot = class.fields.get(&name).copied();
+        if let Some(slot) = slot {
+            if let Some(value) = object.field(slot) {
+                cache.set(InlineCache::Field {
+                    class: object.class,
+                    slot,
+                });
+                return Some(Property::Field(value));
+            }
+        }
+        let method = class.methods.get(&name).copied()?;
+        // Only instances without the field can skip looking for it.
+        if slot.is_none() {
+            cache.set(InlineCache::Method {
+                class: object.class,
+                method,
+                layout: class.fields.len(),
+            });
+        }
+        Some(Property::Method(method))
+    }
+
+    // The slot for field `name` in `instance`, giving the name a slot in
+    // the class layout if it has none yet.
+    fn field_slot(&mut self, instance: ObjRef, name: ObjRef, cache: usize) -> usize {
+        let class = self.heap.instance(instance).class;
+        if let InlineCache::Field {
+            class: cached,
+            slot,
+        } = self.frame().chunk.caches[cache].get()
+        {
+            if cached == class {
+                return slot;
+            }
+        }
+        let fields = &mut self.heap.class_mut(class).fields;
+        let next = fields.len();
+        let slot = *fields.entry(name).or_insert(next);
+        self.frame().chunk.caches[cache].set(InlineCache::Field { class, slot });
+        slot
+    }
+
+    fn invalid_superclass(&self, start: usize) -> RuntimeError {
+        self.error(
+            start,
+            codes::INVALID_SUPERCLASS,
+            "Superclass must be a class.".to_string(),
+        )
+    }
+
+    fn undefined_property(&self, start: usize, name: ObjRef) -> RuntimeError {
+        self.error(
+            start,
+            codes::UNDEFINED_PROPERTY,
+            format!("Undefined property '{}'.", self.heap.string(name)),
+        )
+    }
+
+    // Returns the upvalue for the local in stack slot `slot`, creating it
+    // unless an open one exists already, so closures share variables.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let heap = &self.heap;
+        let position = self.open_upvalues.partition_point(
+            |upvalue| matches!(heap.upvalue(*upvalue), ObjUpvalue::Open(open) if *open < slot),
+        );
+        if let Some(&existing) = self.open_upvalues.get(position) {
+            if matches!(self.heap.upvalue(existing), ObjUpvalue::Open(open) if *open == slot) {
+                return existing;
+            }
+        }
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    // Closes every open upvalue for stack slot `last` or above.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = match self.heap.upvalue(upvalue) {
+                ObjUpvalue::Open(slot) if *slot >= last => *slot,
+                _ => break,
+            };
+            *self.heap.upvalue_mut(upvalue) = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn check_arity(&self, start: usize, expected: usize, got: usize) -> RunResult<()> {
+        if expected != got {
+            return Err(self.error(
+                start,
+                codes::ARITY_MISMATCH,
+                format!("Expected {} arguments but got {}.", expected, got),
+            ));
+        }
+        Ok(())
+    }
+
+    fn not_callable(&self, start: usize) -> RuntimeError {
+        self.error(
+            start,
+            codes::NOT_CALLABLE,
+            "Can only call functions and classes.".to_string(),
+        )
+    }
+
+    fn undefined_variable(&self, start: usize, name: ObjRef) -> RuntimeError {
+        self.error(
+            start,
+            codes::UNDEFINED_VARIABLE,
+            format!("Undefined variable '{}'.", self.heap.string(name)),
+        )
+    }
+
+    // The calls in progress, innermost first, for an error raised at `line`.
+    fn trace_at(&self, line: usize) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let function = self
+                    .heap
+                    .function(self.heap.closure(frame.closure).function);
+                TraceFrame {
+                    function: function.name.clone(),
+                    file: function.file.clone(),
+                    // Callers are at the call instruction just before ip.
+                    line: if depth == 0 {
+                        line
+                    } else {
+                        frame.chunk.line_at(frame.ip - 1)
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn step_limit_error(&self, start: usize) -> RuntimeError {
+        let line = self.frame().chunk.line_at(start);
+        limit_error(line, codes::STEP_LIMIT, "Step limit exceeded.")
+    }
+
+    // An error raised by the instruction at `start` in the running frame,
+    // reported against the token it was compiled from.
+    fn error(&self, start: usize, code: &'static str, message: String) -> RuntimeError {
+        let chunk = &self.frame().chunk;
+        let token = match chunk.token_at(start) {
+            Some(token) => token.clone(),
+            None => Token::new(TokenType::Eof, String::new(), None, chunk.line_at(start)),
+        };
+        RuntimeError::new(code, &token, message)
+    }
+}
+
+// Running out of steps or memory is not the fault of any one token, so the
+// error has only a line.
+fn limit_error(line: usize, code: &'static str, message: &str) -> RuntimeError {
+    let token = Token::new(TokenType::Eof, String::new(), None, line);
+    RuntimeError::new(code, &token, message.to_string())
+}
+
+// The native functions, with the capability each needs.
+const NATIVES: &[(Capability, &str, usize, NativeFn)] = &[
+    (Capability::Clock, "clock", 0, clock),
+    (Capability::FileIo, "readFile", 1, read_file),
+    (Capability::FileIo, "writeFile", 2, write_file),
+    (Capability::EnvVars, "getenv", 1, getenv),
+    (Capability::ProcessExit, "exit", 1, exit),
+    (Capability::Modules, "import", 1, import),
+];
+
+fn clock(_heap: &mut Heap, _arguments: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::number(sandbox::seconds_since_epoch()?))
+}
+
+fn read_file(heap: &mut Heap, arguments: &[Value]) -> Result<Value, NativeError> {
+    let contents = sandbox::read_file(string_argument(heap, arguments[0], "Path")?)?;
+    Ok(Value::obj(heap.intern(&contents)))
+}
+
+fn write_file(heap: &mut Heap, arguments: &[Value]) -> Result<Value, NativeError> {
+    let path = string_argument(heap, arguments[0], "Path")?;
+    sandbox::write_file(path, string_argument(heap, arguments[1], "Contents")?)?;
+    Ok(Value::NIL)
+}
+
+fn getenv(heap: &mut Heap, arguments: &[Value]) -> Result<Value, NativeError> {
+    let name = string_argument(heap, arguments[0], "Name")?;
+    Ok(match sandbox::env_var(name) {
+        Some(value) => Value::obj(heap.intern(&value)),
+        None => Value::NIL,
+    })
+}
+
+fn exit(_heap: &mut Heap, arguments: &[Value]) -> Result<Value, NativeError> {
+    match arguments[0].as_number() {
+        Some(code) => Err(sandbox::exit(code)),
+        None => Err("Exit code must be a number.".to_string().into()),
+    }
+}
+
+fn import(heap: &mut Heap, arguments: &[Value]) -> Result<Value, NativeError> {
+    let path = string_argument(heap, arguments[0], "Path")?;
+    Err(NativeError::Import(path.to_string()))
+}
+
+fn string_argument<'a>(heap: &'a Heap, value: Value, what: &str) -> Result<&'a str, String> {
+    heap.as_string(value)
+        .ok_or_else(|| format!("{} must be a string.", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use std::cell::{Cell, RefCell};
+    use std::io;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(vm: &mut Vm, source: &str) -> (RunResult<()>, String) {
+        let buffer = SharedBuffer::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let tokens = Scanner::new(source.to_string()).scan_tokens().clone();
+        let statements = Parser::new(tokens).parse();
+        let mut resolver = Resolver::new();
+        resolver.resolve(&statements);
+        assert!(resolver.errors().is_empty());
+        let function = vm.compile(&statements, false).expect("Compile error");
+        let result = vm.interpret(function);
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn test_arithmetic_and_strings() {
+        let (result, output) = run(
+            &mut Vm::new(),
+            "print 1 + 2 * 3; print \"a\" + \"b\"; print !nil; print 1 != 2;",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "7\nab\ntrue\ntrue\n");
+    }
+
+    #[test]
+    fn test_closures_share_variables() {
+        let source = "
+            fun pair() {
+              var n = 0;
+              fun inc() { n = n + 1; }
+              fun get() { return n; }
+              inc();
+              return get;
+            }
+            print pair()();
+            var fs;
+            { var x = 1; fun f() { return x; } x = 2; fs = f; }
+            print fs();
+        ";
+        let (result, output) = run(&mut Vm::new(), source);
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\n");
+    }
+
+    #[test]
+    fn test_closures_share_one_closed_upvalue() {
+        let mut vm = Vm::new();
+        let source = "
+            var f;
+            var g;
+            {
+              var shared = 1;
+              fun a() { return shared; }
+              fun b() { shared = shared + 1; }
+              f = a;
+              g = b;
+            }
+        ";
+        let (result, _) = run(&mut vm, source);
+        assert!(result.is_ok());
+        assert!(vm.open_upvalues.is_empty());
+
+        let closure = |vm: &mut Vm, name: &str| match vm.globals[&vm.heap.intern(name)].kind() {
+            ValueKind::Obj(closure) => closure,
+            _ => panic!("Expected a closure"),
+        };
+        let f = closure(&mut vm, "f");
+        let g = closure(&mut vm, "g");
+        let upvalue = vm.heap.closure(f).upvalues[0];
+        assert_eq!(vm.heap.closure(g).upvalues, vec![upvalue]);
+        assert!(matches!(
+            vm.heap.upvalue(upvalue),
+            ObjUpvalue::Closed(value) if value.as_number() == Some(1.0)
+        ));
+    }
+
+    #[test]
+    fn test_classes() {
+        let source = "
+            class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { return super.get() * 10; } }
+            print B(4).get();
+            print B;
+            print B(1);
+        ";
+        let (result, output) = run(&mut Vm::new(), source);
+        assert!(result.is_ok());
+        assert_eq!(output, "40\nB\nB instance\n");
+    }
+
+    #[test]
+    fn test_inline_caches_remember_fields_and_methods() {
+        let source = "
+            class Point {
+              init(x) { this.x = x; }
+              norm() { return this.x; }
+            }
+            fun measure(p) { return p.x + p.norm(); }
+            measure(Point(1));
+            print measure(Point(2));
+        ";
+        let mut vm = Vm::new();
+        let (result, output) = run(&mut vm, source);
+        assert!(result.is_ok());
+        assert_eq!(output, "4\n");
+
+        let find = |vm: &mut Vm, name: &str| {
+            let name = vm.heap.intern(name);
+            vm.globals[&name].as_obj().unwrap()
+        };
+        let class = find(&mut vm, "Point");
+        let measure = find(&mut vm, "measure");
+        let function = vm.heap.closure(measure).function;
+        let caches: Vec<_> = vm
+            .heap
+            .function(function)
+            .chunk
+            .caches
+            .iter()
+            .map(Cell::get)
+            .collect();
+        let norm = vm.heap.intern("norm");
+        let norm = vm.heap.class(class).methods[&norm];
+        assert_eq!(
+            caches,
+            vec![
+                InlineCache::Field { class, slot: 0 },
+                InlineCache::Method {
+                    class,
+                    method: norm,
+                    layout: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invoke_errors_point_at_name_or_parenthesis() {
+        let mut vm = Vm::new();
+        let (result, _) = run(&mut vm, "class A { m(a) {} }\nA().m(\n);");
+        let error = result.unwrap_err();
+        assert_eq!(error.code, codes::ARITY_MISMATCH);
+        assert_eq!((error.token.lexeme.as_str(), error.token.line), (")", 3));
+
+        let (result, _) = run(&mut vm, "A().n();");
+        let error = result.unwrap_err();
+        assert_eq!(error.code, codes::UNDEFINED_PROPERTY);
+        assert_eq!(error.token.lexeme, "n");
+    }
+
+    #[test]
+    fn test_runtime_error_points_at_token() {
+        let mut vm = Vm::new();
+        let (result, _) = run(&mut vm, "var a = 1;\nprint a + nil;");
+        let error = result.unwrap_err();
+        assert_eq!(error.code, codes::TYPE_ERROR);
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!(error.token.lexeme, "+");
+        assert_eq!(error.token.line, 2);
+
+        // Globals survive the error and the VM can run again.
+        let (result, output) = run(&mut vm, "print a;");
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n");
+    }
+
+    #[test]
+    fn test_stress_gc_keeps_reachable_objects() {
+        let mut vm = Vm::new();
+        vm.set_stress_gc(true);
+        let source = "
+            class Pair { init(a, b) { this.a = a; this.b = b; } }
+            fun make(n) {
+              var name = \"item \" + \"one\";
+              fun describe() { return name; }
+              return Pair(describe, n);
+            }
+            var p = make(1);
+            print p.a() + \"!\";
+            print p.b;
+        ";
+        let (result, output) = run(&mut vm, source);
+        assert!(result.is_ok());
+        assert_eq!(output, "item one!\n1\n");
+    }
+
+    #[test]
+    fn test_collects_unreachable_cycles() {
+        let mut vm = Vm::new();
+        // The class keeps the names of its fields in its layout for good.
+        let (result, _) = run(&mut vm, "class A {}\nA().a = A().b = A().f = nil;");
+        assert!(result.is_ok());
+        vm.collect_garbage();
+        let live = vm.heap.len();
+        let bytes = vm.heap.bytes_allocated();
+
+        let source = "
+            for (var i = 0; i < 100; i = i + 1) {
+              var a = A();
+              var b = A();
+              a.b = b;
+              b.a = a;
+              fun f() { return a; }
+              a.f = f;
+            }
+        ";
+        let (result, _) = run(&mut vm, source);
+        assert!(result.is_ok());
+        assert!(vm.heap.len() > live);
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), live);
+        assert_eq!(vm.heap.bytes_allocated(), bytes);
+    }
+
+    #[test]
+    fn test_equal_strings_are_one_object() {
+        let mut vm = Vm::new();
+        let (result, output) = run(
+            &mut vm,
+            "var a = \"x\" + \"y\"; var b = \"xy\"; print a == b;",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\n");
+        let a = vm.globals[&vm.heap.intern("a")];
+        let b = vm.globals[&vm.heap.intern("b")];
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let (result, _) = run(&mut Vm::new(), "fun f() { f(); } f();");
+        assert_eq!(result.unwrap_err().message, "Stack overflow.");
+    }
+
+    #[test]
+    fn test_tail_calls_reuse_the_frame() {
+        let mut vm = Vm::new();
+        let (result, output) = run(
+            &mut vm,
+            "fun down(n) { if (n == 0) return \"done\"; return down(n - 1); }\n\
+             print down(5000);\n\
+             fun last(n, get) {\n\
+               var x = n;\n\
+               fun next() { return x; }\n\
+               if (n == 0) return get();\n\
+               return last(n - 1, next);\n\
+             }\n\
+             print last(5000, nil);",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "done\n1\n");
+
+        // Only calls in tail position.
+        let (result, _) = run(&mut vm, "fun f(n) { return 1 + f(n); } f(1);");
+        assert_eq!(result.unwrap_err().message, "Stack overflow.");
+    }
+}
@@ -0,0 +1,613 @@
+use crate::sandbox::NativeError;
+use crate::vm::chunk::{Chunk, InlineCache};
+use crate::vm::value::{Value, ValueKind};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem;
+use std::rc::Rc;
+
+/// A handle to an object on the `Heap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+impl ObjRef {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// The handle as a number, for packing into other representations.
+    pub fn to_raw(self) -> u32 {
+        self.0
+    }
+
+    pub fn from_raw(raw: u32) -> ObjRef {
+        ObjRef(raw)
+    }
+}
+
+/// Hashes keys that are already well distributed, such as string hashes
+/// and interned string handles, by using them as they are.
+#[derive(Default)]
+pub struct PrecomputedHasher(u64);
+
+impl Hasher for PrecomputedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(*byte);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = u64::from(n);
+    }
+}
+
+/// A hash table keyed by interned strings. Equal names are the same
+/// object, so keys compare and hash by handle.
+pub type Table<V> = HashMap<ObjRef, V, BuildHasherDefault<PrecomputedHasher>>;
+
+/// The FNV-1a hash of `s`, computed once when a string is interned.
+pub fn hash_string(s: &str) -> u32 {
+    let mut hash: u32 = 2_166_136_261;
+    for byte in s.bytes() {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(16_777_619);
+    }
+    hash
+}
+
+/// An interned string: no two live string objects have the same contents.
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: Box<str>,
+    pub hash: u32,
+}
+
+/// A function implemented in Rust. It may allocate its result on the heap.
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, NativeError>;
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    /// Empty for the top-level script.
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Rc<Chunk>,
+    /// The file the function was compiled or loaded from, if any.
+    pub file: Option<Rc<str>>,
+}
+
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    /// The variable is still live in this absolute stack slot.
+    Open(usize),
+    /// The variable has gone out of scope and its value moved here.
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: String,
+    pub methods: Table<ObjRef>,
+    /// The slot of every field name any instance of the class has had.
+    /// Slots are handed out as fields are first set and never change.
+    pub fields: Table<usize>,
+}
+
+impl ObjClass {
+    pub fn new(name: String) -> Self {
+        ObjClass {
+            name,
+            methods: Table::default(),
+            fields: Table::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    /// Indexed by the slots of the class; `None` for fields this instance
+    /// has not set.
+    pub fields: Vec<Option<Value>>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        ObjInstance {
+            class,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(&self, slot: usize) -> Option<Value> {
+        self.fields.get(slot).copied().flatten()
+    }
+
+    pub fn set_field(&mut self, slot: usize, value: Value) {
+        if slot >= self.fields.len() {
+            self.fields.resize(slot + 1, None);
+        }
+        self.fields[slot] = Some(value);
+    }
+}
+
+/// A method closure together with the instance it was accessed on.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+impl Obj {
+    /// An estimate of the memory the object holds, for deciding when to
+    /// collect garbage and for holding scripts to a memory limit.
+    pub fn size(&self) -> usize {
+        let payload = match self {
+            Obj::String(s) => s.chars.len(),
+            Obj::Function(function) => {
+                function.name.len()
+                    + function.chunk.code.len()
+                    + function.chunk.constants.len() * mem::size_of::<Value>()
+            }
+            Obj::Native(native) => native.name.len(),
+            Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+            Obj::Class(class) => {
+                class.name.len()
+                    + class.methods.len() * mem::size_of::<(ObjRef, ObjRef)>()
+                    + class.fields.len() * mem::size_of::<(ObjRef, usize)>()
+            }
+            Obj::Instance(instance) => instance.fields.len() * mem::size_of::<Option<Value>>(),
+        };
+        mem::size_of::<Obj>() + payload
+    }
+
+    // Every object this one refers to directly.
+    fn references(&self) -> Vec<ObjRef> {
+        let value_refs = |values: &mut dyn Iterator<Item = &Value>| -> Vec<ObjRef> {
+            values.filter_map(|value| value.as_obj()).collect()
+        };
+        match self {
+            Obj::String(_) | Obj::Native(_) => Vec::new(),
+            Obj::Function(function) => {
+                let mut refs = value_refs(&mut function.chunk.constants.iter());
+                // Keep cached classes alive, so a new class can never reuse
+                // the handle of one a cache remembers.
+                for cache in &function.chunk.caches {
+                    match cache.get() {
+                        InlineCache::Empty => {}
+                        InlineCache::Field { class, .. } => refs.push(class),
+                        InlineCache::Method { class, method, .. } => refs.extend(&[class, method]),
+                    }
+                }
+                refs
+            }
+            Obj::Closure(closure) => {
+                let mut refs = closure.upvalues.clone();
+                refs.push(closure.function);
+                refs
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => value_refs(&mut std::iter::once(value)),
+            // An open upvalue's variable is on the stack, which is a root.
+            Obj::Upvalue(ObjUpvalue::Open(_)) => Vec::new(),
+            Obj::Class(class) => class
+                .methods
+                .iter()
+                .flat_map(|(name, method)| vec![*name, *method])
+                .chain(class.fields.keys().copied())
+                .collect(),
+            Obj::Instance(instance) => {
+                let mut refs = value_refs(&mut instance.fields.iter().flatten());
+                refs.push(instance.class);
+                refs
+            }
+            Obj::BoundMethod(bound) => {
+                let mut refs = value_refs(&mut std::iter::once(&bound.receiver));
+                refs.push(bound.method);
+                refs
+            }
+        }
+    }
+}
+
+// A slot of the heap. Freed slots are reused by later allocations.
+struct Slot {
+    obj: Option<Obj>,
+    size: usize,
+    marked: bool,
+}
+
+/// The heap size that triggers the first collection.
+pub const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+/// After a collection, the next one happens once the heap has grown by
+/// this factor.
+pub const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Storage for every object the VM allocates, reclaimed by a mark-sweep
+/// collector.
+///
+/// Strings are interned in a table that does not keep them alive: a string
+/// only reachable from the table is freed and removed from it.
+///
+/// The heap does not know the roots, so it never collects on its own: the
+/// VM asks `should_collect` before allocating, marks its roots and then
+/// calls `trace_references` and `sweep`.
+pub struct Heap {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    // Every live string, bucketed by hash.
+    strings: HashMap<u32, Vec<ObjRef>, BuildHasherDefault<PrecomputedHasher>>,
+    // Marked objects whose references have not been marked yet.
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress_gc: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::default(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress_gc: false,
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    /// Moves `obj` to the heap. Strings must not be allocated this way
+    /// unless `find_string` has no match for them.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+        let hash = match &obj {
+            Obj::String(s) => Some(s.hash),
+            _ => None,
+        };
+        let slot = Slot {
+            obj: Some(obj),
+            size,
+            marked: false,
+        };
+        let obj = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = slot;
+                ObjRef(index)
+            }
+            None => {
+                self.slots.push(slot);
+                ObjRef((self.slots.len() - 1) as u32)
+            }
+        };
+        if let Some(hash) = hash {
+            self.strings.entry(hash).or_default().push(obj);
+        }
+        obj
+    }
+
+    /// The interned string with contents `s` and hash `hash`, if any.
+    pub fn find_string(&self, s: &str, hash: u32) -> Option<ObjRef> {
+        self.strings
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|obj| self.string(*obj) == s)
+    }
+
+    /// Returns the string object with contents `s`, allocating it if there
+    /// is none yet. Never collects; the VM has its own `intern` that does.
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        let hash = hash_string(s);
+        match self.find_string(s, hash) {
+            Some(obj) => obj,
+            None => self.alloc(Obj::String(ObjString {
+                chars: s.into(),
+                hash,
+            })),
+        }
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.slots[obj.index()]
+            .obj
+            .as_ref()
+            .expect("Use of a collected object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.slots[obj.index()]
+            .obj
+            .as_mut()
+            .expect("Use of a collected object")
+    }
+
+    /// The number of live objects.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The estimated size of the live objects, in bytes.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Collects on every allocation, to flush out missing roots.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+
+    /// Whether the heap has grown enough to be worth a collection.
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    /// Marks `obj` as reachable, to have its references traced later.
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        let slot = &mut self.slots[obj.index()];
+        if !slot.marked {
+            slot.marked = true;
+            self.gray.push(obj);
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    /// Marks everything reachable from the objects marked so far.
+    pub fn trace_references(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            for reference in self.get(obj).references() {
+                self.mark_object(reference);
+            }
+        }
+    }
+
+    /// Frees every object that was not marked and clears the marks of the
+    /// rest. The next collection is due once the heap has grown by
+    /// `GC_HEAP_GROW_FACTOR`.
+    pub fn sweep(&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.marked {
+                slot.marked = false;
+                continue;
+            }
+            match slot.obj.take() {
+                Some(Obj::String(s)) => {
+                    let bucket = self.strings.get_mut(&s.hash).expect("String not interned");
+                    bucket.retain(|obj| obj.index() != index);
+                    if bucket.is_empty() {
+                        self.strings.remove(&s.hash);
+                    }
+                }
+                Some(_) => {}
+                None => continue,
+            }
+            self.bytes_allocated -= slot.size;
+            self.free.push(index as u32);
+        }
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+    }
+
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Obj::String(s) => &s.chars,
+            _ => panic!("Expected a string object"),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            _ => panic!("Expected a function object"),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            _ => panic!("Expected a closure object"),
+        }
+    }
+
+    pub fn upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => panic!("Expected an upvalue object"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => panic!("Expected an upvalue object"),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            _ => panic!("Expected a class object"),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            _ => panic!("Expected a class object"),
+        }
+    }
+
+    pub fn instance(&self, obj: ObjRef) -> &ObjInstance {
+        match self.get(obj) {
+            Obj::Instance(instance) => instance,
+            _ => panic!("Expected an instance object"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, obj: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(obj) {
+            Obj::Instance(instance) => instance,
+            _ => panic!("Expected an instance object"),
+        }
+    }
+
+    /// If `value` is a string, its contents.
+    pub fn as_string(&self, value: Value) -> Option<&str> {
+        match self.get(value.as_obj()?) {
+            Obj::String(s) => Some(&s.chars),
+            _ => None,
+        }
+    }
+
+    /// Formats `value` the way `print` shows it, matching the tree walker.
+    pub fn format_value(&self, value: Value) -> String {
+        match value.kind() {
+            ValueKind::Nil => "nil".to_string(),
+            ValueKind::Bool(b) => b.to_string(),
+            ValueKind::Number(n) => n.to_string(),
+            ValueKind::Obj(obj) => self.format_object(obj),
+        }
+    }
+
+    fn format_object(&self, obj: ObjRef) -> String {
+        match self.get(obj) {
+            Obj::String(s) => s.chars.to_string(),
+            Obj::Function(function) if function.name.is_empty() => "<script>".to_string(),
+            Obj::Function(function) => format!("<fn {}>", function.name),
+            Obj::Native(_) => "<native fn>".to_string(),
+            Obj::Closure(closure) => self.format_object(closure.function),
+            Obj::Upvalue(_) => "upvalue".to_string(),
+            Obj::Class(class) => class.name.clone(),
+            Obj::Instance(instance) => format!("{} instance", self.class(instance.class).name),
+            Obj::BoundMethod(bound) => self.format_object(bound.method),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strings_are_interned() {
+        let mut heap = Heap::new();
+        let a = heap.intern("lox");
+        let b = heap.intern(&format!("l{}", "ox"));
+        let c = heap.intern("other");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.find_string("lox", hash_string("lox")), Some(a));
+        assert_eq!(hash_string(""), 2_166_136_261);
+    }
+
+    #[test]
+    fn test_intern_table_is_weak() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        heap.intern("dropped");
+        heap.mark_object(kept);
+        heap.trace_references();
+        heap.sweep();
+        assert_eq!(heap.find_string("kept", hash_string("kept")), Some(kept));
+        assert_eq!(heap.find_string("dropped", hash_string("dropped")), None);
+
+        // Interning again makes a new object.
+        heap.intern("dropped");
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn test_sweep_frees_unmarked_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        let list = heap.alloc(Obj::Instance(ObjInstance::new(kept)));
+        heap.intern("garbage");
+        assert_eq!(heap.len(), 3);
+        assert!(!heap.should_collect());
+
+        heap.mark_object(list);
+        heap.trace_references();
+        heap.sweep();
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.string(kept), "kept");
+        assert_eq!(
+            heap.bytes_allocated(),
+            heap.get(kept).size() + heap.get(list).size()
+        );
+
+        // The freed slot is reused.
+        let reused = heap.intern("new");
+        assert_eq!(reused.index(), 2);
+        heap.set_stress_gc(true);
+        assert!(heap.should_collect());
+    }
+
+    #[test]
+    fn test_format_value() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(Obj::Class(ObjClass::new("Point".to_string())));
+        let instance = heap.alloc(Obj::Instance(ObjInstance::new(class)));
+        let function = heap.alloc(Obj::Function(ObjFunction {
+            name: "add".to_string(),
+            arity: 2,
+            upvalue_count: 0,
+            chunk: Rc::new(Chunk::new()),
+            file: None,
+        }));
+        let closure = heap.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+
+        assert_eq!(heap.format_value(Value::number(2.5)), "2.5");
+        assert_eq!(heap.format_value(Value::number(3.0)), "3");
+        assert_eq!(heap.format_value(Value::obj(class)), "Point");
+        assert_eq!(heap.format_value(Value::obj(instance)), "Point instance");
+        assert_eq!(heap.format_value(Value::obj(closure)), "<fn add>");
+    }
+}
@@ -0,0 +1,426 @@
+//! Optimizations made while compiling for the VM, unless turned off with
+//! `-O0`.
+//!
+//! The compiler uses `fold` to evaluate expressions made only of literals
+//! ahead of time, and `returns` to stop compiling a block at the statement
+//! that always returns. `peephole` then rewrites each finished chunk: it
+//! threads jumps that land on other jumps, drops unreachable code and jumps
+//! to the next instruction, and merges runs of pops into one instruction.
+
+use crate::ast::{Expr, ExprKind, LiteralValue, Stmt, StmtKind};
+use crate::token::{Token, TokenType};
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::object::Heap;
+
+/// The value `expr` always evaluates to, if it is made of literals and
+/// evaluating it cannot fail. Operands of the wrong type are left for the
+/// runtime error.
+pub fn fold(expr: &Expr) -> Option<LiteralValue> {
+    match &expr.kind {
+        ExprKind::Literal { value } => Some(value.clone()),
+        ExprKind::Grouping { expression } => fold(expression),
+        ExprKind::Unary { operator, right } => match (&operator.token_type, fold(right)?) {
+            (TokenType::Bang, value) => Some(LiteralValue::Bool(!is_truthy(&value))),
+            (TokenType::Minus, LiteralValue::Number(n)) => Some(LiteralValue::Number(-n)),
+            _ => None,
+        },
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+        } => binary(operator, fold(left)?, fold(right)?),
+        _ => None,
+    }
+}
+
+fn binary(operator: &Token, left: LiteralValue, right: LiteralValue) -> Option<LiteralValue> {
+    use LiteralValue::{Bool, Number};
+
+    let value = match (&operator.token_type, left, right) {
+        (TokenType::EqualEqual, left, right) => Bool(left == right),
+        (TokenType::BangEqual, left, right) => Bool(left != right),
+        (TokenType::Plus, LiteralValue::String(a), LiteralValue::String(b)) => {
+            LiteralValue::String(a + &b)
+        }
+        (TokenType::Plus, Number(a), Number(b)) => Number(a + b),
+        (TokenType::Minus, Number(a), Number(b)) => Number(a - b),
+        (TokenType::Star, Number(a), Number(b)) => Number(a * b),
+        (TokenType::Slash, Number(a), Number(b)) => Number(a / b),
+        (TokenType::Greater, Number(a), Number(b)) => Bool(a > b),
+        (TokenType::GreaterEqual, Number(a), Number(b)) => Bool(a >= b),
+        (TokenType::Less, Number(a), Number(b)) => Bool(a < b),
+        (TokenType::LessEqual, Number(a), Number(b)) => Bool(a <= b),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Whether a constant is true in a condition.
+pub fn is_truthy(value: &LiteralValue) -> bool {
+    !matches!(value, LiteralValue::Nil | LiteralValue::Bool(false))
+}
+
+/// Whether running `stmt` always ends in a `return`, so nothing after it
+/// in the same block can run.
+pub fn returns(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Return { .. } => true,
+        StmtKind::Block { statements } => statements.iter().any(returns),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => match fold(condition) {
+            Some(value) if is_truthy(&value) => returns(then_branch),
+            Some(_) => else_branch.as_deref().is_some_and(returns),
+            None => returns(then_branch) && else_branch.as_deref().is_some_and(returns),
+        },
+        _ => false,
+    }
+}
+
+// An instruction taken out of its chunk, so code can be removed without
+// breaking the jumps around it.
+#[derive(Debug)]
+struct Instruction {
+    op: OpCode,
+    // Operand bytes. Jump offsets are worked out again by `encode`.
+    operands: Vec<u8>,
+    // For jumps, the index of the instruction they land on.
+    target: usize,
+    line: usize,
+    // Tokens for runtime errors, by offset from the opcode byte.
+    tokens: Vec<(usize, Token)>,
+}
+
+fn is_jump(op: OpCode) -> bool {
+    matches!(op, OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop)
+}
+
+/// Rewrites the code of a finished chunk without changing what it does.
+/// `heap` holds the functions its closures create. The chunk is left alone
+/// if a rewritten jump would no longer fit its operand.
+pub fn peephole(heap: &Heap, chunk: &mut Chunk) {
+    let mut code = decode(heap, chunk);
+    loop {
+        let len = code.len();
+        thread_jumps(&mut code);
+        let keep = reachable(&code);
+        code = remove(code, &keep);
+        let keep: Vec<bool> = code
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| !is_jump(instruction.op) || instruction.target != index + 1)
+            .collect();
+        code = remove(code, &keep);
+        if code.len() == len {
+            break;
+        }
+    }
+    let code = merge_pops(code);
+    encode(&code, chunk);
+}
+
+fn decode(heap: &Heap, chunk: &Chunk) -> Vec<Instruction> {
+    let lines: Vec<usize> = chunk
+        .lines
+        .iter()
+        .flat_map(|run| std::iter::repeat_n(run.line, run.count))
+        .collect();
+    let mut tokens = chunk.tokens.iter().peekable();
+    let mut starts = Vec::new();
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]).expect("Invalid opcode");
+        let mut end = offset + 1 + op.operand_len();
+        if op == OpCode::Closure {
+            let function = chunk.constants[chunk.read_u16(offset + 1) as usize];
+            let function = function.as_obj().expect("Closure of a non-function");
+            end += 2 * heap.function(function).upvalue_count;
+        }
+        let target = match op {
+            OpCode::Jump | OpCode::JumpIfFalse => offset + 3 + chunk.read_u16(offset + 1) as usize,
+            OpCode::Loop => offset + 3 - chunk.read_u16(offset + 1) as usize,
+            _ => 0,
+        };
+        let mut instruction_tokens = Vec::new();
+        while let Some((start, token)) = tokens.next_if(|(start, _)| *start < end) {
+            instruction_tokens.push((start - offset, token.clone()));
+        }
+        code.push(Instruction {
+            op,
+            operands: chunk.code[offset + 1..end].to_vec(),
+            target,
+            line: lines[offset],
+            tokens: instruction_tokens,
+        });
+        starts.push(offset);
+        offset = end;
+    }
+    for instruction in &mut code {
+        if is_jump(instruction.op) {
+            instruction.target = starts
+                .binary_search(&instruction.target)
+                .expect("Jump into the middle of an instruction");
+        }
+    }
+    code
+}
+
+// Points jumps that land on an unconditional jump at where that one goes.
+// A conditional jump that lands on another conditional jump goes on too,
+// since the value it jumped on is still on the stack and still falsey.
+fn thread_jumps(code: &mut [Instruction]) {
+    for index in 0..code.len() {
+        let op = code[index].op;
+        if !is_jump(op) {
+            continue;
+        }
+        let mut target = code[index].target;
+        // Bounded, since jumps can form a cycle.
+        for _ in 0..code.len() {
+            let next = &code[target];
+            let follow = match op {
+                // Conditional jumps only go forward.
+                OpCode::JumpIfFalse => {
+                    matches!(next.op, OpCode::Jump | OpCode::JumpIfFalse) && next.target > target
+                }
+                _ => matches!(next.op, OpCode::Jump | OpCode::Loop),
+            };
+            if !follow || next.target == target {
+                break;
+            }
+            target = next.target;
+        }
+        code[index].target = target;
+    }
+}
+
+fn reachable(code: &[Instruction]) -> Vec<bool> {
+    let mut seen = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || seen[index] {
+            continue;
+        }
+        seen[index] = true;
+        let instruction = &code[index];
+        match instruction.op {
+            OpCode::Jump | OpCode::Loop => pending.push(instruction.target),
+            OpCode::JumpIfFalse => pending.extend([instruction.target, index + 1]),
+            OpCode::Return => {}
+            _ => pending.push(index + 1),
+        }
+    }
+    seen
+}
+
+// Removes the instructions not marked to keep. Jumps to a removed
+// instruction land on the next one kept instead.
+fn remove(code: Vec<Instruction>, keep: &[bool]) -> Vec<Instruction> {
+    let mut index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for &keep in keep {
+        index.push(kept);
+        kept += keep as usize;
+    }
+    index.push(kept);
+    code.into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| **keep)
+        .map(|(mut instruction, _)| {
+            if is_jump(instruction.op) {
+                instruction.target = index[instruction.target];
+            }
+            instruction
+        })
+        .collect()
+}
+
+// Replaces consecutive pops with a single `PopN`, unless something jumps
+// into the middle of them.
+fn merge_pops(mut code: Vec<Instruction>) -> Vec<Instruction> {
+    let mut targeted = vec![false; code.len()];
+    for instruction in &code {
+        if is_jump(instruction.op) {
+            targeted[instruction.target] = true;
+        }
+    }
+    let mut keep = vec![true; code.len()];
+    let mut start = 0;
+    while start < code.len() {
+        let mut end = start + 1;
+        if code[start].op == OpCode::Pop {
+            while end < code.len()
+                && code[end].op == OpCode::Pop
+                && !targeted[end]
+                && end - start < u8::MAX as usize
+            {
+                keep[end] = false;
+                end += 1;
+            }
+        }
+        if end - start > 1 {
+            code[start].op = OpCode::PopN;
+            code[start].operands = vec![(end - start) as u8];
+        }
+        start = end;
+    }
+    remove(code, &keep)
+}
+
+// Writes `code` back into `chunk`, choosing between forward and backward
+// jumps by where they land.
+fn encode(code: &[Instruction], chunk: &mut Chunk) {
+    let mut offsets = Vec::with_capacity(code.len());
+    let mut offset = 0;
+    for instruction in code {
+        offsets.push(offset);
+        offset += 1 + instruction.operands.len();
+    }
+
+    let mut out = Chunk::new();
+    for instruction in code {
+        let start = out.code.len();
+        let mut op = instruction.op;
+        let mut operands = instruction.operands.clone();
+        if is_jump(op) {
+            let next = start + 3;
+            let target = offsets[instruction.target];
+            let distance = if target >= next {
+                if op == OpCode::Loop {
+                    op = OpCode::Jump;
+                }
+                target - next
+            } else {
+                assert_ne!(op, OpCode::JumpIfFalse, "Conditional jump backwards");
+                op = OpCode::Loop;
+                next - target
+            };
+            if distance > u16::MAX as usize {
+                return;
+            }
+            operands = (distance as u16).to_be_bytes().to_vec();
+        }
+        for (offset, token) in &instruction.tokens {
+            out.tokens.push((start + offset, token.clone()));
+        }
+        out.write(op as u8, instruction.line);
+        for byte in operands {
+            out.write(byte, instruction.line);
+        }
+    }
+    chunk.code = out.code;
+    chunk.lines = out.lines;
+    chunk.tokens = out.tokens;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::vm::disassembler::disassemble_function;
+    use crate::vm::{loxc, Vm};
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().clone();
+        Parser::new(tokens).parse()
+    }
+
+    fn fold_source(source: &str) -> Option<LiteralValue> {
+        match &parse(&format!("{};", source))[0].kind {
+            StmtKind::Expression { expression } => fold(expression),
+            _ => unreachable!(),
+        }
+    }
+
+    fn disassemble(source: &str, optimize: bool) -> String {
+        let mut vm = Vm::new();
+        vm.set_optimize(optimize);
+        let function = vm.compile(&parse(source), false).unwrap();
+        disassemble_function(vm.heap(), function)
+    }
+
+    #[test]
+    fn test_folds_literal_expressions() {
+        use LiteralValue::{Bool, Nil, Number};
+
+        assert_eq!(fold_source("(1 + 2) * -3"), Some(Number(-9.0)));
+        assert_eq!(
+            fold_source("\"a\" + \"b\" + \"c\""),
+            Some(LiteralValue::String("abc".to_string()))
+        );
+        assert_eq!(fold_source("1 <= 2 == true"), Some(Bool(true)));
+        assert_eq!(fold_source("nil != nil"), Some(Bool(false)));
+        assert_eq!(fold_source("!\"\""), Some(Bool(false)));
+        assert_eq!(fold_source("!nil"), Some(Bool(true)));
+        assert_eq!(fold_source("nil"), Some(Nil));
+
+        // Runtime errors and anything not a literal stay as they are.
+        assert_eq!(fold_source("1 + \"a\""), None);
+        assert_eq!(fold_source("-\"a\""), None);
+        assert_eq!(fold_source("\"a\" < \"b\""), None);
+        assert_eq!(fold_source("1 + a"), None);
+        assert_eq!(fold_source("true and false"), None);
+    }
+
+    #[test]
+    fn test_statements_that_always_return() {
+        let returns_source = |source: &str| returns(&parse(source)[0]);
+        assert!(returns_source("return;"));
+        assert!(returns_source("{ print 1; return 2; }"));
+        assert!(returns_source("if (a) return 1; else { return 2; }"));
+        assert!(returns_source("if (true) return 1;"));
+        assert!(!returns_source("if (a) return 1;"));
+        assert!(!returns_source("if (false) return 1;"));
+        assert!(!returns_source("while (a) return 1;"));
+    }
+
+    #[test]
+    fn test_removes_dead_code() {
+        let source = "fun f() { return 1; print 2; }\nif (false) print 3;\nwhile (false) print 4;";
+        let optimized = disassemble(source, true);
+        assert!(!optimized.contains("'2'"));
+        assert!(!optimized.contains("'3'"));
+        assert!(!optimized.contains("'4'"));
+        // Only the explicit return is left in `f`.
+        assert_eq!(optimized.matches("OP_RETURN").count(), 2);
+
+        let unoptimized = disassemble(source, false);
+        assert!(unoptimized.contains("'2'"));
+        assert!(unoptimized.contains("'3'"));
+    }
+
+    #[test]
+    fn test_threads_jumps_and_merges_pops() {
+        let source = "fun f(a, b) {\n  while (a and b) { var x; var y; var z; }\n}";
+        let optimized = disassemble(source, true);
+        assert!(
+            optimized.contains("OP_POP_N            3\n"),
+            "{}",
+            optimized
+        );
+        // `a` being falsey jumps straight out of the loop instead of to the
+        // check of `b`, which would jump out in turn.
+        assert!(optimized.contains("OP_JUMP_IF_FALSE    2 -> 20\n"));
+        assert!(optimized.contains("0020    | OP_POP\n"));
+
+        let unoptimized = disassemble(source, false);
+        assert!(unoptimized.contains("OP_JUMP_IF_FALSE    2 -> 8\n"));
+        assert!(!unoptimized.contains("OP_POP_N"));
+    }
+
+    #[test]
+    fn test_loops_that_never_end_are_valid_code() {
+        let source = "fun f() { while (true) {} }\nwhile (true) {}";
+        let optimized = disassemble(source, true);
+        assert!(!optimized.contains("OP_RETURN"));
+
+        let mut vm = Vm::new();
+        let function = vm.compile(&parse(source), false).unwrap();
+        let bytes = loxc::write(vm.heap(), function, source);
+        assert!(Vm::new().load(&bytes).is_ok());
+    }
+}
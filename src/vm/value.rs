@@ -0,0 +1,178 @@
+use crate::vm::object::ObjRef;
+
+/// A value on the VM stack. Everything that does not fit in a machine word
+/// lives on the `Heap` and is referred to by handle.
+///
+/// By default a value is a tagged enum. With the `nan-boxing` feature it is
+/// a single 64-bit word instead: numbers are stored as themselves and every
+/// other value hides in the payload of a quiet NaN. Either way, code looks
+/// at a value through `kind` and builds one with the constructors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value(ValueKind);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value(ValueKind::Nil);
+
+    pub fn bool(b: bool) -> Value {
+        Value(ValueKind::Bool(b))
+    }
+
+    pub fn number(n: f64) -> Value {
+        Value(ValueKind::Number(n))
+    }
+
+    pub fn obj(obj: ObjRef) -> Value {
+        Value(ValueKind::Obj(obj))
+    }
+
+    pub fn kind(self) -> ValueKind {
+        self.0
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+mod boxed {
+    use super::ValueKind;
+    use crate::vm::object::ObjRef;
+    use std::fmt;
+
+    // The bits of a quiet NaN, plus one more so that the NaN produced by
+    // arithmetic never looks like a boxed value.
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    // Set, together with `QNAN`, for object handles.
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+        pub fn bool(b: bool) -> Value {
+            Value(QNAN | if b { TAG_TRUE } else { TAG_FALSE })
+        }
+
+        pub fn number(n: f64) -> Value {
+            Value(n.to_bits())
+        }
+
+        pub fn obj(obj: ObjRef) -> Value {
+            Value(SIGN_BIT | QNAN | u64::from(obj.to_raw()))
+        }
+
+        pub fn kind(self) -> ValueKind {
+            if self.0 & QNAN != QNAN {
+                ValueKind::Number(f64::from_bits(self.0))
+            } else if self.0 & SIGN_BIT != 0 {
+                ValueKind::Obj(ObjRef::from_raw(self.0 as u32))
+            } else {
+                match self.0 & 3 {
+                    TAG_NIL => ValueKind::Nil,
+                    TAG_FALSE => ValueKind::Bool(false),
+                    _ => ValueKind::Bool(true),
+                }
+            }
+        }
+    }
+
+    impl PartialEq for Value {
+        fn eq(&self, other: &Value) -> bool {
+            match (self.kind(), other.kind()) {
+                // NaN is not equal to itself.
+                (ValueKind::Number(a), ValueKind::Number(b)) => a == b,
+                _ => self.0 == other.0,
+            }
+        }
+    }
+
+    impl fmt::Debug for Value {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Value({:?})", self.kind())
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+pub use boxed::Value;
+
+impl Value {
+    /// `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_falsey(self) -> bool {
+        matches!(self.kind(), ValueKind::Nil | ValueKind::Bool(false))
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self.kind() {
+            ValueKind::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self.kind() {
+            ValueKind::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::object::{Heap, Obj};
+
+    #[test]
+    fn test_round_trip() {
+        let mut heap = Heap::new();
+        let obj = heap.intern("lox");
+        let values = [
+            (Value::NIL, ValueKind::Nil),
+            (Value::bool(true), ValueKind::Bool(true)),
+            (Value::bool(false), ValueKind::Bool(false)),
+            (Value::number(-2.5), ValueKind::Number(-2.5)),
+            (
+                Value::number(f64::INFINITY),
+                ValueKind::Number(f64::INFINITY),
+            ),
+            (Value::obj(obj), ValueKind::Obj(obj)),
+        ];
+        for (value, kind) in values.iter() {
+            assert_eq!(value.kind(), *kind);
+        }
+        assert!(matches!(
+            heap.get(Value::obj(obj).as_obj().unwrap()),
+            Obj::String(_)
+        ));
+    }
+
+    #[test]
+    fn test_equality_and_truthiness() {
+        let nan = Value::number(f64::NAN);
+        assert_ne!(nan, nan);
+        assert!(nan.kind() != ValueKind::Nil);
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+        assert_ne!(Value::NIL, Value::bool(false));
+        assert!(Value::NIL.is_falsey());
+        assert!(Value::bool(false).is_falsey());
+        assert!(!Value::number(0.0).is_falsey());
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn test_boxed_value_is_one_word() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+}
@@ -0,0 +1,667 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn write_script(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rlox_cli_{}_{}.lox", std::process::id(), name));
+    fs::write(&path, source).expect("Unable to write test script");
+    path
+}
+
+fn rlox(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .output()
+        .expect("Unable to run rlox")
+}
+
+fn rlox_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Unable to run rlox");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_json_error_format() {
+    let path = write_script("json_errors", "var x = 1;\nx @;");
+    let output = rlox(&["--error-format=json", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("{\"severity\":\"error\",\"code\":\"E0001\""));
+    assert!(lines[0].contains("\"line\":2,\"column\":3,\"end_line\":2,\"end_column\":4"));
+    assert!(lines[0].contains(&format!("\"file\":\"{}\"", path.to_str().unwrap())));
+}
+
+#[test]
+fn test_text_error_format_keeps_exit_code() {
+    let path = write_script("text_errors", "x @;");
+    let output = rlox(&[path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.trim_end(), "[line 1 ] Error : Unexpected character.");
+}
+
+#[test]
+fn test_unknown_error_format() {
+    let output = rlox(&["--error-format=xml"]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn test_tokens_subcommand() {
+    let path = write_script("tokens", "var s = \"hi\";");
+    let output = rlox(&["tokens", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "VAR\tvar\tnull\t1:1\n\
+         IDENTIFIER\ts\tnull\t1:5\n\
+         EQUAL\t=\tnull\t1:7\n\
+         STRING\t\"hi\"\t\"hi\"\t1:9\n\
+         SEMICOLON\t;\tnull\t1:13\n\
+         EOF\t\tnull\t1:14\n"
+    );
+}
+
+#[test]
+fn test_dump_tokens_json() {
+    let path = write_script("tokens_json", "1");
+    let output = rlox(&["--dump-tokens", "--json", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().next(),
+        Some("{\"type\":\"NUMBER\",\"lexeme\":\"1\",\"literal\":1,\"line\":1,\"column\":1}")
+    );
+}
+
+#[test]
+fn test_ast_subcommand() {
+    let path = write_script("ast", "print (1 + 2) * 3;\nvar x = -y;");
+    let output = rlox(&["ast", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(print (* (group (+ 1 2)) 3))\n(var x (- y))\n"
+    );
+}
+
+#[test]
+fn test_ast_json_subcommand() {
+    let path = write_script("ast_json", "x;");
+    let output = rlox(&["ast", "--json", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"type\":\"Expression\",\"span\":{\"line\":1,\"column\":1,\"end_line\":1,\"end_column\":3},\
+         \"expression\":{\"type\":\"Variable\",\"span\":{\"line\":1,\"column\":1,\"end_line\":1,\"end_column\":2},\
+         \"name\":\"x\"}}]\n"
+    );
+}
+
+#[test]
+fn test_ast_syntax_error() {
+    let path = write_script("ast_error", "print ;");
+    let output = rlox(&["ast", path.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "[line 1 ] Error at ';': Expect expression."
+    );
+}
+
+#[test]
+fn test_run_subcommand() {
+    let path = write_script("run", "var a = 1;\nprint a + 2;");
+    let output = rlox(&["run", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+
+    let output = rlox(&[path.to_str().unwrap()]);
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn test_runtime_error_exit_code() {
+    let path = write_script("runtime_error", "print 1;\nprint -nil;");
+    let output = rlox(&["run", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "[line 2 ] Error at '-': Operand must be a number.\n[line 2] in script"
+    );
+}
+
+#[test]
+fn test_runtime_error_stack_trace() {
+    let source = "fun fibonacci(n) {\n  if (n < 2) return n;\n  return fibonacci(n - 1) + fibonacci(n - 2) + nil;\n}\n\nfun start() {\n  return fibonacci(4) + 1;\n}\nprint start();";
+    let path = write_script("stack_trace", source);
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "[line 3 ] Error at '+': Operands must be two numbers or two strings.\n\
+             [line 3] in fibonacci()\n\
+             [line 3] in fibonacci()\n\
+             [line 3] in fibonacci()\n\
+             [line 7] in start()\n\
+             [line 9] in script\n"
+        );
+
+        let output = rlox(&[
+            backend,
+            "--error-format=json",
+            "run",
+            path.to_str().unwrap(),
+        ]);
+        assert!(String::from_utf8(output.stderr).unwrap().contains(
+            "\"trace\":[\"[line 3] in fibonacci()\",\"[line 3] in fibonacci()\",\
+             \"[line 3] in fibonacci()\",\"[line 7] in start()\",\"[line 9] in script\"]"
+        ));
+    }
+}
+
+#[test]
+fn test_stack_overflow() {
+    let path = write_script(
+        "stack_overflow",
+        "fun f(n) {\n  return f(n + 1) + 1;\n}\n\nf(0);",
+    );
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let lines: Vec<&str> = stderr.lines().collect();
+        assert_eq!(lines[0], "[line 2 ] Error at ')': Stack overflow.");
+        assert_eq!(
+            lines[1..],
+            [
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "[line 2] in f()",
+                "... 1021 more",
+                "[line 5] in script"
+            ]
+        );
+
+        let output = rlox(&[backend, "--max-depth=10", "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("\n... 7 more\n"));
+    }
+}
+
+#[test]
+fn test_nesting_limit() {
+    let depth = rlox::parser::MAX_NESTING - 10;
+    let source = format!(
+        "print {}1{};\nprint 0{};\nprint {}1;",
+        "(".repeat(depth),
+        ")".repeat(depth),
+        " + 1".repeat(depth),
+        "-".repeat(depth)
+    );
+    let path = write_script("nested", &source);
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(stdout(&output), format!("1\n{}\n1\n", depth));
+    }
+
+    let source = format!("print {}1{};", "(".repeat(50_000), ")".repeat(50_000));
+    let path = write_script("too_deep", &source);
+    let output = rlox(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("[line 1 ] Error at '(': Nesting too deep.\n"));
+}
+
+#[test]
+fn test_step_limit() {
+    let path = write_script("step_limit", "print \"start\";\nwhile (true) {}");
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "--max-steps=500", "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(stdout(&output), "start\n");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "[line 2 ] Error : Step limit exceeded.\n[line 2] in script\n"
+        );
+    }
+}
+
+#[test]
+fn test_memory_limit() {
+    let path = write_script(
+        "memory_limit",
+        "var s = \"x\";\nwhile (true) {\n  s = s + s;\n}",
+    );
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[
+            backend,
+            "--max-memory=100000",
+            "run",
+            path.to_str().unwrap(),
+        ]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "[line 3 ] Error : Out of memory.\n[line 3] in script\n"
+        );
+    }
+}
+
+#[test]
+fn test_sandbox() {
+    let path = write_script("sandbox", "print \"before\";\nexit(3);");
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(stdout(&output), "before\n");
+
+        let output = rlox(&[backend, "--sandbox", "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(stdout(&output), "before\n");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "[line 2 ] Error at 'exit': Undefined variable 'exit'.\n[line 2] in script\n"
+        );
+    }
+}
+
+#[test]
+fn test_exit_in_the_repl() {
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox_with_stdin(&[backend, "repl"], "print 1;\nexit(5);\nprint 2;\n");
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(stdout(&output), "> 1\n> > 2\n> \n");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "Exited with status 5. Type :quit to leave the REPL.\n"
+        );
+    }
+}
+
+#[test]
+fn test_import() {
+    let module = write_script("import_module", "fun fail() {\n  return nil + 1;\n}\n");
+    let source = format!("import(\"{}\");\nfail();", module.display());
+    let path = write_script("import", &source);
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            format!(
+                "[line 2 ] Error at '+': Operands must be two numbers or two strings.\n\
+                 [line 2] in fail() ({})\n[line 2] in script\n",
+                module.display()
+            )
+        );
+
+        let output = rlox(&[backend, "--sandbox", "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "[line 1 ] Error at 'import': Undefined variable 'import'.\n[line 1] in script\n"
+        );
+    }
+}
+
+#[test]
+fn test_deep_recursion_within_the_limit() {
+    let source = "fun depth(n) {\n  if (n == 0) return 0;\n  return depth(n - 1) + 1;\n}\nprint depth(5000);";
+    let path = write_script("deep_recursion", source);
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rlox(&[backend, "--max-depth=6000", "run", path.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(stdout(&output), "5000\n");
+    }
+}
+
+#[test]
+fn test_max_depth_beyond_the_tree_walker() {
+    let source = "fun depth(n) {\n  if (n == 0) return 0;\n  return depth(n - 1) + 1;\n}\nprint depth(100000);";
+    let path = write_script("deeper_recursion", source);
+    let output = rlox(&[
+        "--backend=vm",
+        "--max-depth=1000000",
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "100000\n");
+
+    let output = rlox(&["--max-depth=1000000", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("rlox: Maximum depth 1000000 is too deep for the tree backend"));
+}
+
+#[test]
+fn test_check_subcommand_does_not_run() {
+    let path = write_script("check", "print \"side effect\";\nprint -nil;");
+    let output = rlox(&["check", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+
+    let path = write_script("check_error", "fun f() {}\nreturn f;");
+    let output = rlox(&["check", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "[line 2 ] Error at 'return': Can't return from top-level code."
+    );
+}
+
+#[test]
+fn test_stdin_input() {
+    let output = rlox_with_stdin(&["run", "-"], "print \"from stdin\";");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "from stdin\n");
+
+    let output = rlox_with_stdin(&["--error-format=json", "check", "-"], "print ;");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("\"file\":\"<stdin>\""));
+}
+
+#[test]
+fn test_eval_subcommand() {
+    let output = rlox(&["eval", "-e", "print 6 * 7;"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "42\n");
+}
+
+#[test]
+fn test_help_and_usage_errors() {
+    let output = rlox(&["--help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).contains("check"));
+
+    let output = rlox(&["ast", "--help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("Usage: rlox ast"));
+
+    let output = rlox(&["run"]);
+    assert_eq!(output.status.code(), Some(64));
+    let output = rlox(&["run", "a.lox", "b.lox"]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn test_missing_file() {
+    let output = rlox(&["run", "/nonexistent/script.lox"]);
+    assert_eq!(output.status.code(), Some(66));
+}
+
+#[test]
+fn test_repl_session() {
+    let output = rlox_with_stdin(
+        &["repl"],
+        "var a = 1;\n\nfun inc(n) { return n + a; }\ninc(41)\nprint nope;\n\"still \" + \"here\";\n:quit\nprint 99;\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> > > > 42\n> > still here\n> ");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "[line 1 ] Error at 'nope': Undefined variable 'nope'.\n[line 1] in script"
+    );
+}
+
+#[test]
+fn test_repl_exits_on_eof() {
+    let output = rlox_with_stdin(&[], "1 + 2\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> 3\n> \n");
+}
+
+#[test]
+fn test_repl_multiline_input() {
+    let output = rlox_with_stdin(
+        &["repl"],
+        "fun add(a,\n        b) {\n  return a + b;\n}\nadd(1, 2)\nvar s = \"one\ntwo\";\nprint s;\n/* a\ncomment */ 4\nvar a = 1\n;\na\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "> ... ... ... > 3\n> ... > one\ntwo\n> ... 4\n> ... > 1\n> \n"
+    );
+}
+
+#[test]
+fn test_repl_runs_unfinished_input_at_eof() {
+    let output = rlox_with_stdin(&["repl"], "print 1 +\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "> ... \n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap().trim_end(),
+        "[line 1 ] Error at end: Expect expression."
+    );
+}
+
+#[test]
+fn test_repl_meta_commands() {
+    let script = write_script(
+        "repl_load",
+        "var greeting = \"hi\";\nfun twice(n) { return n * 2; }\n",
+    );
+    let input = format!(
+        ":help\n:load {}\ntwice(21)\n:env\n:tokens 1 + x\n:ast print -1;\n:ast 1 + 2 * 3\n:time twice(2)\n:reset\n:env\n:bogus\n:tokens\n",
+        script.display()
+    );
+    let output = rlox_with_stdin(&["repl"], &input);
+    assert_eq!(output.status.code(), Some(0));
+
+    let out = stdout(&output);
+    assert!(out.contains("  :load <file>    Run a script in the current session.\n"));
+    assert!(out.contains("> 42\n"));
+    assert!(out.contains(
+        "clock = <native fn>\nexit = <native fn>\ngetenv = <native fn>\ngreeting = hi\n\
+         import = <native fn>\nreadFile = <native fn>\ntwice = <fn twice>\n\
+         writeFile = <native fn>\n> "
+    ));
+    assert!(out.contains(
+        "NUMBER\t1\t1\t1:1\nPLUS\t+\tnull\t1:3\nIDENTIFIER\tx\tnull\t1:5\nEOF\t\tnull\t1:6\n"
+    ));
+    assert!(out.contains("(print (- 1))\n"));
+    assert!(out.contains("(; (+ 1 (* 2 3)))\n"));
+    assert!(out.contains("> 4\ntook "));
+    assert!(out.ends_with(
+        "> clock = <native fn>\nexit = <native fn>\ngetenv = <native fn>\n\
+         import = <native fn>\nreadFile = <native fn>\nwriteFile = <native fn>\n> > > \n"
+    ));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Unknown command ':bogus'. Type :help for a list.\nUsage: :tokens <code>\n"
+    );
+}
+
+#[test]
+fn test_highlight_html() {
+    let path = write_script("highlight", "// hi\nprint 1 < 2;\n");
+    let output = rlox(&["highlight", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let html = stdout(&output);
+    assert!(html.starts_with("<!DOCTYPE html>\n"));
+    assert!(html.contains(&format!("<title>{}</title>", path.display())));
+    assert!(html.contains(
+        "<code><span class=\"lox-comment\">// hi</span>\n\
+         <span class=\"lox-keyword\">print</span> <span class=\"lox-number\">1</span> \
+         <span class=\"lox-operator\">&lt;</span> <span class=\"lox-number\">2</span>\
+         <span class=\"lox-operator\">;</span>\n</code>"
+    ));
+}
+
+#[test]
+fn test_highlight_ansi_keeps_invalid_input() {
+    let output = rlox_with_stdin(&["highlight", "--ansi", "-"], "x @ \"open");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "x @ \x1b[32m\"open\x1b[0m");
+}
+
+#[test]
+fn test_fmt() {
+    let path = write_script("fmt", "var a=1; // one\nif(a)print a;else{print -a;}\n");
+    let output = rlox(&["fmt", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let formatted = stdout(&output);
+    assert_eq!(
+        formatted,
+        "var a = 1; // one\nif (a)\n  print a;\nelse {\n  print -a;\n}\n"
+    );
+
+    let output = rlox(&["fmt", "--check", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("rlox: '{}' is not formatted.\n", path.display())
+    );
+    let output = rlox_with_stdin(&["fmt", "--check", "-"], &formatted);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_fmt_syntax_error() {
+    let output = rlox_with_stdin(&["fmt", "-"], "print ;");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_disasm() {
+    let path = write_script("disasm", "var a = 1;\nprint a;\n");
+    let output = rlox(&["disasm", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "== <script> ==\n\
+         0000    1 OP_CONSTANT         0 '1'\n\
+         0003    | OP_DEFINE_GLOBAL    1 'a'\n\
+         0006    2 OP_GET_GLOBAL       1 'a'\n\
+         0009    | OP_PRINT\n\
+         0010    | OP_NIL\n\
+         0011    | OP_RETURN\n"
+    );
+}
+
+#[test]
+fn test_trace_execution() {
+    let output = rlox(&["--trace-execution", "-O0", "eval", "-e", "print -2;"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "          [ <script> ]\n\
+         0000    1 OP_CONSTANT         0 '2'\n\
+         \x20         [ <script> ][ 2 ]\n\
+         0003    | OP_NEGATE\n\
+         \x20         [ <script> ][ -2 ]\n\
+         0004    | OP_PRINT\n\
+         -2\n\
+         \x20         [ <script> ]\n\
+         0005    | OP_NIL\n\
+         \x20         [ <script> ][ nil ]\n\
+         0006    | OP_RETURN\n"
+    );
+}
+
+#[test]
+fn test_compile_and_run_bytecode() {
+    let path = write_script(
+        "compiled",
+        "fun add(a, b) { return a + b; }\nprint add(1, 2);\nadd(nil, 1);\n",
+    );
+    let compiled = path.with_extension("loxc");
+    let output = rlox(&["compile", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(compiled.exists());
+
+    let output = rlox(&[compiled.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "3\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("[line 1 ] Error at '+': Operands must be two numbers or two strings."));
+
+    let output = rlox(&["--backend=tree", "run", compiled.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn test_compile_to_output_path() {
+    let path = write_script("compile_output", "print \"hi\";");
+    let compiled = std::env::temp_dir().join(format!("rlox_cli_{}_out.loxc", std::process::id()));
+    let output = rlox(&[
+        "compile",
+        path.to_str().unwrap(),
+        "-o",
+        compiled.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = rlox(&["run", compiled.to_str().unwrap()]);
+    assert_eq!(stdout(&output), "hi\n");
+
+    // Compile errors are reported and nothing is written.
+    let broken = write_script("compile_broken", "print ;");
+    let missing = broken.with_extension("loxc");
+    let output = rlox(&["compile", broken.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(!missing.exists());
+}
+
+#[test]
+fn test_run_rejects_bad_bytecode() {
+    let path = write_script("bad_bytecode", "print 1;");
+    let compiled = path.with_extension("loxc");
+    rlox(&["compile", path.to_str().unwrap()]);
+    let bytes = fs::read(&compiled).unwrap();
+
+    let mut newer = bytes.clone();
+    newer[5] += 1;
+    fs::write(&compiled, &newer).unwrap();
+    let output = rlox(&[compiled.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Unsupported bytecode version 3 (expected 2)"));
+
+    fs::write(&compiled, &bytes[..bytes.len() - 3]).unwrap();
+    let output = rlox(&[compiled.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Corrupt bytecode file: checksum mismatch."));
+
+    fs::write(&compiled, "print 1;").unwrap();
+    let output = rlox(&[compiled.to_str().unwrap()]);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Not a compiled Lox file."));
+}
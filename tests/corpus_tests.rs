@@ -0,0 +1,115 @@
+//! Runs every script in `tests/corpus` on each backend, and on the VM with
+//! a collection on every allocation and without optimizations. Scripts
+//! state what they should print with `// expect: <line>` comments, and a
+//! runtime error they should stop at with `// expect runtime error:
+//! <message>`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const CONFIGURATIONS: &[&[&str]] = &[
+    &["--backend=tree"],
+    &["--backend=vm"],
+    &["--backend=vm", "--stress-gc"],
+    &["--backend=vm", "-O0"],
+];
+
+struct Expectations {
+    output: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Unable to read the corpus")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+fn expectations(source: &str) -> Expectations {
+    let mut output = Vec::new();
+    let mut runtime_error = None;
+    for line in source.lines() {
+        if let Some((_, expected)) = line.split_once("// expect: ") {
+            output.push(expected.to_string());
+        } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+            runtime_error = Some(message.to_string());
+        }
+    }
+    Expectations {
+        output,
+        runtime_error,
+    }
+}
+
+fn rlox(script: &Path, options: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(options)
+        .arg(script)
+        .output()
+        .expect("Unable to run rlox")
+}
+
+// Runs `script` with `options` and describes how it went wrong, if it did.
+fn check(script: &Path, options: &[&str]) -> Option<String> {
+    let source = fs::read_to_string(script).expect("Unable to read script");
+    let expected = expectations(&source);
+    let output = rlox(script, options);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let actual: Vec<&str> = stdout.lines().collect();
+    if actual != expected.output {
+        return Some(format!(
+            "expected output {:?} but got {:?}",
+            expected.output, actual
+        ));
+    }
+    match &expected.runtime_error {
+        Some(message) if output.status.code() != Some(70) || !stderr.contains(message) => Some(
+            format!("expected runtime error '{}' but got: {}", message, stderr),
+        ),
+        None if !output.status.success() => Some(format!("unexpected error: {}", stderr)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_corpus_on_every_configuration() {
+    let scripts = corpus();
+    assert!(!scripts.is_empty());
+
+    let mut failures = Vec::new();
+    for script in &scripts {
+        for options in CONFIGURATIONS {
+            if let Some(problem) = check(script, options) {
+                let options = options.join(" ");
+                failures.push(format!("{} [{}]: {}", script.display(), options, problem));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_optimizations_do_not_change_behaviour() {
+    let mut differences = Vec::new();
+    for script in &corpus() {
+        let optimized = rlox(script, &["--backend=vm"]);
+        let unoptimized = rlox(script, &["--backend=vm", "-O0"]);
+        if optimized != unoptimized {
+            differences.push(format!(
+                "{}:\n  -O1: {:?}\n  -O0: {:?}",
+                script.display(),
+                optimized,
+                unoptimized
+            ));
+        }
+    }
+    assert!(differences.is_empty(), "\n{}", differences.join("\n"));
+}